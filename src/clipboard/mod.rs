@@ -0,0 +1,217 @@
+extern crate sdl2;
+
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+
+pub mod register;
+use register::Registers;
+
+// max number of entries in the kill ring
+const KILL_RING_SIZE: usize = 32;
+
+// Somewhere text can be copied to and pasted from
+pub trait Clipboard {
+    fn text(&self) -> Option<String>;
+    fn set_text(&mut self, text: &str);
+}
+
+// The system clipboard through SDL
+pub struct SystemClipboard {
+    util: sdl2::clipboard::ClipboardUtil,
+}
+
+impl SystemClipboard {
+    pub fn new(video: &sdl2::VideoSubsystem) -> Self {
+        SystemClipboard {util: video.clipboard()}
+    }
+}
+
+impl Clipboard for SystemClipboard {
+    fn text(&self) -> Option<String> {
+        if !self.util.has_clipboard_text() {
+            return None
+        }
+        self.util.clipboard_text().ok()
+    }
+
+    fn set_text(&mut self, text: &str) {
+        // SDL takes a C string, so text with a nul in it can't be copied
+        if text.contains('\0') {
+            return
+        }
+        let _ = self.util.set_clipboard_text(text);
+    }
+}
+
+// The SDL library the editor is linked with, opened again to look up functions newer than sdl2-sys
+#[cfg(windows)]
+const SDL_LIBRARY: &[u8] = b"SDL2.dll\0";
+#[cfg(target_os = "macos")]
+const SDL_LIBRARY: &[u8] = b"libSDL2-2.0.0.dylib\0";
+#[cfg(not(any(windows, target_os = "macos")))]
+const SDL_LIBRARY: &[u8] = b"libSDL2-2.0.so.0\0";
+
+// added in SDL 2.26, not bound by sdl2-sys 0.34
+type GetPrimarySelectionText = unsafe extern "C" fn() -> *mut c_char;
+type SetPrimarySelectionText = unsafe extern "C" fn(text: *const c_char) -> c_int;
+
+// address of a function in an SDL library loaded with SDL_LoadObject, null if the SDL in use does not have it
+fn sdl_function(library: *mut c_void, name: &[u8]) -> *mut c_void {
+    if library.is_null() {
+        return std::ptr::null_mut()
+    }
+    unsafe { sdl2::sys::SDL_LoadFunction(library, name.as_ptr() as *const c_char) }
+}
+
+// The X11 primary selection, which holds the last selected text and is pasted with a middle click.
+// On platforms without one SDL keeps it inside the process. With an SDL older than 2.26 there is none,
+// nothing is pasted and setting it does nothing.
+pub struct PrimarySelection {
+    // the video subsystem has to stay initialized while this is used
+    _video: sdl2::VideoSubsystem,
+    // loaded once for both functions and unloaded on drop, null if it could not be opened
+    library: *mut c_void,
+    get: Option<GetPrimarySelectionText>,
+    set: Option<SetPrimarySelectionText>,
+    // what we set it to last, so the same selection does not take it back from another program
+    owned: String,
+}
+
+impl PrimarySelection {
+    pub fn new(video: &sdl2::VideoSubsystem) -> Self {
+        let library = unsafe { sdl2::sys::SDL_LoadObject(SDL_LIBRARY.as_ptr() as *const c_char) };
+        let get = sdl_function(library, b"SDL_GetPrimarySelectionText\0");
+        let set = sdl_function(library, b"SDL_SetPrimarySelectionText\0");
+        unsafe {
+            PrimarySelection {
+                _video: video.clone(),
+                library,
+                get: (!get.is_null()).then(|| std::mem::transmute::<*mut c_void, GetPrimarySelectionText>(get)),
+                set: (!set.is_null()).then(|| std::mem::transmute::<*mut c_void, SetPrimarySelectionText>(set)),
+                owned: String::new(),
+            }
+        }
+    }
+}
+
+impl Clipboard for PrimarySelection {
+    fn text(&self) -> Option<String> {
+        let get = self.get?;
+        unsafe {
+            let buf = get();
+            if buf.is_null() {
+                return None
+            }
+            let text = CStr::from_ptr(buf).to_string_lossy().into_owned();
+            sdl2::sys::SDL_free(buf as *mut c_void);
+            if text.is_empty() {None} else {Some(text)}
+        }
+    }
+
+    fn set_text(&mut self, text: &str) {
+        let set = match self.set {
+            Some(set) => set,
+            None => return
+        };
+        if text == self.owned {
+            return
+        }
+        if let Ok(c_text) = CString::new(text) {
+            unsafe {
+                set(c_text.as_ptr());
+            }
+            self.owned = text.to_string();
+        }
+    }
+}
+
+impl Drop for PrimarySelection {
+    fn drop(&mut self) {
+        if !self.library.is_null() {
+            unsafe { sdl2::sys::SDL_UnloadObject(self.library) };
+        }
+    }
+}
+
+// Text copied from every cursor at once, one piece per cursor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Clip {
+    pub pieces: Vec<Vec<u8>>,
+}
+
+impl Clip {
+    pub fn from_text(text: &str) -> Self {
+        Clip {pieces: vec![text.as_bytes().to_vec()]}
+    }
+
+    // the pieces on separate lines, as the text goes to the system clipboard
+    pub fn joined(&self) -> Vec<u8> {
+        self.pieces.join(&b'\n')
+    }
+
+    // content to paste at each of count cursors, one piece each if the counts match
+    pub fn pieces_for(&self, count: usize) -> Vec<Vec<u8>> {
+        if self.pieces.len() == count {
+            self.pieces.clone()
+        } else {
+            vec![self.joined(); count]
+        }
+    }
+}
+
+// What copy and paste commands work with, so they can run like any other command
+pub struct Clipboards<C: Clipboard> {
+    pub ring: KillRing,
+    // shared with other programs
+    pub clipboard: C,
+    pub registers: Registers,
+}
+
+impl<C: Clipboard> Clipboards<C> {
+    pub fn new(clipboard: C, registers: Registers) -> Self {
+        Clipboards {ring: KillRing::default(), clipboard, registers}
+    }
+}
+
+// Recently copied clips, newest first
+pub struct KillRing {
+    entries: VecDeque<Clip>,
+}
+
+impl KillRing {
+    pub fn default() -> Self {
+        KillRing {entries: VecDeque::with_capacity(KILL_RING_SIZE)}
+    }
+
+    pub fn push(&mut self, clip: Clip) {
+        if self.entries.len() == KILL_RING_SIZE {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(clip);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // entry index steps back from the newest, wrapping around
+    pub fn get(&self, index: usize) -> Option<&Clip> {
+        if self.entries.is_empty() {
+            return None
+        }
+        self.entries.get(index % self.entries.len())
+    }
+
+    // The clip to paste given the current clipboard text. Text copied by another program is
+    // added to the ring first, text we copied ourselves keeps its per cursor pieces.
+    pub fn take_clipboard(&mut self, clipboard: &impl Clipboard) -> Option<&Clip> {
+        if let Some(text) = clipboard.text() {
+            let ours = self.entries.front().is_some_and(|clip| clip.joined() == text.as_bytes());
+            if !ours {
+                self.push(Clip::from_text(&text));
+            }
+        }
+        self.entries.front()
+    }
+}
@@ -0,0 +1,313 @@
+// Vim style named registers, which hold copied text or a recorded macro.
+// a-z are set directly and A-Z append to the register of the same lower case letter.
+// The contents are saved to a file so they survive restarts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::mark::Command;
+
+use super::Clip;
+
+// the text of the last search
+pub const LAST_SEARCH: u8 = b'/';
+// the text typed by the last run of inserts
+pub const LAST_INSERT: u8 = b'.';
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Register {
+    Text(Clip),
+    Macro(Vec<Command>),
+}
+
+impl Register {
+    fn append(&mut self, other: Register) {
+        match (self, other) {
+            (Register::Text(clip), Register::Text(other)) => {
+                if clip.pieces.len() == other.pieces.len() {
+                    for (piece, more) in clip.pieces.iter_mut().zip(other.pieces) {
+                        piece.extend(more);
+                    }
+                } else {
+                    let mut joined = clip.joined();
+                    joined.extend(other.joined());
+                    clip.pieces = vec![joined];
+                }
+            }
+            (Register::Macro(commands), Register::Macro(more)) => commands.extend(more),
+            // appending a macro to text or the other way around replaces it
+            (register, other) => *register = other,
+        }
+    }
+}
+
+pub struct Registers {
+    // sorted so the saved file does not change between saves of the same contents
+    registers: BTreeMap<u8, Register>,
+    // register a macro is being recorded into and the commands so far
+    recording: Option<(u8, Vec<Command>)>,
+    // set after an insert, so the next insert adds to the last insert register
+    inserting: bool,
+}
+
+// a register name that can be set by the user, upper case letters append
+fn writable(name: u8) -> bool {
+    name.is_ascii_alphabetic() || name == LAST_SEARCH || name == LAST_INSERT
+}
+
+impl Registers {
+    pub fn default() -> Self {
+        Registers {registers: BTreeMap::new(), recording: None, inserting: false}
+    }
+
+    pub fn get(&self, name: u8) -> Option<&Register> {
+        self.registers.get(&name.to_ascii_lowercase())
+    }
+
+    pub fn text(&self, name: u8) -> Option<&Clip> {
+        match self.get(name)? {
+            Register::Text(clip) => Some(clip),
+            Register::Macro(_) => None
+        }
+    }
+
+    pub fn commands(&self, name: u8) -> Option<&[Command]> {
+        match self.get(name)? {
+            Register::Macro(commands) => Some(commands),
+            Register::Text(_) => None
+        }
+    }
+
+    // Returns false if name is not a register.
+    pub fn set(&mut self, name: u8, register: Register) -> bool {
+        if !writable(name) {
+            return false
+        }
+        let key = name.to_ascii_lowercase();
+        match self.registers.get_mut(&key) {
+            Some(existing) if name.is_ascii_uppercase() => existing.append(register),
+            _ => {
+                self.registers.insert(key, register);
+            }
+        }
+        true
+    }
+
+    pub fn set_search(&mut self, text: &[u8]) {
+        self.set(LAST_SEARCH, Register::Text(Clip {pieces: vec![text.to_vec()]}));
+    }
+
+    // Start recording commands into a register, an upper case name appends to it when done.
+    // Returns false if already recording or name is not a letter.
+    pub fn start_recording(&mut self, name: u8) -> bool {
+        if self.recording.is_some() || !name.is_ascii_alphabetic() {
+            return false
+        }
+        self.recording = Some((name, Vec::new()));
+        true
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // save the recorded macro into its register
+    pub fn stop_recording(&mut self) {
+        if let Some((name, commands)) = self.recording.take() {
+            self.set(name, Register::Macro(commands));
+        }
+    }
+
+    // Called with every command the user runs, replayed commands should not be passed in.
+    pub fn record(&mut self, command: &Command) {
+        if let Some((_, commands)) = &mut self.recording {
+            commands.push(command.clone());
+        }
+        match command {
+            Command::Insert(text) => {
+                let text = Register::Text(Clip {pieces: vec![text.clone()]});
+                match self.registers.get_mut(&LAST_INSERT) {
+                    Some(existing) if self.inserting => existing.append(text),
+                    _ => {
+                        self.registers.insert(LAST_INSERT, text);
+                    }
+                }
+                self.inserting = true;
+            }
+            Command::Search(text) => {
+                self.set_search(text);
+                self.inserting = false;
+            }
+            _ => self.inserting = false
+        }
+    }
+
+    // $XDG_DATA_HOME/editor/registers, or under ~/.local/share without it
+    pub fn default_path() -> Option<PathBuf> {
+        let data = match std::env::var_os("XDG_DATA_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+        };
+        Some(data.join("editor").join("registers"))
+    }
+
+    // Every register as text. A register is a line with its name, kind and number of entries,
+    // followed by the entries: length prefixed text pieces or encoded commands.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, register) in &self.registers {
+            match register {
+                Register::Text(clip) => {
+                    out.extend(format!("{} text {}\n", *name as char, clip.pieces.len()).as_bytes());
+                    for piece in &clip.pieces {
+                        out.extend(format!("{}:", piece.len()).as_bytes());
+                        out.extend(piece);
+                        out.push(b'\n');
+                    }
+                }
+                Register::Macro(commands) => {
+                    out.extend(format!("{} macro {}\n", *name as char, commands.len()).as_bytes());
+                    for command in commands {
+                        command.encode(&mut out);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    // Registers written by encode. Decoding stops at the first malformed register,
+    // so a damaged file loses what comes after the damage and nothing before it.
+    pub fn decode(data: &[u8]) -> Self {
+        let mut registers = Registers::default();
+        let mut pos = 0;
+        while let Some((name, register, len)) = decode_register(&data[pos..]) {
+            if writable(name) {
+                registers.registers.insert(name.to_ascii_lowercase(), register);
+            }
+            pos += len;
+        }
+        registers
+    }
+
+    // a missing file gives empty registers
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => Ok(Registers::decode(&data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Registers::default()),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // write then rename so a crash while saving keeps the old file
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, self.encode())?;
+        fs::rename(&temp, path)
+    }
+}
+
+// one register from the start of data, with the number of bytes it took
+fn decode_register(data: &[u8]) -> Option<(u8, Register, usize)> {
+    let end = data.iter().position(|b| *b == b'\n')?;
+    let header = std::str::from_utf8(&data[..end]).ok()?;
+    let mut fields = header.split(' ');
+    let name = fields.next()?.as_bytes();
+    let kind = fields.next()?;
+    let count: usize = fields.next()?.parse().ok()?;
+    if name.len() != 1 {
+        return None
+    }
+
+    let mut pos = end + 1;
+    let register = match kind {
+        "text" => {
+            let mut pieces = Vec::with_capacity(count.min(data.len()));
+            for _ in 0..count {
+                let rest = &data[pos..];
+                let colon = rest.iter().position(|b| *b == b':')?;
+                let len: usize = std::str::from_utf8(&rest[..colon]).ok()?.parse().ok()?;
+                pieces.push(rest.get(colon + 1..colon + 1 + len)?.to_vec());
+                if rest.get(colon + 1 + len) != Some(&b'\n') {
+                    return None
+                }
+                pos += colon + len + 2;
+            }
+            Register::Text(Clip {pieces})
+        }
+        "macro" => {
+            let mut commands = Vec::with_capacity(count.min(data.len()));
+            for _ in 0..count {
+                let (command, len) = Command::decode(&data[pos..])?;
+                commands.push(command);
+                pos += len;
+            }
+            Register::Macro(commands)
+        }
+        _ => return None
+    };
+    Some((name[0], register, pos))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(s: &str) -> Register {
+        Register::Text(Clip::from_text(s))
+    }
+
+    #[test]
+    fn test_append() {
+        let mut registers = Registers::default();
+        assert!(registers.set(b'a', text("one")));
+        assert!(registers.set(b'A', text(" two")));
+        assert_eq!(registers.get(b'a'), Some(&text("one two")));
+        // appending to an empty register sets it
+        registers.set(b'B', text("b"));
+        assert_eq!(registers.get(b'b'), Some(&text("b")));
+        assert!(!registers.set(b'1', text("x")));
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut registers = Registers::default();
+        registers.record(&Command::Insert(b"ab".to_vec()));
+        assert!(registers.start_recording(b'q'));
+        registers.record(&Command::Insert(b"c".to_vec()));
+        registers.record(&Command::DeleteBackward);
+        registers.record(&Command::Insert(b"d".to_vec()));
+        registers.stop_recording();
+        registers.record(&Command::Insert(b"e".to_vec()));
+        registers.record(&Command::Search(b"needle".to_vec()));
+        registers.record(&Command::Insert(b"f".to_vec()));
+
+        assert_eq!(registers.commands(b'q'), Some(&[
+            Command::Insert(b"c".to_vec()), Command::DeleteBackward, Command::Insert(b"d".to_vec())
+        ][..]));
+        // the last insert is the run of inserts since the last other command
+        assert_eq!(registers.get(LAST_INSERT), Some(&text("f")));
+        assert_eq!(registers.get(LAST_SEARCH), Some(&text("needle")));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut registers = Registers::default();
+        registers.set(b'a', Register::Text(Clip {pieces: vec![b"one\ntwo".to_vec(), b"".to_vec()]}));
+        registers.set(b'q', Register::Macro(vec![Command::Insert(b"x:y".to_vec()), Command::SplitIntoLines]));
+        registers.set_search(b"needle");
+
+        let data = registers.encode();
+        let decoded = Registers::decode(&data);
+        assert_eq!(decoded.registers, registers.registers);
+
+        // a truncated file keeps the registers before the damage
+        let decoded = Registers::decode(&data[..data.len() - 5]);
+        assert_eq!(decoded.registers.len(), 2);
+    }
+}
@@ -0,0 +1,423 @@
+// Soft wrap layout, which breaks every line of the buffer into visual lines that fit the window.
+// Lines are measured with shaped glyph advances and broken after whitespace, or inside a word
+// that is wider than the window. The layout of every line is cached until an edit touches it.
+// Lines hidden by folds take no rows, the row of the folded line is drawn with a placeholder after it.
+
+extern crate swash;
+
+use std::ops::Range;
+
+use swash::shape::ShapeContext;
+
+use crate::mark::{fold_at, lossy_str, EditDelta};
+use crate::text_renderer::{Advance, FontChain, TAB_SIZE};
+
+// Measures the clusters of a line of text
+pub trait LineShaper {
+    // byte offset and advance of every cluster of line, in order
+    fn clusters(&mut self, line: &str) -> Vec<(usize, f32)>;
+}
+
+// Shapes lines with swash and the fonts of a fallback chain, so ligatures, combining marks and
+// characters from fallback fonts are measured as they are drawn
+pub struct SwashShaper<'a> {
+    context: ShapeContext,
+    fonts: &'a FontChain,
+    size: f32,
+}
+
+impl<'a> SwashShaper<'a> {
+    pub fn new(fonts: &'a FontChain, size: f32) -> Self {
+        SwashShaper {context: ShapeContext::new(), fonts, size}
+    }
+}
+
+impl LineShaper for SwashShaper<'_> {
+    fn clusters(&mut self, line: &str) -> Vec<(usize, f32)> {
+        let mut clusters = Vec::new();
+        self.fonts.shape_with(&mut self.context, self.size, line, |_, start, _, _, advance| clusters.push((start, advance)));
+        clusters.sort_by_key(|cluster| cluster.0);
+        clusters
+    }
+}
+
+// without shaping every character is a cluster of its own
+impl<T: Advance> LineShaper for T {
+    fn clusters(&mut self, line: &str) -> Vec<(usize, f32)> {
+        line.char_indices().map(|(i, ch)| (i, self.advance(ch))).collect()
+    }
+}
+
+// A part of a line that is drawn as one row
+#[derive(Clone, Debug, PartialEq)]
+pub struct VisualLine {
+    // byte range in the buffer
+    pub range: Range<usize>,
+    // pixels the row is drawn from the left edge
+    pub indent: f32,
+    // the region hidden after the row, drawn as a placeholder
+    pub fold: Option<Range<usize>>,
+}
+
+struct LineLayout {
+    // offset in the line and x of every cluster, followed by the end of the line
+    xs: Vec<(usize, f32)>,
+    // offsets where the rows after the first one start
+    breaks: Vec<usize>,
+    // x the rows after the first one are drawn at
+    indent: f32,
+}
+
+impl LineLayout {
+    fn rows(&self) -> usize {
+        self.breaks.len() + 1
+    }
+
+    fn x(&self, offset: usize) -> f32 {
+        let i = self.xs.partition_point(|(start, _)| *start <= offset);
+        self.xs[i.saturating_sub(1)].1
+    }
+
+    // offsets of the start and end of a row
+    fn row_range(&self, row: usize, len: usize) -> Range<usize> {
+        let start = if row == 0 {0} else {self.breaks[row - 1]};
+        let end = self.breaks.get(row).copied().unwrap_or(len);
+        start..end
+    }
+
+    fn row_indent(&self, row: usize) -> f32 {
+        if row == 0 {0.} else {self.indent}
+    }
+}
+
+// A run of lines of the buffer, laid out as one line or waiting to be laid out again
+struct CachedLine {
+    start: usize,
+    len: usize,
+    // None after an edit, then the run may hold several lines
+    layout: Option<LineLayout>,
+}
+
+fn is_blank(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+// text drawn in place of the lines a fold hides
+pub fn fold_placeholder(text: &[u8], fold: Range<usize>) -> String {
+    let lines = text[fold].iter().filter(|b| **b == b'\n').count();
+    format!(" ⋯ {} lines", lines)
+}
+
+pub struct WrapLayout {
+    // width rows are wrapped to in pixels
+    width: f32,
+    // indent the rows after the first one as far as the line itself is indented
+    wrap_indent: bool,
+    lines: Vec<CachedLine>,
+    // false until the first update and after the width changes
+    valid: bool,
+    // hidden regions, see EditingBuffer::folds
+    folds: Vec<Range<usize>>,
+}
+
+impl WrapLayout {
+    pub fn new(width: f32, wrap_indent: bool) -> Self {
+        WrapLayout {width, wrap_indent, lines: Vec::new(), valid: false, folds: Vec::new()}
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        if width != self.width {
+            self.width = width;
+            self.valid = false;
+        }
+    }
+
+    pub fn set_wrap_indent(&mut self, wrap_indent: bool) {
+        if wrap_indent != self.wrap_indent {
+            self.wrap_indent = wrap_indent;
+            self.valid = false;
+        }
+    }
+
+    // folds only hide lines, so the lines keep their layout
+    pub fn set_folds(&mut self, folds: Vec<Range<usize>>) {
+        self.folds = folds;
+    }
+
+    // Drop the layout of every line an edit touched and shift the lines after it.
+    // The edits have to be applied in the order they were made.
+    pub fn apply_edits(&mut self, edits: &[EditDelta]) {
+        if !self.valid {
+            return
+        }
+        for edit in edits {
+            // lines touching the edit, including one that ends right where it starts
+            let first = self.lines.partition_point(|line| line.start + line.len < edit.start);
+            let last = self.lines.partition_point(|line| line.start <= edit.end).max(first + 1);
+            let start = self.lines[first].start;
+            let end = self.lines[last - 1].start + self.lines[last - 1].len;
+            let len = end - start - (edit.end - edit.start) + edit.inserted;
+            self.lines.splice(first..last, std::iter::once(CachedLine {start, len, layout: None}));
+
+            for line in &mut self.lines[first + 1..] {
+                line.start = line.start + edit.inserted - (edit.end - edit.start);
+            }
+        }
+    }
+
+    pub fn needs_update(&self) -> bool {
+        !self.valid || self.lines.iter().any(|line| line.layout.is_none())
+    }
+
+    // lay out every line that changed, text is the whole contents of the buffer
+    pub fn update(&mut self, text: &[u8], shaper: &mut impl LineShaper) {
+        if !self.valid {
+            self.lines = vec![CachedLine {start: 0, len: text.len(), layout: None}];
+            self.valid = true;
+        }
+        let tab = shaper.clusters(" ").first().map_or(0., |space| space.1) * TAB_SIZE as f32;
+
+        let mut i = 0;
+        while i < self.lines.len() {
+            if self.lines[i].layout.is_some() {
+                i += 1;
+                continue
+            }
+            let (start, end) = (self.lines[i].start, self.lines[i].start + self.lines[i].len);
+            let mut lines = Vec::new();
+            let mut line_start = start;
+            loop {
+                let line_end = line_start + text[line_start..end].iter().position(|b| *b == b'\n').unwrap_or(end - line_start);
+                let layout = self.layout_line(&text[line_start..line_end], shaper, tab);
+                lines.push(CachedLine {start: line_start, len: line_end - line_start, layout: Some(layout)});
+                if line_end >= end {
+                    break
+                }
+                line_start = line_end + 1;
+            }
+            let count = lines.len();
+            self.lines.splice(i..i + 1, lines);
+            i += count;
+        }
+    }
+
+    fn layout_line(&self, bytes: &[u8], shaper: &mut impl LineShaper, tab: f32) -> LineLayout {
+        let mut xs = Vec::new();
+        let mut x = 0.;
+        for (offset, advance) in shaper.clusters(&lossy_str(bytes)) {
+            xs.push((offset, x));
+            x += if bytes[offset] == b'\t' && tab > 0. {tab - x % tab} else {advance};
+        }
+        xs.push((bytes.len(), x));
+
+        // the indent is capped so wrapped rows always have room for some text
+        let leading = bytes.iter().take_while(|b| is_blank(**b)).count();
+        let indent = if self.wrap_indent {xs[xs.partition_point(|c| c.0 < leading)].1.min(self.width / 2.)} else {0.};
+
+        let mut breaks = Vec::new();
+        let (mut row, mut row_x, mut row_indent) = (0, 0., 0.);
+        // the last cluster starting a word in the current row
+        let mut word_start = None;
+        let mut j = 0;
+        while j + 1 < xs.len() {
+            let offset = xs[j].0;
+            let blank = is_blank(bytes[offset]);
+            if j > row && !blank && is_blank(bytes[offset - 1]) {
+                word_start = Some(j);
+            }
+            // whitespace may hang past the edge, anything else starts a new row
+            if !blank && j > row && xs[j + 1].1 - row_x + row_indent > self.width {
+                let at = word_start.unwrap_or(j);
+                breaks.push(xs[at].0);
+                row = at;
+                row_x = xs[at].1;
+                row_indent = indent;
+                word_start = None;
+                j = at;
+                continue
+            }
+            j += 1;
+        }
+        LineLayout {xs, breaks, indent}
+    }
+
+    fn line_rows(&self, line: &CachedLine) -> usize {
+        if fold_at(&self.folds, line.start).is_some() {
+            return 0
+        }
+        line.layout.as_ref().map_or(1, LineLayout::rows)
+    }
+
+    // number of rows, the layout has to be up to date
+    pub fn rows(&self) -> usize {
+        self.lines.iter().map(|line| self.line_rows(line)).sum()
+    }
+
+    // index of the line holding pos and the number of rows before it
+    fn line_of(&self, pos: usize) -> (usize, usize) {
+        let i = self.lines.partition_point(|line| line.start <= pos).saturating_sub(1);
+        let rows = self.lines[..i].iter().map(|line| self.line_rows(line)).sum();
+        (i, rows)
+    }
+
+    // row and x of pos, a hidden pos is at the end of the folded line
+    pub fn position(&self, pos: usize) -> (usize, f32) {
+        let pos = fold_at(&self.folds, pos).map_or(pos, |fold| fold.start);
+        let (i, rows) = self.line_of(pos);
+        let line = &self.lines[i];
+        let layout = match &line.layout {
+            Some(layout) => layout,
+            None => return (rows, 0.)
+        };
+        let offset = pos - line.start;
+        let row = layout.breaks.partition_point(|b| *b <= offset);
+        let x = layout.x(offset) - layout.x(layout.row_range(row, line.len).start) + layout.row_indent(row);
+        (rows + row, x)
+    }
+
+    // the position in a row closest to x
+    pub fn pos_at(&self, row: usize, x: f32) -> usize {
+        let mut rows = 0;
+        for line in &self.lines {
+            let line_rows = self.line_rows(line);
+            if row >= rows + line_rows {
+                rows += line_rows;
+                continue
+            }
+            let layout = match &line.layout {
+                Some(layout) => layout,
+                None => return line.start
+            };
+
+            let row = row - rows;
+            let range = layout.row_range(row, line.len);
+            let left = layout.x(range.start) - layout.row_indent(row);
+            let clusters = layout.xs.iter().zip(&layout.xs[1..])
+                .filter(|(cluster, _)| range.contains(&cluster.0));
+            let mut last = range.start;
+            for ((offset, start), (_, end)) in clusters {
+                if x < (start + end) / 2. - left {
+                    return line.start + offset
+                }
+                last = *offset;
+            }
+            // the end of a wrapped row is the start of the next one, so stop before its last cluster
+            let end = if row + 1 < layout.rows() {last} else {line.len};
+            return line.start + end
+        }
+        self.lines.last().map_or(0, |line| line.start + line.len)
+    }
+
+    // every row in order, for drawing
+    pub fn visual_lines(&self) -> Vec<VisualLine> {
+        let mut rows = Vec::new();
+        for line in &self.lines {
+            if self.line_rows(line) == 0 {
+                continue
+            }
+            let end = line.start + line.len;
+            let fold = self.folds.iter().find(|fold| fold.start == end).cloned();
+            let layout = match &line.layout {
+                Some(layout) => layout,
+                None => {
+                    rows.push(VisualLine {range: line.start..end, indent: 0., fold});
+                    continue
+                }
+            };
+            for row in 0..layout.rows() {
+                let range = layout.row_range(row, line.len);
+                rows.push(VisualLine {
+                    range: line.start + range.start..line.start + range.end,
+                    indent: layout.row_indent(row),
+                    fold: if row + 1 == layout.rows() {fold.clone()} else {None},
+                });
+            }
+        }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // every character is 10px wide
+    struct Mono;
+
+    impl Advance for Mono {
+        fn advance(&self, _: char) -> f32 {
+            10.
+        }
+    }
+
+    fn rows(layout: &WrapLayout, text: &str) -> Vec<String> {
+        layout.visual_lines().into_iter().map(|row| text[row.range].to_string()).collect()
+    }
+
+    #[test]
+    fn test_wrap() {
+        let text = "the quick brown fox\n  jumps over the lazy dog\nsupercalifragilistic";
+        let mut layout = WrapLayout::new(100., true);
+        layout.update(text.as_bytes(), &mut Mono);
+        assert_eq!(rows(&layout, text), vec![
+            "the quick ", "brown fox",
+            "  jumps ", "over the ", "lazy dog",
+            "supercalif", "ragilistic",
+        ]);
+        // rows after the first are indented like the line
+        assert_eq!(layout.visual_lines()[3].indent, 20.);
+        assert_eq!(layout.rows(), 7);
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "aaaa bbbb cccc\nd";
+        let mut layout = WrapLayout::new(100., false);
+        layout.update(text.as_bytes(), &mut Mono);
+        assert_eq!(rows(&layout, text), vec!["aaaa bbbb ", "cccc", "d"]);
+        assert_eq!(layout.position(12), (1, 20.));
+        assert_eq!(layout.pos_at(1, 24.), 12);
+        assert_eq!(layout.pos_at(0, 500.), 9);
+        assert_eq!(layout.pos_at(1, 500.), 14);
+        assert_eq!(layout.pos_at(2, 0.), 15);
+    }
+
+    #[test]
+    fn test_edits() {
+        let mut text = "one two three\nfour\nfive six".to_string();
+        let mut layout = WrapLayout::new(60., false);
+        layout.update(text.as_bytes(), &mut Mono);
+        assert_eq!(layout.rows(), 6);
+
+        // join the first two lines
+        text.replace_range(13..14, " ");
+        layout.apply_edits(&[EditDelta {start: 13, end: 14, inserted: 1}]);
+        // the line after them keeps its layout
+        assert_eq!(layout.lines.len(), 2);
+        assert!(layout.lines[1].layout.is_some());
+
+        // add a line at the end
+        text.push_str("\nseven");
+        layout.apply_edits(&[EditDelta {start: 27, end: 27, inserted: 6}]);
+        layout.update(text.as_bytes(), &mut Mono);
+        assert_eq!(rows(&layout, &text), vec!["one ", "two ", "three ", "four", "five ", "six", "seven"]);
+    }
+
+    #[test]
+    fn test_folds() {
+        let text = "fn a() {\n    b();\n    c();\n}\nd";
+        let mut layout = WrapLayout::new(100., false);
+        layout.update(text.as_bytes(), &mut Mono);
+        layout.set_folds(std::iter::once(8..26).collect());
+        assert_eq!(rows(&layout, text), vec!["fn a() {", "}", "d"]);
+        assert_eq!(layout.visual_lines()[0].fold, Some(8..26));
+        assert_eq!(fold_placeholder(text.as_bytes(), 8..26), " ⋯ 2 lines");
+
+        // hidden lines take no rows
+        assert_eq!(layout.rows(), 3);
+        assert_eq!(layout.position(27), (1, 0.));
+        assert_eq!(layout.position(14), (0, 80.));
+        assert_eq!(layout.pos_at(1, 0.), 27);
+        assert_eq!(layout.pos_at(2, 0.), 29);
+    }
+}
@@ -3,29 +3,137 @@ extern crate swash;
 extern crate zeno;
 
 mod text_renderer;
-use text_renderer::{GlyphRenderer, FontData};
+use text_renderer::{Advance, FontChain, FontMetrics, FontRegistry, FontRequest, GlyphAtlas, GlyphRenderer, ImageBuffer, RenderOptions, ShapedText};
+
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::{Window, WindowContext};
 
 mod span_table;
-use span_table::{SpanTable};
 
 mod mark;
+use mark::{lossy_str, Command, EditingBuffer, FoldKind, ShortLines};
+
+mod layout;
+use layout::{fold_placeholder, SwashShaper, WrapLayout};
+
+mod clipboard;
+use clipboard::{Clipboards, PrimarySelection, SystemClipboard};
+use clipboard::register::{Registers, LAST_SEARCH};
+
+// families tried in order for characters the primary font has no glyph for
+const FALLBACK_FONTS: [&str; 6] = ["Noto Sans Mono CJK SC", "Noto Sans CJK SC", "Noto Color Emoji", "Noto Sans Symbols", "Noto Sans Math", "sans-serif"];
+
+// Draw every run of shaped with its font of the chain, composited into row when there is one,
+// otherwise copied from the atlas. baseline is in row or in the canvas.
+#[allow(clippy::too_many_arguments)]
+fn draw_shaped(
+  canvas: &mut Canvas<Window>,
+  atlas: &mut GlyphAtlas<WindowContext>,
+  mut row: Option<&mut ImageBuffer>,
+  glyph_renderer: &mut GlyphRenderer,
+  fonts: &FontChain,
+  size: f32,
+  shaped: &ShapedText,
+  colors: ([u8; 4], [u8; 4]),
+  baseline: f32,
+) {
+  for (font, glyphs) in shaped.runs() {
+    let font = fonts.font(font).as_ref();
+    match row.as_deref_mut() {
+      Some(row) => glyph_renderer.render_run(font, size as u32, glyphs, colors.0, row, (0., baseline)),
+      None => atlas.draw_run(canvas, glyph_renderer, font, size as u32, glyphs, colors, (0., baseline)).unwrap(),
+    }
+  }
+}
+
+// height of a row of text in pixels
+fn line_height(fonts: &FontChain, size: f32) -> u32 {
+  let font_metrics = fonts.primary().as_ref().metrics(&[]).scale(size);
+  (font_metrics.ascent + font_metrics.descent + font_metrics.leading).ceil() as u32
+}
+
+// how many rows fit in the window, which is how far Page Up and Page Down move
+fn page_lines(canvas: &Canvas<Window>, row_height: u32) -> usize {
+  (canvas.window().size().1 / row_height.max(1)).max(1) as usize
+}
+
+// Draw the rows of the layout that fit in the window, copying every glyph from the atlas.
+// The software renderer copies every glyph twice, so there each row is composited on the cpu and copied once.
+#[allow(clippy::too_many_arguments)]
+fn draw_rows(
+  canvas: &mut Canvas<Window>,
+  atlas: &mut GlyphAtlas<WindowContext>,
+  glyph_renderer: &mut GlyphRenderer,
+  shape_ctx: &mut swash::shape::ShapeContext,
+  fonts: &FontChain,
+  size: f32,
+  layout: &WrapLayout,
+  text: &[u8],
+  colors: ([u8; 4], [u8; 4]),
+) {
+  let destcolor = colors.1;
+  let placeholder_color = [120, 120, 120, 255];
+  let font = fonts.primary();
+  let font_metrics = font.as_ref().metrics(&[]).scale(size);
+  let line_height = line_height(fonts, size);
+  let tab = FontMetrics::new(font.as_ref(), size as u32).tab_width();
+  let (width, height) = canvas.window().size();
+
+  canvas.set_draw_color(sdl2::pixels::Color::RGBA(destcolor[0], destcolor[1], destcolor[2], destcolor[3]));
+  canvas.clear();
+
+  let texture_creator = canvas.texture_creator();
+  let mut row_texture = if canvas.info().name == "software" {
+    texture_creator.create_texture_streaming(PixelFormatEnum::ABGR8888, width, line_height).ok()
+  } else {
+    None
+  };
+
+  let mut shaped = ShapedText::default();
+  for (i, row) in layout.visual_lines().into_iter().take((height / line_height + 1) as usize).enumerate() {
+    let top = (i as u32 * line_height) as i32;
+    let mut buffer = row_texture.is_some().then(|| ImageBuffer::new(width, line_height, destcolor));
+    let baseline = if buffer.is_some() {0.} else {top as f32} + font_metrics.ascent;
+    shaped.clear();
+    let end = fonts.shape(shape_ctx, size, tab, &lossy_str(&text[row.range]), row.indent, &mut shaped);
+    draw_shaped(canvas, atlas, buffer.as_mut(), glyph_renderer, fonts, size, &shaped, colors, baseline);
+    if let Some(fold) = row.fold {
+      shaped.clear();
+      fonts.shape(shape_ctx, size, tab, &fold_placeholder(text, fold), end, &mut shaped);
+      draw_shaped(canvas, atlas, buffer.as_mut(), glyph_renderer, fonts, size, &shaped, (placeholder_color, destcolor), baseline);
+    }
+    if let (Some(texture), Some(buffer)) = (&mut row_texture, &buffer) {
+      texture.update(None, &buffer.data, width as usize * 4).unwrap();
+      canvas.copy(texture, None, sdl2::rect::Rect::new(0, top, width, line_height)).unwrap();
+    }
+  }
+  canvas.present();
+}
 
+// what Ctrl+F searches for: the selection of the last cursor, or the last search for a caret
+fn search_text(editing_buffer: &EditingBuffer, registers: &Registers) -> Option<Vec<u8>> {
+  let selected = editing_buffer.selections().pieces.pop().unwrap_or_default();
+  if !selected.is_empty() {
+    return Some(selected)
+  }
+  Some(registers.text(LAST_SEARCH)?.joined())
+}
 
 fn main() {
   let mut font_manager = GlyphRenderer::default();
-  let go_mono = FontData::from_file("/usr/share/fonts/TTF/FiraCode-Regular.ttf", 0).unwrap();
-  
+  // falls back to the font bundled with the editor when no monospace font is installed
+  let font_request = FontRequest::parse("monospace 14");
+  let fonts = FontRegistry::system().load_chain(&font_request, &FALLBACK_FONTS);
+
 
   let textcolor: [u8; 4] = [30, 30, 30, 255];
   let destcolor: [u8; 4] = [230, 230, 230, 255];
-  let fm = &mut font_manager;
 
-  let size = 14.0;
+  let size = font_request.size;
   let mut shape_ctx = swash::shape::ShapeContext::new();
-  let mut shaper = shape_ctx.builder(go_mono.as_ref())
-    .script(swash::text::Script::Latin)
-    .size(size)
-    .build();
   
   let sdl = sdl2::init().unwrap();
 
@@ -41,60 +149,153 @@ fn main() {
 
   let mut canvas = window.into_canvas().build().unwrap();
   let texture_creator = canvas.texture_creator();
-  /*
-  canvas.set_draw_color(sdl2::pixels::Color::RGBA(230, 230, 230, 255));
-  canvas.clear();
-
-  shaper.add_str("hello world =>;");
-  let mut x = 5.0;
-  shaper.shape_with(|gc: &swash::shape::cluster::GlyphCluster<'_>| {
-    for glyph in gc.glyphs {
-      let mut img = fm.render_glyph(go_mono.as_ref(), size as u32, glyph.id, textcolor, destcolor);
-      if img.height == 0 || img.width == 0 {x += glyph.advance; continue}
-      let surface = sdl2::surface::Surface::from_data(&mut img.data, img.width, img.height, img.width*4, sdl2::pixels::PixelFormatEnum::ABGR8888).unwrap();
-      let texture = texture_creator
-        .create_texture_from_surface(&surface)
-        .unwrap();
-    
-      let sdl2::render::TextureQuery {width, height, ..} = texture.query();
-      canvas.copy(&texture, None, sdl2::rect::Rect::new(x.round() as i32 + img.left as i32, 20-img.top as i32, width, height)).unwrap();
-      x += glyph.advance;
-    }
-  });*/
+  let mut atlas = GlyphAtlas::new(&texture_creator);
   
   canvas.present(); 
   
-  // TODO: implement mark/cursor library with better editing primitives
-  let mut buffer: Vec<u8> = Vec::new();
-
-  fn span(buffer: &mut Vec<u8>, add: &str) -> span_table::Span {
-    let span = span_table::Span {
-        start: buffer.len(),
-        end: buffer.len() + add.len()
-    };
-    buffer.extend(add.as_bytes());
-    span
-}
-  
-  let mut span_table = SpanTable::default();
+  // the file named on the command line, or an empty buffer
+  let mut editing_buffer = match std::env::args_os().nth(1) {
+    Some(path) => EditingBuffer::load(std::path::Path::new(&path)).unwrap_or_else(|err| {
+      println!("could not open {}: {}", path.to_string_lossy(), err);
+      EditingBuffer::default()
+    }),
+    None => EditingBuffer::default(),
+  };
+  editing_buffer.new_cursor();
+
+  let mut primary = PrimarySelection::new(&video_subsystem);
+
+  let registers_path = Registers::default_path();
+  let registers = registers_path.as_deref()
+    .and_then(|path| Registers::load(path).ok())
+    .unwrap_or_else(Registers::default);
+  let mut clipboards = Clipboards::new(SystemClipboard::new(&video_subsystem), registers);
+  let metrics = FontMetrics::new(fonts.primary().as_ref(), size as u32);
+
+  let mut line_shaper = SwashShaper::new(&fonts, size);
+  let mut layout = WrapLayout::new(canvas.window().size().0 as f32, true);
+  let row_height = line_height(&fonts, size);
+
+  // set by Ctrl+R, the next character typed names the register to paste from, or with shift to yank into
+  let mut register_prefix: Option<bool> = None;
 
   let mut event_pump = sdl.event_pump().unwrap();
+  // draw again only after something happened
+  let mut dirty = true;
   'main: loop {
     for event in event_pump.poll_iter() {
+      dirty = true;
+      // keep the layout in step with the buffer before handling anything that uses it
+      editing_buffer.sync_layout(&mut layout, &mut line_shaper);
+
       match event {
-        sdl2::event::Event::KeyDown { keycode: Some(keycode), .. } => {
-          println!("keydown");
+        sdl2::event::Event::KeyDown { keycode: Some(keycode), keymod, .. } => {
+          let ctrl = keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD);
+          let alt = keymod.intersects(Mod::LALTMOD | Mod::RALTMOD);
+          let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+          let command = match keycode {
+            Keycode::Return => Some(Command::Newline),
+            Keycode::Tab if shift => Some(Command::Dedent),
+            Keycode::Tab => Some(Command::Indent),
+            Keycode::Backspace => Some(Command::DeleteBackward),
+            Keycode::Delete => Some(Command::DeleteForward),
+            Keycode::D if ctrl => Some(Command::Duplicate),
+            Keycode::K if ctrl && shift => Some(Command::DeleteLines),
+            Keycode::J if ctrl => Some(Command::JoinLines),
+            Keycode::T if ctrl => Some(Command::TransposeChars),
+            Keycode::T if alt => Some(Command::TransposeWords),
+            Keycode::Up if alt => Some(Command::MoveLinesUp),
+            Keycode::Down if alt => Some(Command::MoveLinesDown),
+            Keycode::Up => Some(Command::VisualVertical(-1, shift)),
+            Keycode::Down => Some(Command::VisualVertical(1, shift)),
+            Keycode::PageUp => Some(Command::PageUp(page_lines(&canvas, row_height), shift)),
+            Keycode::PageDown => Some(Command::PageDown(page_lines(&canvas, row_height), shift)),
+            // the selections become blocks, with shift lines shorter than a block are left out of its edits
+            Keycode::B if ctrl && shift => Some(Command::Block(ShortLines::Skip)),
+            Keycode::B if ctrl => Some(Command::Block(ShortLines::Pad)),
+            Keycode::Slash if ctrl => Some(Command::ToggleComment),
+            Keycode::F if ctrl => search_text(&editing_buffer, &clipboards.registers).map(Command::Search),
+            // the usual width for commit messages
+            Keycode::Q if alt => Some(Command::Reflow(72)),
+            Keycode::LeftBracket if ctrl && alt => Some(Command::Fold(FoldKind::Indent)),
+            Keycode::LeftBracket if ctrl && shift => Some(Command::Fold(FoldKind::Selection)),
+            Keycode::LeftBracket if ctrl => Some(Command::Fold(FoldKind::Bracket)),
+            Keycode::RightBracket if ctrl && shift => Some(Command::UnfoldAll),
+            Keycode::RightBracket if ctrl => Some(Command::Unfold),
+            Keycode::C if ctrl => Some(Command::Copy),
+            Keycode::X if ctrl => Some(Command::Cut),
+            Keycode::V if ctrl => Some(Command::Paste),
+            Keycode::Y if alt => Some(Command::YankPop),
+            _ => None,
+          };
+          if let Some(command) = command {
+            editing_buffer.run(&command, &metrics, &mut layout, &mut line_shaper, &mut clipboards);
+            clipboards.registers.record(&command);
+            continue
+          }
+
+          match keycode {
+            Keycode::R if ctrl => register_prefix = Some(shift),
+            Keycode::Escape => register_prefix = None,
+            // try the kinds of antialiasing and hinting on the screen at hand
+            Keycode::F5 => {
+              let options = font_manager.render_options();
+              font_manager.set_render_options(RenderOptions {antialias: options.antialias.next(), ..options});
+            },
+            Keycode::F6 => {
+              let options = font_manager.render_options();
+              font_manager.set_render_options(RenderOptions {hinting: !options.hinting, ..options});
+            },
+            // record a macro into register q, then replay it
+            Keycode::F3 if clipboards.registers.is_recording() => clipboards.registers.stop_recording(),
+            Keycode::F3 => {
+              clipboards.registers.start_recording(b'q');
+            },
+            // copied out, the macro can yank into registers while it runs
+            Keycode::F4 => if let Some(commands) = clipboards.registers.commands(b'q').map(|commands| commands.to_vec()) {
+              editing_buffer.replay(&commands, 1, &metrics, &mut layout, &mut line_shaper, &mut clipboards);
+            },
+            _ => {},
+          }
+        },
+        sdl2::event::Event::MouseButtonDown { mouse_btn: MouseButton::Middle, x, y, .. } => {
+          let row = y.max(0) as u32 / row_height;
+          editing_buffer.paste_primary(&primary, layout.pos_at(row as usize, x as f32));
         },
         sdl2::event::Event::TextInput { text, .. } => {
-          let new = span(&mut buffer, &text);
-          span_table.insert_span(new, span_table.span_len());
-          println!("textinput: {}", text);
-          println!("buffer: {:?}", String::from_utf8_lossy(&buffer));
-          println!("spans: {:?}", span_table.spans(&buffer));
+          let command = match register_prefix.take() {
+            Some(true) => Command::YankRegister(text.as_bytes()[0]),
+            Some(false) => Command::PasteRegister(text.as_bytes()[0]),
+            None => Command::Insert(text.as_bytes().to_vec()),
+          };
+          editing_buffer.run(&command, &metrics, &mut layout, &mut line_shaper, &mut clipboards);
+          clipboards.registers.record(&command);
+        },
+        sdl2::event::Event::Window { win_event: sdl2::event::WindowEvent::Resized(width, _), .. } => {
+          layout.set_width(width as f32);
+        },
+        sdl2::event::Event::Quit {..} => {
+          if let Some(path) = &registers_path {
+            if let Err(err) = clipboards.registers.save(path) {
+              println!("could not save registers: {}", err);
+            }
+          }
+          break 'main
         },
-        sdl2::event::Event::Quit {..} => break 'main,
         _ => {},
       }
     }
+
+    if dirty {
+      // whatever is selected now can be pasted into other programs with a middle click
+      editing_buffer.set_primary(&mut primary);
+      editing_buffer.sync_layout(&mut layout, &mut line_shaper);
+      let text = editing_buffer.contents();
+      draw_rows(
+        &mut canvas, &mut atlas, &mut font_manager, &mut shape_ctx,
+        &fonts, size, &layout, &text, (textcolor, destcolor),
+      );
+      dirty = false;
+    }
   }
 }
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::Range;
+
+// Anything that lives at a byte position in the buffer and has to move when it is edited
+pub trait Anchor: Copy {
+    // sort key, the first element is the byte position used for range queries
+    fn key(&self) -> (usize, usize);
+    // the last byte position covered, an edit starting after it leaves the anchor alone
+    fn reach(&self) -> usize;
+    // move after start..end was replaced with inserted bytes
+    fn adjust(&mut self, start: usize, end: usize, inserted: usize);
+    // move by delta bytes after edits that were all before it
+    fn shift(&mut self, delta: isize);
+}
+
+// Generational handle into an AnchorMap, stale handles never alias a newer entry
+pub struct Id<T> {
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+// derive would put the bounds on T
+impl<T> Clone for Id<T> {
+    fn clone(&self) -> Self {*self}
+}
+impl<T> Copy for Id<T> {}
+impl<T> PartialEq for Id<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+impl<T> Eq for Id<T> {}
+impl<T> std::hash::Hash for Id<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+impl<T> std::fmt::Debug for Id<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Id({}v{})", self.index, self.generation)
+    }
+}
+
+// total bytes and number of edits entries were shifted by
+type Shift = (isize, u32);
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+    // for entries in the shifted tail, the shift of the tail when the value was stored
+    seen: Option<Shift>,
+}
+
+// Slot map which also keeps its entries sorted by position so edits only touch the entries around them.
+// Like the gap of a gap buffer, the entries from shifted_from on have not moved by shift yet. Moving
+// them costs nothing, so an edit only adjusts the entries touching it and the ones between it and the last edit.
+pub struct AnchorMap<T: Anchor> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+    // live ids sorted by key
+    order: Vec<Id<T>>,
+    shifted_from: usize,
+    shift: Shift,
+    // how many entries span each number of bytes, the widest tells how far before an edit an entry can touch it
+    widths: BTreeMap<usize, usize>,
+}
+
+impl<T: Anchor> Default for AnchorMap<T> {
+    fn default() -> Self {
+        AnchorMap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            order: Vec::new(),
+            shifted_from: 0,
+            shift: (0, 0),
+            widths: BTreeMap::new(),
+        }
+    }
+}
+
+fn width<T: Anchor>(value: &T) -> usize {
+    value.reach() - value.key().0
+}
+
+impl<T: Anchor> AnchorMap<T> {
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn get(&self, id: Id<T>) -> Option<T> {
+        let slot = self.slots.get(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None
+        }
+        slot.value.map(|value| self.shifted(value, slot.seen))
+    }
+
+    // value moved by the shift of the tail since it was stored
+    fn shifted(&self, mut value: T, seen: Option<Shift>) -> T {
+        if let Some((delta, edits)) = seen {
+            if edits != self.shift.1 {
+                value.shift(self.shift.0 - delta);
+            }
+        }
+        value
+    }
+
+    fn value_of(&self, id: Id<T>) -> T {
+        let slot = &self.slots[id.index as usize];
+        self.shifted(slot.value.unwrap(), slot.seen)
+    }
+
+    fn key_of(&self, id: Id<T>) -> (usize, usize) {
+        self.value_of(id).key()
+    }
+
+    // store value for the entry at index at in order
+    fn store(&mut self, at: usize, value: T) {
+        let seen = if at >= self.shifted_from {Some(self.shift)} else {None};
+        let slot = &mut self.slots[self.order[at].index as usize];
+        slot.value = Some(value);
+        slot.seen = seen;
+    }
+
+    // Move the start of the shifted tail to index at of order
+    fn shift_from(&mut self, at: usize) {
+        if at > self.shifted_from {
+            // entries leaving the tail take the shift with them
+            for i in self.shifted_from..at {
+                let id = self.order[i];
+                let value = self.value_of(id);
+                let slot = &mut self.slots[id.index as usize];
+                slot.value = Some(value);
+                slot.seen = None;
+            }
+        } else {
+            // entries joining it are stored with the current shift, so they do not move
+            for i in at..self.shifted_from {
+                self.slots[self.order[i].index as usize].seen = Some(self.shift);
+            }
+        }
+        self.shifted_from = at;
+    }
+
+    fn add_width(&mut self, width: usize) {
+        *self.widths.entry(width).or_default() += 1;
+    }
+
+    fn remove_width(&mut self, width: usize) {
+        let count = self.widths.get_mut(&width).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.widths.remove(&width);
+        }
+    }
+
+    // index into order of the first entry with a key not less than key
+    fn lower_bound(&self, key: (usize, usize)) -> usize {
+        self.order.partition_point(|id| self.key_of(*id) < key)
+    }
+
+    fn order_index(&self, id: Id<T>) -> usize {
+        let key = self.key_of(id);
+        let first = self.lower_bound(key);
+        first + self.order[first..].iter().position(|x| *x == id).unwrap()
+    }
+
+    // put id into order where value goes
+    fn place(&mut self, id: Id<T>, value: T) {
+        let at = self.lower_bound(value.key());
+        self.order.insert(at, id);
+        if at < self.shifted_from {
+            self.shifted_from += 1;
+        }
+        self.store(at, value);
+        self.add_width(width(&value));
+    }
+
+    // take id out of order
+    fn unplace(&mut self, id: Id<T>) {
+        let at = self.order_index(id);
+        self.order.remove(at);
+        if at < self.shifted_from {
+            self.shifted_from -= 1;
+        }
+        self.remove_width(width(&self.value_of(id)));
+    }
+
+    pub fn insert(&mut self, value: T) -> Id<T> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {generation: 0, value: None, seen: None});
+                (self.slots.len() - 1) as u32
+            }
+        };
+        let id = Id {index, generation: self.slots[index as usize].generation, _marker: PhantomData};
+        self.place(id, value);
+        id
+    }
+
+    pub fn remove(&mut self, id: Id<T>) -> Option<T> {
+        let value = self.get(id)?;
+        self.unplace(id);
+
+        let slot = &mut self.slots[id.index as usize];
+        slot.value = None;
+        slot.seen = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        Some(value)
+    }
+
+    // replace the value behind id, returns false for a stale id
+    pub fn set(&mut self, id: Id<T>, value: T) -> bool {
+        if self.get(id).is_none() {
+            return false
+        }
+        self.unplace(id);
+        self.place(id, value);
+        true
+    }
+
+    // entries in position order
+    pub fn iter(&self) -> impl Iterator<Item = (Id<T>, T)> + '_ {
+        self.order.iter().map(move |id| (*id, self.value_of(*id)))
+    }
+
+    // entries whose position lies inside of range, in position order
+    pub fn range(&self, range: Range<usize>) -> impl Iterator<Item = (Id<T>, T)> + '_ {
+        let first = self.lower_bound((range.start, 0));
+        let last = self.lower_bound((range.end, 0)).max(first);
+        self.order[first..last].iter().map(move |id| (*id, self.value_of(*id)))
+    }
+
+    // move every entry after start..end was replaced with inserted bytes
+    pub fn adjust(&mut self, start: usize, end: usize, inserted: usize) {
+        // an entry starting before the edit touches it if it reaches start, none reaches further than the widest
+        let widest = self.widths.keys().next_back().copied().unwrap_or(0);
+        let first = self.lower_bound((start.saturating_sub(widest), 0));
+        // entries touching the edit can collapse onto each other and change order,
+        // everything after them moves by the same amount and stays sorted, so they join the shifted tail
+        let window_end = self.lower_bound((end + 1, 0));
+        self.shift_from(window_end);
+        for i in first..window_end {
+            let id = self.order[i];
+            let mut value = self.value_of(id);
+            if value.reach() < start {
+                continue
+            }
+            self.remove_width(width(&value));
+            value.adjust(start, end, inserted);
+            self.add_width(width(&value));
+            self.slots[id.index as usize].value = Some(value);
+        }
+        let delta = inserted as isize - (end - start) as isize;
+        if delta != 0 {
+            self.shift = (self.shift.0 + delta, self.shift.1.wrapping_add(1));
+        }
+
+        let slots = &self.slots;
+        self.order[first..window_end].sort_by_key(|id| slots[id.index as usize].value.unwrap().key());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Pos(usize);
+
+    impl Anchor for Pos {
+        fn key(&self) -> (usize, usize) {(self.0, 0)}
+        fn reach(&self) -> usize {self.0}
+        fn adjust(&mut self, start: usize, end: usize, inserted: usize) {
+            if self.0 > end {
+                self.0 = self.0 - (end - start) + inserted;
+            } else if self.0 >= start {
+                self.0 = start;
+            }
+        }
+        fn shift(&mut self, delta: isize) {self.0 = (self.0 as isize + delta) as usize}
+    }
+
+    fn positions(map: &AnchorMap<Pos>) -> Vec<usize> {
+        map.iter().map(|(_, p)| p.0).collect()
+    }
+
+    #[test]
+    fn test_generations() {
+        let mut map = AnchorMap::default();
+        let a = map.insert(Pos(3));
+        assert_eq!(map.remove(a), Some(Pos(3)));
+        let b = map.insert(Pos(4));
+        // the slot is reused but the old handle stays dead
+        assert_eq!(map.get(a), None);
+        assert_eq!(map.get(b), Some(Pos(4)));
+        assert!(!map.set(a, Pos(1)));
+        assert_eq!(map.remove(a), None);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_sorted() {
+        let mut map = AnchorMap::default();
+        let a = map.insert(Pos(10));
+        map.insert(Pos(2));
+        map.insert(Pos(6));
+        assert_eq!(positions(&map), vec![2, 6, 10]);
+
+        map.set(a, Pos(0));
+        assert_eq!(positions(&map), vec![0, 2, 6]);
+
+        let found: Vec<usize> = map.range(1..7).map(|(_, p)| p.0).collect();
+        assert_eq!(found, vec![2, 6]);
+    }
+
+    #[test]
+    fn test_adjust() {
+        let mut map = AnchorMap::default();
+        for pos in &[1, 3, 5, 8, 12] {
+            map.insert(Pos(*pos));
+        }
+        // replace 3..8 with 2 bytes
+        map.adjust(3, 8, 2);
+        assert_eq!(positions(&map), vec![1, 3, 3, 3, 9]);
+    }
+
+    // a range whose ends move like positions
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Span(Pos, Pos);
+
+    impl Anchor for Span {
+        fn key(&self) -> (usize, usize) {(self.0.0, self.1.0)}
+        fn reach(&self) -> usize {self.1.0}
+        fn adjust(&mut self, start: usize, end: usize, inserted: usize) {
+            self.0.adjust(start, end, inserted);
+            self.1.adjust(start, end, inserted);
+        }
+        fn shift(&mut self, delta: isize) {
+            self.0.shift(delta);
+            self.1.shift(delta);
+        }
+    }
+
+    #[test]
+    fn test_adjust_spanning() {
+        let mut map = AnchorMap::default();
+        let wide = map.insert(Span(Pos(0), Pos(20)));
+        let after = map.insert(Span(Pos(12), Pos(14)));
+        // the edit is inside of wide, which starts before it
+        map.adjust(10, 10, 3);
+        assert_eq!(map.get(wide), Some(Span(Pos(0), Pos(23))));
+        assert_eq!(map.get(after), Some(Span(Pos(15), Pos(17))));
+    }
+
+    #[test]
+    fn test_adjust_lazily() {
+        // edits all over the place, checked against adjusting every entry right away
+        let mut map = AnchorMap::default();
+        let mut expected: Vec<(Id<Span>, Span)> = Vec::new();
+        let mut seed = 7usize;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        for step in 0..400 {
+            let len = expected.iter().map(|(_, span)| span.1.0).max().unwrap_or(0) + 10;
+            match random(4) {
+                0 => {
+                    let start = random(len);
+                    let span = Span(Pos(start), Pos(start + random(8)));
+                    expected.push((map.insert(span), span));
+                }
+                1 if !expected.is_empty() => {
+                    let (id, _) = expected.swap_remove(random(expected.len()));
+                    map.remove(id);
+                }
+                _ => {
+                    let start = random(len);
+                    let end = start + random(4);
+                    let inserted = random(5);
+                    map.adjust(start, end, inserted);
+                    for (_, span) in &mut expected {
+                        span.adjust(start, end, inserted);
+                    }
+                }
+            }
+            for (id, span) in &expected {
+                assert_eq!(map.get(*id), Some(*span), "step {}", step);
+            }
+            let keys: Vec<(usize, usize)> = map.iter().map(|(_, span)| span.key()).collect();
+            assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]), "step {}", step);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// Auto pairing of brackets and quotes while typing
+
+use std::ops::Range;
+
+use super::{Cursor, CursorId, EditingBuffer, Gravity};
+use super::indent::closing_dedent;
+use super::motion::{char_at, char_before, char_class, CharClass};
+
+// an edit made for one cursor and where the cursor ends up relative to the start of the edit
+type PairEdit = (CursorId, Range<usize>, Vec<u8>, Range<usize>);
+
+// Which characters get paired for a language
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PairRules {
+    // open and close character, the same character for quotes
+    pub pairs: Vec<(u8, u8)>,
+}
+
+impl Default for PairRules {
+    fn default() -> Self {
+        PairRules {pairs: vec![(b'(', b')'), (b'[', b']'), (b'{', b'}'), (b'"', b'"'), (b'\'', b'\'')]}
+    }
+}
+
+impl PairRules {
+    // rules for a file extension, languages without special rules get the default ones
+    pub fn for_extension(extension: &str) -> Self {
+        let mut rules = PairRules::default();
+        match extension {
+            // ' starts a lifetime or a quoted form
+            "rs" | "lisp" | "el" | "scm" | "clj" => rules.pairs.retain(|pair| pair.0 != b'\''),
+            "html" | "xml" | "svg" => rules.pairs.push((b'<', b'>')),
+            "md" | "js" | "ts" | "go" | "sh" => rules.pairs.push((b'`', b'`')),
+            _ => {}
+        }
+        rules
+    }
+
+    fn by_open(&self, ch: u8) -> Option<(u8, u8)> {
+        self.pairs.iter().copied().find(|pair| pair.0 == ch)
+    }
+
+    fn is_close(&self, ch: u8) -> bool {
+        self.pairs.iter().any(|pair| pair.1 == ch)
+    }
+}
+
+// Whether typing open at pos should insert the closing character too.
+// Only before whitespace or a closing character, and quotes not right after a word character
+// so apostrophes and closing quotes are typed normally.
+fn should_pair(text: &[u8], pos: usize, rules: &PairRules, (open, close): (u8, u8)) -> bool {
+    let next_ok = match char_at(text, pos) {
+        None => true,
+        Some((ch, _)) => ch.is_whitespace() || (ch.is_ascii() && rules.is_close(ch as u8)),
+    };
+    if open != close {
+        return next_ok
+    }
+    let prev_ok = match char_before(text, pos) {
+        None => true,
+        Some((ch, _)) => ch as u32 != open as u32 && char_class(ch) != CharClass::Word,
+    };
+    next_ok && prev_ok
+}
+
+// the range backspace deletes for a caret between an empty pair
+pub fn pair_backspace(text: &[u8], pos: usize, rules: &PairRules) -> Option<Range<usize>> {
+    let (_, close) = rules.by_open(*text.get(pos.checked_sub(1)?)?)?;
+    if text.get(pos) == Some(&close) {
+        Some(pos - 1..pos + 1)
+    } else {
+        None
+    }
+}
+
+impl EditingBuffer {
+    pub fn pair_rules(&self) -> &PairRules {
+        &self.pairs
+    }
+
+    pub fn set_pair_rules(&mut self, rules: PairRules) {
+        self.pairs = rules;
+    }
+
+    // forget auto inserted closing characters that have been deleted or typed over
+    fn prune_autopaired(&mut self, text: &[u8]) {
+        let (keep, stale): (Vec<_>, Vec<_>) = std::mem::take(&mut self.autopaired)
+            .into_iter()
+            .partition(|(id, close)| self.marks.get(*id).is_some_and(|mark| text.get(mark.pos) == Some(close)));
+        self.autopaired = keep;
+        for (id, _) in stale {
+            self.remove_mark(id);
+        }
+    }
+
+    fn is_autopaired(&self, pos: usize, close: u8) -> bool {
+        self.autopaired.iter().any(|(id, c)| *c == close && self.mark(*id).pos == pos)
+    }
+
+    // Type text at every cursor. A single opening character is paired with its closing one,
+    // or wraps the selection, and a closing character steps over one that was auto inserted.
+    pub fn type_text(&mut self, content: &[u8]) {
+        let text = self.contents();
+        self.prune_autopaired(&text);
+        let ch = match content {
+            [ch] => *ch,
+            _ => return self.insert(content)
+        };
+
+        let mut paired = Vec::new();
+        let edits: Vec<PairEdit> = self.cursors()
+            .map(|(id, cursor)| {
+                let range = cursor.range();
+                let pair = self.pairs.by_open(ch);
+                if cursor.is_caret() && text.get(range.start) == Some(&ch) && self.is_autopaired(range.start, ch) {
+                    return (id, range.clone(), Vec::new(), 1..1)
+                }
+                let dedent = if cursor.is_caret() {closing_dedent(&text, range.start, ch, self.indent)} else {None};
+                if let Some((start, mut dedented)) = dedent {
+                    dedented.push(ch);
+                    let len = dedented.len();
+                    return (id, start..range.start, dedented, len..len)
+                }
+                match pair {
+                    Some((open, close)) if !cursor.is_caret() => {
+                        let mut wrapped = vec![open];
+                        wrapped.extend(&text[range.clone()]);
+                        wrapped.push(close);
+                        let len = range.len();
+                        let inner = if cursor.start > cursor.end {len + 1..1} else {1..len + 1};
+                        (id, range, wrapped, inner)
+                    }
+                    Some((open, close)) if should_pair(&text, range.start, &self.pairs, (open, close)) => {
+                        paired.push((id, close));
+                        (id, range, vec![open, close], 1..1)
+                    }
+                    _ => (id, range, vec![ch], 1..1)
+                }
+            })
+            .collect();
+
+        self.transaction(|eb| {
+            // back to front so the ranges of the remaining edits stay valid
+            for (id, range, content, cursor) in edits.into_iter().rev() {
+                if range.is_empty() && content.is_empty() {
+                    eb.set_cursor(id, Cursor::caret(range.start + cursor.start));
+                    continue
+                }
+                eb.replace(range.start, range.end, &content);
+                eb.set_cursor(id, Cursor::new(range.start + cursor.start, range.start + cursor.end));
+            }
+            // the closing character is right after the caret, right gravity keeps it there while typing
+            for (id, close) in paired {
+                let pos = eb.cursor(id).end;
+                let mark = eb.new_mark(pos, Gravity::Right);
+                eb.autopaired.push((mark, close));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn typed(text: &str, cursor: Cursor, typing: &str) -> (String, Cursor) {
+        let mut eb = EditingBuffer::from_bytes(text.as_bytes());
+        eb.add_cursor(cursor);
+        for ch in typing.bytes() {
+            eb.type_text(&[ch]);
+        }
+        let cursor = eb.cursors().next().unwrap().1;
+        (String::from_utf8(eb.contents()).unwrap(), cursor)
+    }
+
+    #[test]
+    fn test_pairing() {
+        assert_eq!(typed("", Cursor::caret(0), "(a"), ("(a)".to_string(), Cursor::caret(2)));
+        // stepping over the inserted closing characters
+        assert_eq!(typed("", Cursor::caret(0), "([x])"), ("([x])".to_string(), Cursor::caret(5)));
+        assert_eq!(typed("", Cursor::caret(0), "\"s\""), ("\"s\"".to_string(), Cursor::caret(3)));
+        // no pairing before a word or for an apostrophe
+        assert_eq!(typed("x", Cursor::caret(0), "("), ("(x".to_string(), Cursor::caret(1)));
+        assert_eq!(typed("don", Cursor::caret(3), "'"), ("don'".to_string(), Cursor::caret(4)));
+        // a closing character that was not auto inserted is typed
+        assert_eq!(typed(")", Cursor::caret(0), ")"), ("))".to_string(), Cursor::caret(1)));
+    }
+
+    #[test]
+    fn test_wrap_selection() {
+        assert_eq!(typed("a bc d", Cursor::new(2, 4), "("), ("a (bc) d".to_string(), Cursor::new(3, 5)));
+        assert_eq!(typed("a bc d", Cursor::new(4, 2), "\""), ("a \"bc\" d".to_string(), Cursor::new(5, 3)));
+    }
+
+    #[test]
+    fn test_delete_pair() {
+        let mut eb = EditingBuffer::from_bytes(b"f[]");
+        eb.add_cursor(Cursor::caret(2));
+        eb.delete_backward();
+        assert_eq!(eb.contents(), b"f");
+
+        let mut eb = EditingBuffer::from_bytes(b"'x'");
+        eb.set_pair_rules(PairRules::for_extension("rs"));
+        eb.add_cursor(Cursor::caret(0));
+        eb.type_text(b"'");
+        assert_eq!(eb.contents(), b"''x'");
+    }
+}
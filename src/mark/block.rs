@@ -0,0 +1,216 @@
+// Rectangular (block) selections.
+// A block is edited by splitting it into one cursor per line, so typing, deleting and pasting
+// work per line through the normal multiple cursor commands.
+
+use std::ops::Range;
+
+use crate::clipboard::Clip;
+use crate::text_renderer::{Advance, line_x, line_index};
+
+use super::{Cursor, CursorId, EditingBuffer, line_start, line_end};
+use super::motion::line_str;
+
+// What happens to lines that end before the left edge of a block
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShortLines {
+    // pad them with spaces up to the left edge
+    #[default]
+    Pad,
+    // leave them out of the block
+    Skip,
+}
+
+// the part of one line covered by a block
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockLine {
+    // byte range on the line, empty for a short line
+    pub range: Range<usize>,
+    // pixels between the end of a short line and the left edge of the block
+    pub missing: f32,
+}
+
+fn x_of(text: &[u8], advance: &impl Advance, pos: usize) -> f32 {
+    let start = line_start(text, pos);
+    let line = line_str(text, start);
+    line_x(advance, &line, (pos - start).min(line.len()))
+}
+
+// the part of every line between the lines of start and end covered by the columns left..right
+fn block_lines(text: &[u8], advance: &impl Advance, cursor: Cursor, left: f32, right: f32) -> Vec<BlockLine> {
+    let range = cursor.range();
+    let last = line_start(text, range.end);
+    let mut start = line_start(text, range.start);
+    let mut lines = Vec::new();
+    loop {
+        let line = line_str(text, start);
+        let width = line_x(advance, &line, line.len());
+        let from = start + line_index(advance, &line, left);
+        let to = start + line_index(advance, &line, right);
+        lines.push(BlockLine {range: from..to, missing: (left - width).max(0.)});
+
+        if start >= last {
+            return lines
+        }
+        start = line_end(text, start) + 1;
+    }
+}
+
+impl EditingBuffer {
+    // turn a cursor into a block with its corners at start and end
+    pub fn start_block(&mut self, id: CursorId, advance: &impl Advance) {
+        let text = self.contents();
+        let cursor = self.cursor(id);
+        self.set_cursor(id, Cursor {
+            horizontal: Some(x_of(&text, advance, cursor.end)),
+            block: Some(x_of(&text, advance, cursor.start)),
+            ..cursor
+        });
+    }
+
+    // Turn every cursor into a block, edits then go to every line of them.
+    // short_lines is how the lines ending before a block are edited, it is kept until the next blocks.
+    pub fn start_blocks(&mut self, advance: &impl Advance, short_lines: ShortLines) {
+        let ids: Vec<CursorId> = self.cursors().map(|(id, _)| id).collect();
+        for id in ids {
+            self.start_block(id, advance);
+        }
+        self.short_lines = short_lines;
+    }
+
+    pub fn has_blocks(&self) -> bool {
+        self.cursors().any(|(_, cursor)| cursor.block.is_some())
+    }
+
+    // left and right edge of a block in pixels
+    fn block_columns(&self, text: &[u8], advance: &impl Advance, cursor: Cursor) -> Option<(f32, f32)> {
+        let anchor = cursor.block?;
+        let head = cursor.horizontal.unwrap_or_else(|| x_of(text, advance, cursor.end));
+        Some((anchor.min(head), anchor.max(head)))
+    }
+
+    // the lines covered by a block cursor, top to bottom, or None if it is not a block
+    pub fn block(&self, id: CursorId, advance: &impl Advance) -> Option<Vec<BlockLine>> {
+        let text = self.contents();
+        let cursor = self.cursor(id);
+        let (left, right) = self.block_columns(&text, advance, cursor)?;
+        Some(block_lines(&text, advance, cursor, left, right))
+    }
+
+    // text of every line of a block, short lines give empty strings
+    pub fn block_text(&self, id: CursorId, advance: &impl Advance) -> Option<Vec<Vec<u8>>> {
+        let text = self.contents();
+        let lines = self.block(id, advance)?;
+        Some(lines.into_iter().map(|line| text[line.range].to_vec()).collect())
+    }
+
+    // the text of every cursor, with a piece for every line of a block
+    pub fn block_selections(&self, advance: &impl Advance) -> Clip {
+        let text = self.contents();
+        let pieces = self.cursors()
+            .flat_map(|(id, cursor)| self.block_text(id, advance).unwrap_or_else(|| vec![text[cursor.range()].to_vec()]))
+            .collect();
+        Clip {pieces}
+    }
+
+    // Replace every block cursor with one cursor per line, keeping the direction of the block.
+    // Short lines are padded with spaces when needed, in the current transaction.
+    pub fn split_blocks(&mut self, advance: &impl Advance, short_lines: ShortLines) {
+        let text = self.contents();
+        let blocks: Vec<(CursorId, Cursor, Vec<BlockLine>)> = self.cursors()
+            .filter_map(|(id, cursor)| {
+                let (left, right) = self.block_columns(&text, advance, cursor)?;
+                Some((id, cursor, block_lines(&text, advance, cursor, left, right)))
+            })
+            .collect();
+
+        self.transaction(|eb| {
+            for (id, cursor, lines) in blocks {
+                let backwards = cursor.horizontal.zip(cursor.block).is_some_and(|(head, anchor)| head < anchor);
+                let space = advance.advance(' ').max(1.);
+                eb.remove_cursor(id);
+
+                // bottom to top so padding does not move the lines that are still to come
+                for line in lines.into_iter().rev() {
+                    let mut range = line.range;
+                    if line.missing > 0. {
+                        if short_lines == ShortLines::Skip {
+                            continue
+                        }
+                        let padding = vec![b' '; (line.missing / space).ceil() as usize];
+                        let pad = eb.new_cursor();
+                        eb.set_cursor(pad, Cursor::caret(range.start));
+                        eb.set(pad, &padding);
+                        eb.remove_cursor(pad);
+                        range = range.start + padding.len() .. range.start + padding.len();
+                    }
+                    let line_cursor = if backwards {
+                        Cursor::new(range.end, range.start)
+                    } else {
+                        Cursor::new(range.start, range.end)
+                    };
+                    eb.cursors.insert(line_cursor);
+                }
+            }
+        });
+    }
+
+    // split the blocks and run an edit over the resulting cursors as one transaction
+    pub fn edit_blocks<F: FnOnce(&mut Self)>(&mut self, advance: &impl Advance, short_lines: ShortLines, f: F) {
+        self.transaction(|eb| {
+            eb.split_blocks(advance, short_lines);
+            f(eb);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mark::motion::test::TestAdvance;
+
+    fn block_buffer(text: &str, start: usize, end: usize) -> (EditingBuffer, CursorId) {
+        let mut eb = EditingBuffer::from_bytes(text.as_bytes());
+        let id = eb.new_cursor();
+        eb.set_cursor(id, Cursor::new(start, end));
+        eb.start_block(id, &TestAdvance);
+        (eb, id)
+    }
+
+    fn ranges(eb: &EditingBuffer) -> Vec<Range<usize>> {
+        eb.cursors().map(|(_, c)| c.range()).collect()
+    }
+
+    #[test]
+    fn test_block_lines() {
+        // the tab takes 40px, the same as four ascii characters
+        let (mut eb, id) = block_buffer("abcdefgh\nab\n\tXYZW\nabcdef", 2, 23);
+        let lines: Vec<Range<usize>> = eb.block(id, &TestAdvance).unwrap().into_iter().map(|l| l.range).collect();
+        assert_eq!(lines, vec![2..5, 11..11, 13..14, 20..23]);
+        assert_eq!(eb.block_text(id, &TestAdvance).unwrap(), vec![
+            b"cde".to_vec(), b"".to_vec(), b"X".to_vec(), b"cde".to_vec()
+        ]);
+
+        // moving keeps the block columns
+        eb.move_up(&TestAdvance, true);
+        let lines: Vec<Range<usize>> = eb.block(id, &TestAdvance).unwrap().into_iter().map(|l| l.range).collect();
+        assert_eq!(lines, vec![2..5, 11..11, 13..14]);
+    }
+
+    #[test]
+    fn test_block_typing() {
+        let (mut eb, _) = block_buffer("abcdef\na\nabcdef", 2, 11);
+        let transactions = eb.span_table.transaction_count();
+        eb.edit_blocks(&TestAdvance, ShortLines::Skip, |eb| eb.insert("X".as_bytes()));
+        assert_eq!(eb.contents(), "abXcdef\na\nabXcdef".as_bytes());
+        assert_eq!(eb.span_table.transaction_count(), transactions + 1);
+        assert_eq!(ranges(&eb), vec![3..3, 13..13]);
+    }
+
+    #[test]
+    fn test_block_padding() {
+        let (mut eb, _) = block_buffer("abcdef\na\nabcdef", 3, 12);
+        eb.edit_blocks(&TestAdvance, ShortLines::Pad, |eb| eb.delete_backward());
+        assert_eq!(eb.contents(), "abdef\na \nabdef".as_bytes());
+        assert_eq!(ranges(&eb), vec![2..2, 8..8, 11..11]);
+    }
+}
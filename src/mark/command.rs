@@ -0,0 +1,495 @@
+// Editing commands as values, so a sequence of them can be recorded as a macro and replayed.
+// Replaying runs the same commands against the buffer, so it does not depend on key bindings
+// or on the events the commands were originally typed with.
+
+use crate::clipboard::{Clipboard, Clipboards};
+use crate::layout::{LineShaper, WrapLayout};
+use crate::text_renderer::Advance;
+
+use super::EditingBuffer;
+use super::motion::Motion;
+use super::text_object::TextObject;
+use super::lines::{Case, SortOptions};
+use super::fold::FoldKind;
+use super::block::ShortLines;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    // typed text, with auto pairing
+    Insert(Vec<u8>),
+    DeleteBackward,
+    DeleteForward,
+    Newline,
+    Indent,
+    Dedent,
+    // motion, extend
+    Move(Motion, bool),
+    // lines up (negative) or down, extend
+    Vertical(isize, bool),
+    // rows of the soft wrap layout up (negative) or down, extend
+    VisualVertical(isize, bool),
+    // lines in a page, extend
+    PageUp(usize, bool),
+    PageDown(usize, bool),
+    AddCursorAbove,
+    AddCursorBelow,
+    AddNextOccurrence,
+    // select the next occurrence of the text after the last cursor
+    Search(Vec<u8>),
+    SplitIntoLines,
+    // object, inner
+    SelectTextObject(TextObject, bool),
+    // turn the selections into blocks
+    Block(ShortLines),
+    Copy,
+    Cut,
+    Paste,
+    YankPop,
+    // register name
+    YankRegister(u8),
+    PasteRegister(u8),
+    Duplicate,
+    MoveLinesUp,
+    MoveLinesDown,
+    DeleteLines,
+    JoinLines,
+    SortLines(SortOptions),
+    ReverseLines,
+    TransposeChars,
+    TransposeWords,
+    ConvertCase(Case),
+    ToggleComment,
+    // column to wrap at
+    Reflow(usize),
+    Fold(FoldKind),
+    Unfold,
+    UnfoldAll,
+}
+
+const MOTIONS: [Motion; 14] = [
+    Motion::GraphemeLeft, Motion::GraphemeRight, Motion::WordLeft, Motion::WordRight,
+    Motion::SubwordLeft, Motion::SubwordRight, Motion::LineStart, Motion::LineEnd,
+    Motion::SmartHome, Motion::ParagraphUp, Motion::ParagraphDown,
+    Motion::DocumentStart, Motion::DocumentEnd, Motion::MatchingBracket,
+];
+
+const CASES: [Case; 5] = [Case::Upper, Case::Lower, Case::Title, Case::Snake, Case::Camel];
+
+const FOLD_KINDS: [FoldKind; 3] = [FoldKind::Indent, FoldKind::Bracket, FoldKind::Selection];
+
+const SHORT_LINES: [ShortLines; 2] = [ShortLines::Pad, ShortLines::Skip];
+
+// Reads the space separated fields of an encoded command
+struct Fields<'a> {
+    line: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn word(&mut self) -> Option<&'a str> {
+        let end = self.line.iter().position(|b| *b == b' ').unwrap_or(self.line.len());
+        let word = std::str::from_utf8(&self.line[..end]).ok()?;
+        self.line = &self.line[(end + 1).min(self.line.len())..];
+        Some(word)
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Option<T> {
+        self.word()?.parse().ok()
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        Some(self.number::<u8>()? != 0)
+    }
+}
+
+// a command holding text, as its name and the length prefixed text
+fn encode_text(out: &mut Vec<u8>, name: &str, text: &[u8]) {
+    out.extend(format!("{} {}:", name, text.len()).as_bytes());
+    out.extend(text);
+    out.push(b'\n');
+}
+
+// the text of a command written by encode_text, prefix is its name and a space
+fn decode_text(data: &[u8], prefix: &str) -> Option<(Vec<u8>, usize)> {
+    let rest = data.strip_prefix(prefix.as_bytes())?;
+    let colon = rest.iter().position(|b| *b == b':')?;
+    let len: usize = std::str::from_utf8(&rest[..colon]).ok()?.parse().ok()?;
+    let text = rest.get(colon + 1..colon + 1 + len)?;
+    if rest.get(colon + 1 + len) != Some(&b'\n') {
+        return None
+    }
+    Some((text.to_vec(), prefix.len() + colon + len + 2))
+}
+
+impl Command {
+    // typing, deleting and pasting, which go to every line of a block
+    fn edits_blocks(&self) -> bool {
+        matches!(
+            self,
+            Command::Insert(_) | Command::DeleteBackward | Command::DeleteForward | Command::Cut | Command::Paste | Command::PasteRegister(_)
+        )
+    }
+
+    // Append the command to out as one line. Inserted and searched text is length prefixed so it can hold newlines.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let line = match self {
+            Command::Insert(text) => return encode_text(out, "insert", text),
+            Command::Search(text) => return encode_text(out, "search", text),
+            Command::DeleteBackward => "delete_backward".to_string(),
+            Command::DeleteForward => "delete_forward".to_string(),
+            Command::Newline => "newline".to_string(),
+            Command::Indent => "indent".to_string(),
+            Command::Dedent => "dedent".to_string(),
+            Command::Move(motion, extend) => format!("move {:?} {}", motion, *extend as u8),
+            Command::Vertical(lines, extend) => format!("vertical {} {}", lines, *extend as u8),
+            Command::VisualVertical(rows, extend) => format!("visual_vertical {} {}", rows, *extend as u8),
+            Command::PageUp(lines, extend) => format!("page_up {} {}", lines, *extend as u8),
+            Command::PageDown(lines, extend) => format!("page_down {} {}", lines, *extend as u8),
+            Command::AddCursorAbove => "add_cursor_above".to_string(),
+            Command::AddCursorBelow => "add_cursor_below".to_string(),
+            Command::AddNextOccurrence => "add_next_occurrence".to_string(),
+            Command::SplitIntoLines => "split_into_lines".to_string(),
+            Command::SelectTextObject(object, inner) => {
+                let object = match object {
+                    TextObject::Quote(quote) => format!("Quote {}", quote),
+                    TextObject::Bracket(open, close) => format!("Bracket {} {}", open, close),
+                    object => format!("{:?}", object),
+                };
+                format!("select {} {}", object, *inner as u8)
+            }
+            Command::Block(short_lines) => format!("block {:?}", short_lines),
+            Command::Copy => "copy".to_string(),
+            Command::Cut => "cut".to_string(),
+            Command::Paste => "paste".to_string(),
+            Command::YankPop => "yank_pop".to_string(),
+            Command::YankRegister(name) => format!("yank_register {}", name),
+            Command::PasteRegister(name) => format!("paste_register {}", name),
+            Command::Duplicate => "duplicate".to_string(),
+            Command::MoveLinesUp => "move_lines_up".to_string(),
+            Command::MoveLinesDown => "move_lines_down".to_string(),
+            Command::DeleteLines => "delete_lines".to_string(),
+            Command::JoinLines => "join_lines".to_string(),
+            Command::SortLines(options) => format!(
+                "sort_lines {} {} {} {}",
+                options.case_insensitive as u8, options.numeric as u8, options.reverse as u8, options.unique as u8
+            ),
+            Command::ReverseLines => "reverse_lines".to_string(),
+            Command::TransposeChars => "transpose_chars".to_string(),
+            Command::TransposeWords => "transpose_words".to_string(),
+            Command::ConvertCase(case) => format!("convert_case {:?}", case),
+            Command::ToggleComment => "toggle_comment".to_string(),
+            Command::Reflow(width) => format!("reflow {}", width),
+            Command::Fold(kind) => format!("fold {:?}", kind),
+            Command::Unfold => "unfold".to_string(),
+            Command::UnfoldAll => "unfold_all".to_string(),
+        };
+        out.extend(line.as_bytes());
+        out.push(b'\n');
+    }
+
+    // Read one command written by encode from the start of data.
+    // Returns the command and the number of bytes it took, or None if data does not start with one.
+    pub fn decode(data: &[u8]) -> Option<(Command, usize)> {
+        if let Some((text, len)) = decode_text(data, "insert ") {
+            return Some((Command::Insert(text), len))
+        }
+        if let Some((text, len)) = decode_text(data, "search ") {
+            return Some((Command::Search(text), len))
+        }
+
+        let end = data.iter().position(|b| *b == b'\n')?;
+        let mut fields = Fields {line: &data[..end]};
+        let command = match fields.word()? {
+            "delete_backward" => Command::DeleteBackward,
+            "delete_forward" => Command::DeleteForward,
+            "newline" => Command::Newline,
+            "indent" => Command::Indent,
+            "dedent" => Command::Dedent,
+            "move" => {
+                let name = fields.word()?;
+                let motion = MOTIONS.iter().find(|motion| format!("{:?}", motion) == name)?;
+                Command::Move(*motion, fields.flag()?)
+            }
+            "vertical" => Command::Vertical(fields.number()?, fields.flag()?),
+            "visual_vertical" => Command::VisualVertical(fields.number()?, fields.flag()?),
+            "page_up" => Command::PageUp(fields.number()?, fields.flag()?),
+            "page_down" => Command::PageDown(fields.number()?, fields.flag()?),
+            "add_cursor_above" => Command::AddCursorAbove,
+            "add_cursor_below" => Command::AddCursorBelow,
+            "add_next_occurrence" => Command::AddNextOccurrence,
+            "split_into_lines" => Command::SplitIntoLines,
+            "select" => {
+                let object = match fields.word()? {
+                    "Word" => TextObject::Word,
+                    "BigWord" => TextObject::BigWord,
+                    "Sentence" => TextObject::Sentence,
+                    "Paragraph" => TextObject::Paragraph,
+                    "Indent" => TextObject::Indent,
+                    "Quote" => TextObject::Quote(fields.number()?),
+                    "Bracket" => TextObject::Bracket(fields.number()?, fields.number()?),
+                    _ => return None
+                };
+                Command::SelectTextObject(object, fields.flag()?)
+            }
+            "block" => {
+                let name = fields.word()?;
+                Command::Block(*SHORT_LINES.iter().find(|short_lines| format!("{:?}", short_lines) == name)?)
+            }
+            "copy" => Command::Copy,
+            "cut" => Command::Cut,
+            "paste" => Command::Paste,
+            "yank_pop" => Command::YankPop,
+            "yank_register" => Command::YankRegister(fields.number()?),
+            "paste_register" => Command::PasteRegister(fields.number()?),
+            "duplicate" => Command::Duplicate,
+            "move_lines_up" => Command::MoveLinesUp,
+            "move_lines_down" => Command::MoveLinesDown,
+            "delete_lines" => Command::DeleteLines,
+            "join_lines" => Command::JoinLines,
+            "sort_lines" => Command::SortLines(SortOptions {
+                case_insensitive: fields.flag()?,
+                numeric: fields.flag()?,
+                reverse: fields.flag()?,
+                unique: fields.flag()?,
+            }),
+            "reverse_lines" => Command::ReverseLines,
+            "transpose_chars" => Command::TransposeChars,
+            "transpose_words" => Command::TransposeWords,
+            "convert_case" => {
+                let name = fields.word()?;
+                Command::ConvertCase(*CASES.iter().find(|case| format!("{:?}", case) == name)?)
+            }
+            "toggle_comment" => Command::ToggleComment,
+            "reflow" => Command::Reflow(fields.number()?),
+            "fold" => {
+                let name = fields.word()?;
+                Command::Fold(*FOLD_KINDS.iter().find(|kind| format!("{:?}", kind) == name)?)
+            }
+            "unfold" => Command::Unfold,
+            "unfold_all" => Command::UnfoldAll,
+            _ => return None
+        };
+        Some((command, end + 1))
+    }
+}
+
+impl EditingBuffer {
+    // The layout is what is drawn, commands that move by rows bring it up to date with shaper first.
+    // Copy and paste commands use clipboards.
+    pub fn run(
+        &mut self, command: &Command, advance: &impl Advance, layout: &mut WrapLayout, shaper: &mut impl LineShaper,
+        clipboards: &mut Clipboards<impl Clipboard>,
+    ) {
+        if command.edits_blocks() && self.has_blocks() {
+            let short_lines = self.short_lines;
+            self.edit_blocks(advance, short_lines, |eb| eb.apply(command, advance, layout, shaper, clipboards));
+        } else {
+            self.apply(command, advance, layout, shaper, clipboards);
+        }
+        self.update_folds();
+    }
+
+    fn apply(
+        &mut self, command: &Command, advance: &impl Advance, layout: &mut WrapLayout, shaper: &mut impl LineShaper,
+        clipboards: &mut Clipboards<impl Clipboard>,
+    ) {
+        match command {
+            Command::Insert(text) => self.type_text(text),
+            Command::DeleteBackward => self.delete_backward(),
+            Command::DeleteForward => self.delete_forward(),
+            Command::Newline => self.newline(),
+            Command::Indent => self.indent(),
+            Command::Dedent => self.dedent(),
+            Command::Move(motion, extend) => self.move_cursors(*motion, *extend),
+            Command::Vertical(lines, extend) => self.move_vertical(advance, *lines, *extend),
+            Command::VisualVertical(rows, extend) => {
+                self.sync_layout(layout, shaper);
+                self.move_visual(layout, *rows, *extend);
+            }
+            Command::PageUp(lines, extend) => self.page_up(advance, *lines, *extend),
+            Command::PageDown(lines, extend) => self.page_down(advance, *lines, *extend),
+            Command::AddCursorAbove => self.add_cursor_above(),
+            Command::AddCursorBelow => self.add_cursor_below(),
+            Command::AddNextOccurrence => self.add_next_occurrence(),
+            Command::Search(needle) => {
+                self.search(needle);
+            }
+            Command::SplitIntoLines => self.split_into_lines(),
+            Command::SelectTextObject(object, inner) => self.select_text_object(*object, *inner),
+            Command::Block(short_lines) => self.start_blocks(advance, *short_lines),
+            Command::Copy => self.copy(advance, &mut clipboards.ring, &mut clipboards.clipboard),
+            Command::Cut => self.cut(advance, &mut clipboards.ring, &mut clipboards.clipboard),
+            Command::Paste => self.paste(&mut clipboards.ring, &clipboards.clipboard),
+            Command::YankPop => {
+                self.yank_pop(&clipboards.ring);
+            }
+            Command::YankRegister(name) => {
+                self.yank_register(advance, &mut clipboards.registers, *name);
+            }
+            Command::PasteRegister(name) => {
+                self.paste_register(&clipboards.registers, *name);
+            }
+            Command::Duplicate => self.duplicate(),
+            Command::MoveLinesUp => self.move_lines_up(),
+            Command::MoveLinesDown => self.move_lines_down(),
+            Command::DeleteLines => self.delete_lines(),
+            Command::JoinLines => self.join_lines(),
+            Command::SortLines(options) => self.sort_lines(*options),
+            Command::ReverseLines => self.reverse_lines(),
+            Command::TransposeChars => self.transpose_chars(),
+            Command::TransposeWords => self.transpose_words(),
+            Command::ConvertCase(case) => self.convert_case(*case),
+            Command::ToggleComment => self.toggle_comment(),
+            Command::Reflow(width) => self.reflow(*width),
+            Command::Fold(kind) => self.fold(*kind),
+            Command::Unfold => self.unfold(),
+            Command::UnfoldAll => self.unfold_all(),
+        }
+    }
+
+    // run a recorded macro count times, all of it as one transaction
+    pub fn replay(
+        &mut self, commands: &[Command], count: usize, advance: &impl Advance, layout: &mut WrapLayout, shaper: &mut impl LineShaper,
+        clipboards: &mut Clipboards<impl Clipboard>,
+    ) {
+        self.transaction(|eb| {
+            for _ in 0..count {
+                for command in commands {
+                    eb.run(command, advance, layout, shaper, clipboards);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::Cursor;
+    use crate::mark::motion::test::TestAdvance;
+    use crate::mark::copy_paste::test::TestClipboard;
+    use crate::clipboard::register::Registers;
+
+    fn clipboards() -> Clipboards<TestClipboard> {
+        Clipboards::new(TestClipboard::default(), Registers::default())
+    }
+
+    #[test]
+    fn test_replay() {
+        // turn each "key value" line into "key = value;"
+        let commands = vec![
+            Command::Move(Motion::WordRight, false),
+            Command::Insert(" =".as_bytes().to_vec()),
+            Command::Move(Motion::LineEnd, false),
+            Command::Insert(";".as_bytes().to_vec()),
+            Command::Vertical(1, false),
+            Command::Move(Motion::LineStart, false),
+        ];
+        let mut eb = EditingBuffer::from_bytes("a 1\nb 2\nc 3\nd 4".as_bytes());
+        eb.add_cursor(Cursor::caret(0));
+        let transactions = eb.span_table.transaction_count();
+        eb.replay(&commands, 3, &TestAdvance, &mut WrapLayout::new(1000., false), &mut TestAdvance, &mut clipboards());
+        assert_eq!(eb.contents(), "a = 1;\nb = 2;\nc = 3;\nd 4".as_bytes());
+        assert_eq!(eb.span_table.transaction_count(), transactions + 1);
+    }
+
+    #[test]
+    fn test_replay_rows() {
+        let mut eb = EditingBuffer::from_bytes("abcdefghij\nk".as_bytes());
+        eb.add_cursor(Cursor::caret(0));
+        let mut layout = WrapLayout::new(80., false);
+        eb.sync_layout(&mut layout, &mut TestAdvance);
+
+        // the layout is brought up to date after the insert, rows are then
+        // "12345678", "abcdefgh", "ij" and "k"
+        let commands = vec![Command::Insert("12345678\n".as_bytes().to_vec()), Command::VisualVertical(1, false)];
+        eb.replay(&commands, 1, &TestAdvance, &mut layout, &mut TestAdvance, &mut clipboards());
+        let cursor = eb.cursors().next().unwrap().1;
+        assert_eq!(cursor.range(), 17..17);
+    }
+
+    #[test]
+    fn test_replay_block() {
+        let mut eb = EditingBuffer::from_bytes("abcdef\na\nabcdef".as_bytes());
+        let id = eb.new_cursor();
+        eb.set_cursor(id, Cursor::new(2, 11));
+        let commands = vec![Command::Block(ShortLines::Skip), Command::Insert("X".as_bytes().to_vec())];
+        eb.replay(&commands, 1, &TestAdvance, &mut WrapLayout::new(1000., false), &mut TestAdvance, &mut clipboards());
+        assert_eq!(eb.contents(), "abXcdef\na\nabXcdef".as_bytes());
+
+        // padded when the block is made again
+        let mut eb = EditingBuffer::from_bytes("abcdef\na\nabcdef".as_bytes());
+        let id = eb.new_cursor();
+        eb.set_cursor(id, Cursor::new(3, 12));
+        let commands = vec![Command::Block(ShortLines::Pad), Command::DeleteBackward];
+        eb.replay(&commands, 1, &TestAdvance, &mut WrapLayout::new(1000., false), &mut TestAdvance, &mut clipboards());
+        assert_eq!(eb.contents(), "abdef\na \nabdef".as_bytes());
+    }
+
+    #[test]
+    fn test_replay_clipboard() {
+        let mut eb = EditingBuffer::from_bytes("a b".as_bytes());
+        let id = eb.new_cursor();
+        eb.set_cursor(id, Cursor::new(0, 1));
+        let commands = vec![
+            Command::YankRegister(b'r'),
+            Command::Cut,
+            Command::Move(Motion::LineEnd, false),
+            Command::PasteRegister(b'r'),
+            Command::Paste,
+        ];
+        let mut clipboards = clipboards();
+        eb.replay(&commands, 1, &TestAdvance, &mut WrapLayout::new(1000., false), &mut TestAdvance, &mut clipboards);
+        assert_eq!(eb.contents(), " baa".as_bytes());
+        assert_eq!(clipboards.clipboard.0.as_deref(), Some("a"));
+        assert_eq!(clipboards.registers.text(b'r').unwrap().joined(), b"a");
+
+        // copying a block copies its lines, which paste one at each cursor
+        let mut eb = EditingBuffer::from_bytes("ab\ncd\n".as_bytes());
+        let id = eb.new_cursor();
+        eb.set_cursor(id, Cursor::new(0, 4));
+        let commands = vec![Command::Block(ShortLines::Pad), Command::Copy, Command::Paste];
+        eb.replay(&commands, 1, &TestAdvance, &mut WrapLayout::new(1000., false), &mut TestAdvance, &mut clipboards);
+        assert_eq!(clipboards.ring.get(0).unwrap().pieces, vec![b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(eb.contents(), "ab\ncd\n".as_bytes());
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let commands = vec![
+            Command::Insert("two\nlines".as_bytes().to_vec()),
+            Command::DeleteBackward,
+            Command::Newline,
+            Command::Move(Motion::SubwordLeft, true),
+            Command::Vertical(-3, false),
+            Command::VisualVertical(2, true),
+            Command::PageDown(40, false),
+            Command::SelectTextObject(TextObject::BRACES, true),
+            Command::SelectTextObject(TextObject::Quote(b'"'), false),
+            Command::SplitIntoLines,
+            Command::Block(ShortLines::Skip),
+            Command::Cut,
+            Command::YankPop,
+            Command::PasteRegister(b'a'),
+            Command::Search("a\nb".as_bytes().to_vec()),
+            Command::SortLines(SortOptions {numeric: true, unique: true, ..SortOptions::default()}),
+            Command::ConvertCase(Case::Snake),
+            Command::Reflow(72),
+            Command::Fold(FoldKind::Bracket),
+            Command::UnfoldAll,
+        ];
+        let mut data = Vec::new();
+        for command in &commands {
+            command.encode(&mut data);
+        }
+
+        let mut decoded = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let (command, len) = Command::decode(&data[pos..]).unwrap();
+            decoded.push(command);
+            pos += len;
+        }
+        assert_eq!(decoded, commands);
+        assert!(Command::decode(b"move Sideways 0\n").is_none());
+    }
+}
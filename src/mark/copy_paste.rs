@@ -0,0 +1,257 @@
+// Cut, copy and paste for every cursor, with a kill ring to cycle through older clips
+
+use crate::clipboard::{Clip, Clipboard, KillRing};
+use crate::clipboard::register::{Register, Registers};
+use crate::text_renderer::Advance;
+
+use super::{Cursor, CursorId, EditingBuffer, Gravity, MarkId};
+
+// What the last paste inserted, so that yank_pop can swap it for an older clip
+pub struct Yank {
+    // kill ring entry that was pasted
+    index: usize,
+    // every pasted cursor with a mark at the start of its text and where the text ended
+    pasted: Vec<(CursorId, MarkId, usize)>,
+    // transaction count after the paste, any later edit makes the yank stale
+    transactions: usize,
+}
+
+impl EditingBuffer {
+    // the text selected by every cursor
+    pub fn selections(&self) -> Clip {
+        let text = self.contents();
+        Clip {pieces: self.cursors().map(|(_, cursor)| text[cursor.range()].to_vec()).collect()}
+    }
+
+    // a block is copied as one piece per line
+    pub fn copy(&self, advance: &impl Advance, ring: &mut KillRing, clipboard: &mut impl Clipboard) {
+        let clip = self.block_selections(advance);
+        clipboard.set_text(&String::from_utf8_lossy(&clip.joined()));
+        ring.push(clip);
+    }
+
+    pub fn cut(&mut self, advance: &impl Advance, ring: &mut KillRing, clipboard: &mut impl Clipboard) {
+        self.copy(advance, ring, clipboard);
+        self.edit_cursors(|_, cursor| (cursor.range(), Vec::new()));
+    }
+
+    // paste the clipboard, one piece per cursor when the clip was copied from as many cursors
+    pub fn paste(&mut self, ring: &mut KillRing, clipboard: &impl Clipboard) {
+        if ring.take_clipboard(clipboard).is_some() {
+            self.yank(ring, 0);
+        }
+    }
+
+    // paste a clip from somewhere other than the kill ring, such as a register
+    pub fn paste_clip(&mut self, clip: &Clip) {
+        self.clear_yank();
+        let mut pieces = clip.pieces_for(self.cursors.len()).into_iter();
+        self.edit_cursors(|_, cursor| (cursor.range(), pieces.next().unwrap()));
+    }
+
+    // Copy the selections into register name, an upper case name appends to it.
+    // Returns false if name is not a register.
+    pub fn yank_register(&self, advance: &impl Advance, registers: &mut Registers, name: u8) -> bool {
+        registers.set(name, Register::Text(self.block_selections(advance)))
+    }
+
+    // paste the text in register name, returns false if it holds none
+    pub fn paste_register(&mut self, registers: &Registers, name: u8) -> bool {
+        match registers.text(name) {
+            Some(clip) => {
+                self.paste_clip(clip);
+                true
+            }
+            None => false
+        }
+    }
+
+    // share the selected text as the primary selection, carets are left out
+    pub fn set_primary(&self, primary: &mut impl Clipboard) {
+        let mut clip = self.selections();
+        clip.pieces.retain(|piece| !piece.is_empty());
+        if !clip.pieces.is_empty() {
+            primary.set_text(&String::from_utf8_lossy(&clip.joined()));
+        }
+    }
+
+    // middle click paste at pos, where the click was. The cursors stay where they are
+    // and the primary selection is not added to the kill ring.
+    pub fn paste_primary(&mut self, primary: &impl Clipboard, pos: usize) {
+        if let Some(text) = primary.text() {
+            let pos = pos.min(self.len());
+            self.clear_yank();
+            self.transaction(|eb| eb.replace(pos, pos, text.as_bytes()));
+        }
+    }
+
+    fn clear_yank(&mut self) {
+        if let Some(yank) = self.yank.take() {
+            for (_, mark, _) in yank.pasted {
+                self.remove_mark(mark);
+            }
+        }
+    }
+
+    // replace every selection with kill ring entry index
+    fn yank(&mut self, ring: &KillRing, index: usize) {
+        self.clear_yank();
+        let clip = match ring.get(index) {
+            Some(clip) => clip,
+            None => return
+        };
+
+        let cursors: Vec<(CursorId, Cursor)> = self.cursors().collect();
+        let mut pieces = clip.pieces_for(cursors.len()).into_iter();
+        // left gravity keeps the marks before the pasted text
+        let marks: Vec<(CursorId, MarkId)> = cursors.iter()
+            .map(|(id, cursor)| (*id, self.new_mark(cursor.range().start, Gravity::Left)))
+            .collect();
+        self.edit_cursors(|_, cursor| (cursor.range(), pieces.next().unwrap()));
+
+        let pasted = marks.into_iter()
+            .filter_map(|(id, mark)| Some((id, mark, self.cursors.get(id)?.end)))
+            .collect();
+        self.yank = Some(Yank {index, pasted, transactions: self.span_table.transaction_count()});
+    }
+
+    // Replace the text of the last paste with the next older kill ring entry.
+    // Returns false if anything was edited or moved since the paste.
+    pub fn yank_pop(&mut self, ring: &KillRing) -> bool {
+        let yank = match self.yank.take() {
+            Some(yank) => yank,
+            None => return false
+        };
+        let fresh = yank.transactions == self.span_table.transaction_count()
+            && yank.pasted.len() == self.cursors.len()
+            && yank.pasted.iter().all(|(id, _, end)| self.cursors.get(*id) == Some(Cursor::caret(*end)));
+        if !fresh || ring.len() < 2 {
+            self.yank = Some(yank);
+            self.clear_yank();
+            return false
+        }
+
+        // select the pasted text again and paste over it
+        for (id, mark, end) in &yank.pasted {
+            let start = self.mark(*mark).pos;
+            self.set_cursor(*id, Cursor::new(start, *end));
+        }
+        let index = yank.index + 1;
+        // yank removes the marks of the previous paste
+        self.yank = Some(yank);
+        self.yank(ring, index);
+        true
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::mark::motion::test::TestAdvance;
+
+    #[derive(Default)]
+    pub struct TestClipboard(pub Option<String>);
+
+    impl Clipboard for TestClipboard {
+        fn text(&self) -> Option<String> {
+            self.0.clone()
+        }
+
+        fn set_text(&mut self, text: &str) {
+            self.0 = Some(text.to_string());
+        }
+    }
+
+    fn carets(eb: &mut EditingBuffer, positions: &[usize]) {
+        for pos in positions {
+            eb.add_cursor(Cursor::caret(*pos));
+        }
+    }
+
+    #[test]
+    fn test_copy_paste_per_cursor() {
+        let mut ring = KillRing::default();
+        let mut clipboard = TestClipboard::default();
+        let mut eb = EditingBuffer::from_bytes("one two\nthree four".as_bytes());
+        eb.add_cursor(Cursor::new(0, 3));
+        eb.add_cursor(Cursor::new(8, 13));
+
+        eb.cut(&TestAdvance, &mut ring, &mut clipboard);
+        assert_eq!(clipboard.0.as_deref(), Some("one\nthree"));
+        assert_eq!(eb.contents(), " two\n four".as_bytes());
+
+        // same number of cursors, so one piece each
+        eb.paste(&mut ring, &clipboard);
+        assert_eq!(eb.contents(), "one two\nthree four".as_bytes());
+
+        // a different number of cursors gets the joined text
+        let mut eb = EditingBuffer::from_bytes("ab".as_bytes());
+        eb.add_cursor(Cursor::caret(1));
+        eb.paste(&mut ring, &clipboard);
+        assert_eq!(eb.contents(), "aone\nthreeb".as_bytes());
+    }
+
+    #[test]
+    fn test_external_clipboard() {
+        let mut ring = KillRing::default();
+        let mut eb = EditingBuffer::from_bytes("".as_bytes());
+        carets(&mut eb, &[0]);
+        eb.paste(&mut ring, &TestClipboard(Some("x\ny".to_string())));
+        assert_eq!(eb.contents(), "x\ny".as_bytes());
+        assert_eq!(ring.len(), 1);
+
+        let mut primary = TestClipboard::default();
+        eb.add_cursor(Cursor::new(0, 1));
+        eb.set_primary(&mut primary);
+        assert_eq!(primary.0.as_deref(), Some("x"));
+
+        // pasted where the click was, the cursors move with the text around them
+        primary.0 = Some("ab".to_string());
+        eb.paste_primary(&primary, 2);
+        assert_eq!(eb.contents(), "x\naby".as_bytes());
+        assert_eq!(eb.cursors().map(|(_, c)| c.range()).collect::<Vec<_>>(), vec![0..1, 5..5]);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut registers = Registers::default();
+        let mut eb = EditingBuffer::from_bytes("one two".as_bytes());
+        eb.add_cursor(Cursor::new(0, 3));
+        eb.add_cursor(Cursor::new(4, 7));
+        assert!(eb.yank_register(&TestAdvance, &mut registers, b'a'));
+        assert!(eb.yank_register(&TestAdvance, &mut registers, b'A'));
+        assert!(!eb.yank_register(&TestAdvance, &mut registers, b'1'));
+
+        // one piece per cursor, appended to piece by piece
+        eb.paste_register(&registers, b'a');
+        assert_eq!(eb.contents(), "oneone twotwo".as_bytes());
+        assert!(!eb.paste_register(&registers, b'b'));
+    }
+
+    #[test]
+    fn test_yank_pop() {
+        let mut ring = KillRing::default();
+        let mut clipboard = TestClipboard::default();
+        let mut eb = EditingBuffer::from_bytes("a b c|".as_bytes());
+        for range in &[(0, 1), (2, 3), (4, 5)] {
+            let id = eb.new_cursor();
+            eb.set_cursor(id, Cursor::new(range.0, range.1));
+            eb.copy(&TestAdvance, &mut ring, &mut clipboard);
+            eb.remove_cursor(id);
+        }
+
+        carets(&mut eb, &[6]);
+        eb.paste(&mut ring, &clipboard);
+        assert_eq!(eb.contents(), "a b c|c".as_bytes());
+        assert!(eb.yank_pop(&ring));
+        assert_eq!(eb.contents(), "a b c|b".as_bytes());
+        assert!(eb.yank_pop(&ring));
+        assert!(eb.yank_pop(&ring));
+        assert_eq!(eb.contents(), "a b c|c".as_bytes());
+
+        // typing ends the yank
+        eb.insert("!".as_bytes());
+        assert!(!eb.yank_pop(&ring));
+        assert_eq!(eb.contents(), "a b c|c!".as_bytes());
+    }
+}
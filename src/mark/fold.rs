@@ -0,0 +1,245 @@
+// Code folding, which hides regions of the buffer behind a placeholder.
+// A fold keeps the line it starts on visible and hides the lines after it. Both of its ends are marks,
+// so edits before or inside a fold move it along with the text.
+
+use std::ops::Range;
+
+use super::{Cursor, CursorId, EditingBuffer, Gravity, MarkId, line_start, line_end};
+use super::indent::indent_end;
+use super::motion::{find_close, BRACKETS};
+use super::reflow::display_width;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FoldKind {
+    // the lines after the cursor line that are indented deeper than it
+    Indent,
+    // the lines between a bracket opened on the cursor line and the line closing it
+    Bracket,
+    // the lines of the selection after its first one
+    Selection,
+}
+
+// start is the end of the line that stays visible and end the end of the last hidden line.
+// Everything after start up to and including end is hidden.
+pub(super) struct Fold {
+    start: MarkId,
+    end: MarkId,
+}
+
+// the hidden region containing pos, folds have to be sorted and apart like the ones EditingBuffer::folds returns
+pub fn fold_at(folds: &[Range<usize>], pos: usize) -> Option<Range<usize>> {
+    let i = folds.partition_point(|fold| fold.start < pos);
+    folds[..i].last().filter(|fold| pos <= fold.end).cloned()
+}
+
+// pos moved out of the fold hiding it, forward to the start of the line after the fold if there is one
+pub(super) fn skip_fold(folds: &[Range<usize>], len: usize, pos: usize, forward: bool) -> usize {
+    match fold_at(folds, pos) {
+        Some(fold) if forward && fold.end < len => fold.end + 1,
+        Some(fold) => fold.start,
+        None => pos,
+    }
+}
+
+fn indent_width(text: &[u8], start: usize) -> usize {
+    display_width(&String::from_utf8_lossy(&text[start..indent_end(text, start)]), 0)
+}
+
+// the lines after the one starting at start that are indented deeper, up to the last one that is not blank
+fn indent_fold(text: &[u8], start: usize) -> Option<Range<usize>> {
+    let header_end = line_end(text, start);
+    let width = indent_width(text, start);
+    let mut end = header_end;
+    let mut line = header_end + 1;
+    while line <= text.len() {
+        let next_end = line_end(text, line);
+        if indent_end(text, line) < next_end {
+            if indent_width(text, line) <= width {
+                break
+            }
+            end = next_end;
+        }
+        line = next_end + 1;
+    }
+    Some(header_end..end).filter(|fold| !fold.is_empty())
+}
+
+// the lines inside the last bracket on the line starting at start that is closed on a later line,
+// the line with the closing bracket stays visible
+fn bracket_fold(text: &[u8], start: usize) -> Option<Range<usize>> {
+    let header_end = line_end(text, start);
+    (start..header_end).rev().find_map(|i| {
+        let (open, close) = BRACKETS.iter().find(|pair| pair.0 == text[i])?;
+        let close_line = line_start(text, find_close(text, i + 1, *open, *close)?);
+        if close_line > header_end + 1 {
+            Some(header_end..close_line - 1)
+        } else {
+            None
+        }
+    })
+}
+
+// the lines of range after the first one, a range ending at the start of a line leaves it out
+fn selection_fold(text: &[u8], range: Range<usize>) -> Option<Range<usize>> {
+    let last = if range.end > range.start && range.end == line_start(text, range.end) {range.end - 1} else {range.end};
+    Some(line_end(text, range.start)..line_end(text, last)).filter(|fold| !fold.is_empty())
+}
+
+impl EditingBuffer {
+    fn fold_range(&self, fold: &Fold) -> Range<usize> {
+        self.mark(fold.start).pos..self.mark(fold.end).pos
+    }
+
+    // hidden regions in order, nested and touching folds are merged into one
+    pub fn folds(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = self.folds.iter()
+            .map(|fold| self.fold_range(fold))
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+
+        let mut merged: Vec<Range<usize>> = Vec::new();
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    // drop the folds for which keep returns false
+    fn retain_folds<F: Fn(Range<usize>) -> bool>(&mut self, keep: F) {
+        let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.folds)
+            .into_iter()
+            .partition(|fold| keep(self.fold_range(fold)));
+        self.folds = kept;
+        for fold in removed {
+            self.remove_mark(fold.start);
+            self.remove_mark(fold.end);
+        }
+    }
+
+    // Fold a region at every cursor and move the cursors out of the regions that got hidden.
+    // Indent and bracket folds start on the line of the cursor, selection folds need a selection over several lines.
+    pub fn fold(&mut self, kind: FoldKind) {
+        let text = self.contents();
+        let ranges: Vec<Range<usize>> = self.cursors()
+            .filter_map(|(_, cursor)| match kind {
+                FoldKind::Indent => indent_fold(&text, line_start(&text, cursor.end)),
+                FoldKind::Bracket => bracket_fold(&text, line_start(&text, cursor.end)),
+                FoldKind::Selection => selection_fold(&text, cursor.range()),
+            })
+            .collect();
+        for range in ranges {
+            if self.folds.iter().any(|fold| self.fold_range(fold) == range) {
+                continue
+            }
+            // right gravity keeps text typed at the end of the visible line out of the fold
+            let start = self.new_mark(range.start, Gravity::Right);
+            let end = self.new_mark(range.end, Gravity::Left);
+            self.folds.push(Fold {start, end});
+        }
+
+        let folds = self.folds();
+        let hidden: Vec<(CursorId, usize)> = self.cursors()
+            .filter_map(|(id, cursor)| Some((id, fold_at(&folds, cursor.end)?.start)))
+            .collect();
+        for (id, pos) in hidden {
+            self.set_cursor(id, Cursor::caret(pos));
+        }
+        self.merge_cursors();
+    }
+
+    // open the folds starting on the line of a cursor
+    pub fn unfold(&mut self) {
+        let text = self.contents();
+        let line_ends: Vec<usize> = self.cursors().map(|(_, cursor)| line_end(&text, cursor.end)).collect();
+        self.retain_folds(|range| !line_ends.contains(&range.start));
+    }
+
+    pub fn unfold_all(&mut self) {
+        self.retain_folds(|_| false);
+    }
+
+    // Drop folds that edits emptied and open the ones a cursor ended up inside of,
+    // for example by selecting a search match.
+    pub(super) fn update_folds(&mut self) {
+        let heads: Vec<usize> = self.cursors().map(|(_, cursor)| cursor.end).collect();
+        self.retain_folds(|range| !range.is_empty() && !heads.iter().any(|head| range.start < *head && *head <= range.end));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::motion::{Motion, test::TestAdvance};
+
+    fn folded(text: &str, cursor: Cursor, kind: FoldKind) -> Vec<String> {
+        let mut eb = EditingBuffer::from_bytes(text.as_bytes());
+        eb.add_cursor(cursor);
+        eb.fold(kind);
+        eb.folds().into_iter().map(|fold| text[fold].to_string()).collect()
+    }
+
+    #[test]
+    fn test_fold_kinds() {
+        let text = "fn a() {\n    b(\n        c);\n\n    d();\n}\ne";
+        assert_eq!(folded(text, Cursor::caret(0), FoldKind::Indent), vec!["\n    b(\n        c);\n\n    d();"]);
+        assert_eq!(folded(text, Cursor::caret(0), FoldKind::Bracket), vec!["\n    b(\n        c);\n\n    d();"]);
+        // the bracket is closed on the next line, so there is nothing between to hide
+        assert_eq!(folded(text, Cursor::caret(12), FoldKind::Bracket), Vec::<String>::new());
+        assert_eq!(folded(text, Cursor::caret(12), FoldKind::Indent), vec!["\n        c);"]);
+        assert_eq!(folded(text, Cursor::new(9, 29), FoldKind::Selection), vec!["\n        c);\n"]);
+        assert_eq!(folded(text, Cursor::caret(9), FoldKind::Selection), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_fold_edits() {
+        let mut eb = EditingBuffer::from_bytes(b"a {\n  b\n}\nc {\n  d\n}");
+        eb.add_cursor(Cursor::caret(10));
+        eb.fold(FoldKind::Bracket);
+        assert_eq!(eb.folds(), vec![13..17]);
+
+        // typing before the fold or at the end of its visible line moves it along
+        let c = eb.cursors().next().unwrap().0;
+        eb.set_cursor(c, Cursor::caret(0));
+        eb.insert(b"x");
+        eb.set_cursor(c, Cursor::caret(14));
+        eb.insert(b"y");
+        assert_eq!(eb.folds(), vec![15..19]);
+        assert_eq!(fold_at(&eb.folds(), 16), Some(15..19));
+        assert_eq!(fold_at(&eb.folds(), 15), None);
+
+        // a cursor landing inside opens it
+        eb.set_cursor(c, Cursor::caret(17));
+        eb.update_folds();
+        assert!(eb.folds().is_empty());
+    }
+
+    #[test]
+    fn test_fold_motions() {
+        let mut eb = EditingBuffer::from_bytes(b"a\n  b\n  c\nd\n  e");
+        eb.add_cursor(Cursor::caret(0));
+        eb.fold(FoldKind::Indent);
+        let head = |eb: &EditingBuffer| eb.cursors().next().unwrap().1.end;
+
+        // the folded line and the lines it hides are one line
+        eb.move_down(&TestAdvance, false);
+        assert_eq!(head(&eb), 10);
+        eb.move_up(&TestAdvance, false);
+        assert_eq!(head(&eb), 0);
+        eb.move_cursors(Motion::LineEnd, false);
+        eb.move_cursors(Motion::GraphemeRight, false);
+        assert_eq!(head(&eb), 10);
+        eb.move_cursors(Motion::GraphemeLeft, false);
+        assert_eq!(head(&eb), 1);
+
+        // a fold reaching the end of the buffer
+        eb.move_down(&TestAdvance, false);
+        eb.fold(FoldKind::Indent);
+        assert_eq!(eb.folds(), vec![1..9, 11..15]);
+        eb.move_cursors(Motion::DocumentEnd, false);
+        assert_eq!(head(&eb), 11);
+    }
+}
@@ -0,0 +1,363 @@
+// Indentation aware editing: auto indent on newline, indenting and dedenting lines,
+// and backspace over a whole indent unit.
+
+use std::collections::BTreeMap;
+
+use crate::text_renderer::TAB_SIZE;
+
+use super::{Cursor, CursorId, EditingBuffer, line_start, line_end};
+use super::motion::is_blank_line;
+use super::selection::char_column;
+
+// number of lines looked at to detect the indent style of a file
+const SAMPLE_LINES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndentStyle {
+    Tabs,
+    // number of spaces in a level
+    Spaces(usize),
+}
+
+impl Default for IndentStyle {
+    fn default() -> Self {
+        IndentStyle::Spaces(TAB_SIZE)
+    }
+}
+
+fn is_blank(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+// end of the leading whitespace of the line starting at start
+pub(super) fn indent_end(text: &[u8], start: usize) -> usize {
+    let end = line_end(text, start);
+    start + text[start..end].iter().take_while(|b| is_blank(**b)).count()
+}
+
+// line starts of every line a range touches, a range ending at the start of a line leaves it out
+fn line_starts(text: &[u8], start: usize, end: usize) -> Vec<usize> {
+    let mut lines = vec![line_start(text, start)];
+    let mut pos = line_end(text, start);
+    while pos < text.len() && pos + 1 < end {
+        lines.push(pos + 1);
+        pos = line_end(text, pos + 1);
+    }
+    lines
+}
+
+impl IndentStyle {
+    // Guess the style from the leading whitespace of the first lines.
+    // Spaces are measured from the changes in indentation between lines, so alignment
+    // and deeply nested blocks do not skew it. None if nothing in text is indented.
+    pub fn detect(text: &[u8]) -> Option<IndentStyle> {
+        let (mut tabs, mut spaces) = (0, 0);
+        // how often each change in indentation appears
+        let mut changes = [0usize; 9];
+        let mut prev = 0;
+        for line in text.split(|b| *b == b'\n').take(SAMPLE_LINES) {
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue
+            }
+            if line[0] == b'\t' {
+                tabs += 1;
+                continue
+            }
+            let width = line.iter().take_while(|b| **b == b' ').count();
+            if width > 0 {
+                spaces += 1;
+            }
+            let change = width.abs_diff(prev);
+            if change < changes.len() {
+                changes[change] += 1;
+            }
+            prev = width;
+        }
+
+        if tabs > spaces {
+            return Some(IndentStyle::Tabs)
+        }
+        // the smallest of the most common changes
+        let width = (2..changes.len()).rev().max_by_key(|width| changes[*width])?;
+        if spaces == 0 || changes[width] == 0 {
+            return None
+        }
+        Some(IndentStyle::Spaces(width))
+    }
+
+    // one level of indentation
+    pub fn unit(self) -> Vec<u8> {
+        match self {
+            IndentStyle::Tabs => vec![b'\t'],
+            IndentStyle::Spaces(width) => vec![b' '; width],
+        }
+    }
+
+    // bytes taken by the first level of indentation in whitespace
+    fn unit_len(self, whitespace: &[u8]) -> usize {
+        match whitespace.first() {
+            Some(b'\t') => 1,
+            Some(b' ') => {
+                let width = match self {
+                    IndentStyle::Tabs => TAB_SIZE,
+                    IndentStyle::Spaces(width) => width,
+                };
+                whitespace.iter().take(width).take_while(|b| **b == b' ').count()
+            }
+            _ => 0
+        }
+    }
+}
+
+// Start of the range backspace at pos deletes when pos is in leading spaces,
+// which goes back to the previous indent level instead of one space.
+pub fn indent_backspace(text: &[u8], pos: usize, style: IndentStyle) -> Option<usize> {
+    let width = match style {
+        IndentStyle::Spaces(width) if width > 1 => width,
+        _ => return None
+    };
+    let start = line_start(text, pos);
+    let column = pos - start;
+    if column == 0 || !text[start..pos].iter().all(|b| *b == b' ') {
+        return None
+    }
+    Some(start + (column - 1) / width * width)
+}
+
+// Leading whitespace to replace when a closing bracket is typed at pos on a line with nothing else
+// before it, which takes the line back a level. The range start and the whitespace to put there.
+pub fn closing_dedent(text: &[u8], pos: usize, ch: u8, style: IndentStyle) -> Option<(usize, Vec<u8>)> {
+    let start = line_start(text, pos);
+    let whitespace = &text[start..pos];
+    if !matches!(ch, b'}' | b')' | b']') || whitespace.is_empty() || !whitespace.iter().all(|b| is_blank(*b)) {
+        return None
+    }
+    Some((start, whitespace[style.unit_len(whitespace)..].to_vec()))
+}
+
+impl EditingBuffer {
+    pub fn indent_style(&self) -> IndentStyle {
+        self.indent
+    }
+
+    pub fn set_indent_style(&mut self, style: IndentStyle) {
+        self.indent = style;
+    }
+
+    // Break the line at every cursor, the new line keeps the indentation of the old one.
+    // A level is added after an opening bracket or colon and removed before a closing bracket.
+    // Whitespace around the cursor is dropped so no line is left with trailing whitespace.
+    // Between a pair of brackets the closing one goes on a line of its own after an indented line.
+    pub fn newline(&mut self) {
+        let text = self.contents();
+        let style = self.indent;
+        let cursors: Vec<(CursorId, Cursor)> = self.cursors().collect();
+        // the edit of every cursor and where its caret ends up from the start of the edit
+        let mut edits: Vec<(CursorId, usize, usize, Vec<u8>, usize)> = Vec::with_capacity(cursors.len());
+        for (i, (id, cursor)) in cursors.iter().enumerate() {
+            let range = cursor.range();
+            let start = line_start(&text, range.start);
+            // the whitespace dropped stops at the neighbouring cursors, which may be in the same run of it
+            let prev_end = edits.last().map_or(start, |edit| edit.2);
+            let next_start = cursors.get(i + 1).map_or(text.len(), |(_, next)| next.range().start);
+            let before = start + text[start..range.start].iter().rposition(|b| !is_blank(*b)).map_or(0, |i| i + 1);
+            let before = before.max(prev_end);
+            let after = range.end + text[range.end..].iter().take_while(|b| is_blank(**b)).count();
+            let after = after.min(next_start);
+            let open = if before > start {text[before - 1]} else {0};
+            let close = text.get(after).copied().unwrap_or(0);
+
+            let base = &text[start..indent_end(&text, start).min(range.start)];
+            let mut indent = base.to_vec();
+            if matches!(open, b'{' | b'(' | b'[' | b':') {
+                indent.extend(style.unit());
+            }
+            let mut content = vec![b'\n'];
+            if matches!((open, close), (b'{', b'}') | (b'(', b')') | (b'[', b']')) {
+                content.extend(indent);
+                let caret = content.len();
+                content.push(b'\n');
+                content.extend(base);
+                edits.push((*id, before, after, content, caret));
+                continue
+            }
+            if matches!(close, b'}' | b')' | b']') {
+                indent.drain(..style.unit_len(&indent));
+            }
+            content.extend(indent);
+            let caret = content.len();
+            edits.push((*id, before, after, content, caret));
+        }
+
+        self.transaction(|eb| {
+            // back to front so the ranges of the remaining edits stay valid
+            for (id, start, end, content, caret) in edits.into_iter().rev() {
+                eb.replace(start, end, &content);
+                eb.set_cursor(id, Cursor::caret(start + caret));
+            }
+        });
+    }
+
+    // Tab: a caret inserts indentation up to the next level,
+    // a selection indents every line it touches except blank ones.
+    pub fn indent(&mut self) {
+        let text = self.contents();
+        let mut edits: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        for (_, cursor) in self.cursors() {
+            if cursor.is_caret() {
+                let content = match self.indent {
+                    IndentStyle::Tabs => vec![b'\t'],
+                    IndentStyle::Spaces(width) => vec![b' '; width - char_column(&text, cursor.end) % width],
+                };
+                edits.insert(cursor.end, content);
+                continue
+            }
+            let range = cursor.range();
+            for start in line_starts(&text, range.start, range.end) {
+                if !is_blank_line(&text, start) {
+                    edits.insert(start, self.indent.unit());
+                }
+            }
+        }
+
+        self.transaction(|eb| {
+            // back to front so the positions of the remaining edits stay valid
+            for (pos, content) in edits.into_iter().rev() {
+                eb.replace(pos, pos, &content);
+            }
+        });
+    }
+
+    // Shift-Tab: remove a level from every line touched by a cursor
+    pub fn dedent(&mut self) {
+        let text = self.contents();
+        let mut edits: BTreeMap<usize, usize> = BTreeMap::new();
+        for (_, cursor) in self.cursors() {
+            let range = cursor.range();
+            for start in line_starts(&text, range.start, range.end) {
+                let len = self.indent.unit_len(&text[start..indent_end(&text, start)]);
+                if len > 0 {
+                    edits.insert(start, len);
+                }
+            }
+        }
+
+        self.transaction(|eb| {
+            for (start, len) in edits.into_iter().rev() {
+                eb.replace(start, start + len, &[]);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::Cursor;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(IndentStyle::detect(b"fn a() {\n\tb();\n}\n"), Some(IndentStyle::Tabs));
+        let two = b"a:\n  b:\n    c\n  d\n      aligned\n";
+        assert_eq!(IndentStyle::detect(two), Some(IndentStyle::Spaces(2)));
+        let four = b"fn a() {\n    if b {\n        c();\n    }\n}\n";
+        assert_eq!(IndentStyle::detect(four), Some(IndentStyle::Spaces(4)));
+        assert_eq!(IndentStyle::detect(b"no\nindent\n"), None);
+
+        // detected when the buffer is loaded
+        let eb = EditingBuffer::from_bytes(two);
+        assert_eq!(eb.indent_style(), IndentStyle::Spaces(2));
+    }
+
+    #[test]
+    fn test_newline() {
+        let mut eb = EditingBuffer::from_bytes(b"    if a {   b }");
+        eb.add_cursor(Cursor::caret(10));
+        eb.newline();
+        assert_eq!(eb.contents(), b"    if a {\n        b }");
+        assert_eq!(eb.cursors().next().unwrap().1, Cursor::caret(19));
+
+        eb.move_cursors(super::super::motion::Motion::GraphemeRight, false);
+        eb.newline();
+        assert_eq!(eb.contents(), b"    if a {\n        b\n    }");
+
+        // between a pair the caret goes on an indented line and the closing bracket back to the outer level
+        let mut eb = EditingBuffer::from_bytes(b"\tf(x) {}");
+        eb.set_indent_style(IndentStyle::Tabs);
+        eb.add_cursor(Cursor::caret(7));
+        eb.newline();
+        assert_eq!(eb.contents(), b"\tf(x) {\n\t\t\n\t}");
+        assert_eq!(eb.cursors().next().unwrap().1, Cursor::caret(10));
+    }
+
+    #[test]
+    fn test_multiple_carets() {
+        // two carets in one run of blanks each break the line
+        let mut eb = EditingBuffer::from_bytes(b"a      b");
+        eb.add_cursor(Cursor::caret(3));
+        eb.add_cursor(Cursor::caret(5));
+        eb.newline();
+        assert_eq!(eb.contents(), b"a\n\nb");
+        assert_eq!(eb.cursors().map(|(_, c)| c.end).collect::<Vec<_>>(), vec![2, 3]);
+
+        // backspace from two carets in one indent deletes back to the level before the first
+        let mut eb = EditingBuffer::from_bytes(b"        x");
+        eb.set_indent_style(IndentStyle::Spaces(4));
+        eb.add_cursor(Cursor::caret(6));
+        eb.add_cursor(Cursor::caret(8));
+        eb.delete_backward();
+        assert_eq!(eb.contents(), b"    x");
+        assert_eq!(eb.cursors().map(|(_, c)| c.end).collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[test]
+    fn test_closing_dedent() {
+        let mut eb = EditingBuffer::from_bytes(b"if a {\n    b\n    ");
+        eb.add_cursor(Cursor::caret(17));
+        eb.type_text(b"}");
+        assert_eq!(eb.contents(), b"if a {\n    b\n}");
+        assert_eq!(eb.cursors().next().unwrap().1, Cursor::caret(14));
+
+        // only on a line with nothing before the caret
+        eb.type_text(b"\n    x");
+        eb.type_text(b"}");
+        assert_eq!(eb.contents(), b"if a {\n    b\n}\n    x}");
+    }
+
+    #[test]
+    fn test_indent_dedent() {
+        // two space indents are detected from the file
+        let mut eb = EditingBuffer::from_bytes(b"a\n\n  b\nc");
+        eb.add_cursor(Cursor::new(0, 6));
+        eb.indent();
+        assert_eq!(eb.contents(), b"  a\n\n    b\nc");
+        // the selection still covers the lines it started on and the indentation added to them
+        assert_eq!(eb.cursors().next().unwrap().1, Cursor::new(0, 10));
+        eb.dedent();
+        eb.dedent();
+        assert_eq!(eb.contents(), b"a\n\nb\nc");
+        assert_eq!(eb.cursors().next().unwrap().1, Cursor::new(0, 4));
+
+        // a caret indents to the next level
+        let mut eb = EditingBuffer::from_bytes(b"ab");
+        eb.add_cursor(Cursor::caret(1));
+        eb.indent();
+        assert_eq!(eb.contents(), b"a   b");
+    }
+
+    #[test]
+    fn test_backspace_indent() {
+        let mut eb = EditingBuffer::from_bytes(b"      x\n  y");
+        eb.add_cursor(Cursor::caret(6));
+        eb.delete_backward();
+        assert_eq!(eb.contents(), b"    x\n  y");
+        eb.delete_backward();
+        assert_eq!(eb.contents(), b"x\n  y");
+
+        // not in leading whitespace, so only one space goes
+        let mut eb = EditingBuffer::from_bytes(b"a    b");
+        eb.add_cursor(Cursor::caret(5));
+        eb.delete_backward();
+        assert_eq!(eb.contents(), b"a   b");
+    }
+}
@@ -0,0 +1,620 @@
+// Line operations: duplicating, moving, deleting, joining, sorting and commenting lines,
+// plus transposing and changing the case of text. Every command is one transaction.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use super::{Cursor, CursorId, EditingBuffer, line_start, line_end};
+use super::indent::indent_end;
+use super::motion::{CharClass, char_class, is_blank_line, next_grapheme, prev_grapheme, skip_back_while, skip_while};
+use super::text_object::TextObject;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SortOptions {
+    pub case_insensitive: bool,
+    // order by the first number on each line
+    pub numeric: bool,
+    pub reverse: bool,
+    // drop lines equal to the one before them after sorting
+    pub unique: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Case {
+    Upper,
+    Lower,
+    Title,
+    Snake,
+    Camel,
+}
+
+// The token starting a line comment in a language
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommentToken(pub Vec<u8>);
+
+impl Default for CommentToken {
+    fn default() -> Self {
+        CommentToken(b"//".to_vec())
+    }
+}
+
+impl CommentToken {
+    // token for a file extension, languages without one get //
+    pub fn for_extension(extension: &str) -> Self {
+        let token: &[u8] = match extension {
+            "py" | "sh" | "rb" | "pl" | "toml" | "yaml" | "yml" | "conf" | "mk" => b"#",
+            "lua" | "sql" | "hs" | "elm" => b"--",
+            "lisp" | "el" | "scm" | "clj" | "asm" | "ini" => b";",
+            "tex" | "erl" => b"%",
+            "vim" => b"\"",
+            _ => return CommentToken::default()
+        };
+        CommentToken(token.to_vec())
+    }
+}
+
+// the first number on a line, used for numeric sorting
+fn leading_number(line: &[u8]) -> Option<f64> {
+    let start = line.iter().position(|b| b.is_ascii_digit())?;
+    let start = if start > 0 && line[start - 1] == b'-' {start - 1} else {start};
+    let len = line[start + 1..].iter().take_while(|b| b.is_ascii_digit() || **b == b'.').count();
+    std::str::from_utf8(&line[start..start + 1 + len]).ok()?.parse().ok()
+}
+
+// Words of an identifier or phrase, split at anything but letters and digits and at camelCase humps.
+// An acronym ends before its last capital when that starts a word, as in HTTPResponse.
+fn split_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut prev: Option<char> = None;
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let acronym_end = prev.is_some_and(char::is_uppercase) && chars.peek().is_some_and(|next| next.is_lowercase());
+        let hump = ch.is_uppercase() && (acronym_end || prev.is_some_and(|prev| prev.is_lowercase() || prev.is_numeric()));
+        if (!ch.is_alphanumeric() || hump) && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        if ch.is_alphanumeric() {
+            word.push(ch);
+            prev = Some(ch);
+        } else {
+            prev = None;
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+        None => String::new()
+    }
+}
+
+pub fn convert_case(text: &str, case: Case) -> String {
+    match case {
+        Case::Upper => text.to_uppercase(),
+        Case::Lower => text.to_lowercase(),
+        Case::Title => {
+            let mut prev_alnum = false;
+            text.chars().flat_map(|ch| {
+                let converted: Vec<char> = if prev_alnum {
+                    ch.to_lowercase().collect()
+                } else {
+                    ch.to_uppercase().collect()
+                };
+                prev_alnum = ch.is_alphanumeric();
+                converted
+            }).collect()
+        }
+        // each line becomes one identifier, keeping its leading and trailing whitespace
+        Case::Snake | Case::Camel => text.split('\n').map(|line| {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                return line.to_string()
+            }
+            let leading = &line[..line.len() - line.trim_start().len()];
+            let trailing = &line[line.trim_end().len()..];
+            let words = split_words(trimmed);
+            let joined = if case == Case::Snake {
+                words.iter().map(|word| word.to_lowercase()).collect::<Vec<_>>().join("_")
+            } else {
+                words.iter().enumerate()
+                    .map(|(i, word)| if i == 0 {word.to_lowercase()} else {capitalize(word)})
+                    .collect()
+            };
+            format!("{}{}{}", leading, joined, trailing)
+        }).collect::<Vec<_>>().join("\n")
+    }
+}
+
+impl EditingBuffer {
+    pub fn comment_token(&self) -> &CommentToken {
+        &self.comment
+    }
+
+    pub fn set_comment_token(&mut self, token: CommentToken) {
+        self.comment = token;
+    }
+
+    // The lines touched by every cursor, from a line start to the end of a line before its newline.
+    // A selection ending at the start of a line leaves that line out. Touching blocks are merged.
+    fn line_blocks(&self, text: &[u8]) -> Vec<Range<usize>> {
+        let mut blocks: Vec<Range<usize>> = Vec::new();
+        for (_, cursor) in self.cursors() {
+            let range = cursor.range();
+            let end = if range.end > range.start && range.end == line_start(text, range.end) {
+                range.end - 1
+            } else {
+                range.end
+            };
+            let block = line_start(text, range.start)..line_end(text, end);
+            match blocks.last_mut() {
+                Some(last) if block.start <= last.end + 1 => last.end = last.end.max(block.end),
+                _ => blocks.push(block),
+            }
+        }
+        blocks
+    }
+
+    // Make every edit in one transaction, the ranges are from before any of them.
+    // Edits overlapping an earlier one are dropped.
+    pub(super) fn replace_all(&mut self, mut edits: Vec<(Range<usize>, Vec<u8>)>) {
+        edits.sort_by_key(|(range, _)| (range.start, range.end));
+        let mut end = 0;
+        edits.retain(|(range, _)| {
+            let keep = range.start >= end;
+            if keep {
+                end = range.end;
+            }
+            keep
+        });
+
+        self.transaction(|eb| {
+            // back to front so the ranges of the remaining edits stay valid
+            for (range, content) in edits.into_iter().rev() {
+                eb.replace(range.start, range.end, &content);
+            }
+        });
+    }
+
+    // Duplicate the selection, or the line of a caret. The cursor ends up on the copy.
+    pub fn duplicate(&mut self) {
+        let text = self.contents();
+        let mut edits: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        let mut selections = Vec::new();
+        for (id, cursor) in self.cursors() {
+            let range = cursor.range();
+            // inserting the copy before the original moves a caret onto the copy
+            if cursor.is_caret() {
+                let start = line_start(&text, range.start);
+                let mut line = text[start..line_end(&text, start)].to_vec();
+                line.push(b'\n');
+                edits.insert(start, line);
+            } else {
+                selections.push((id, range.len()));
+                edits.insert(range.start, text[range].to_vec());
+            }
+        }
+        self.transaction(|eb| {
+            eb.replace_all(edits.into_iter().map(|(pos, content)| (pos..pos, content)).collect());
+            // a selection keeps what is inserted at its start, so it covers both now
+            for (id, len) in selections {
+                let cursor = eb.cursor(id);
+                let cursor = if cursor.start < cursor.end {
+                    Cursor::new(cursor.start + len, cursor.end)
+                } else {
+                    Cursor::new(cursor.start, cursor.end + len)
+                };
+                eb.set_cursor(id, cursor);
+            }
+        });
+    }
+
+    // swap the lines of every cursor with the line above or below, the cursors move with them
+    fn move_lines(&mut self, up: bool) {
+        let text = self.contents();
+        let cursors: Vec<(CursorId, Cursor)> = self.cursors().collect();
+        let mut edits = Vec::new();
+        let mut moved = Vec::new();
+        for block in self.line_blocks(&text) {
+            let lines = &text[block.clone()];
+            let (range, content, shift) = if up {
+                if block.start == 0 {
+                    continue
+                }
+                let prev = line_start(&text, block.start - 1);
+                let content = [lines, b"\n", &text[prev..block.start - 1]].concat();
+                (prev..block.end, content, -((block.start - prev) as isize))
+            } else {
+                if block.end >= text.len() {
+                    continue
+                }
+                let next_end = line_end(&text, block.end + 1);
+                let content = [&text[block.end + 1..next_end], b"\n", lines].concat();
+                (block.start..next_end, content, (next_end - block.end) as isize)
+            };
+
+            let shifted = |pos: usize| (pos as isize + shift) as usize;
+            for (id, cursor) in &cursors {
+                if block.contains(&cursor.range().start) || block.end == cursor.range().start {
+                    moved.push((*id, Cursor {start: shifted(cursor.start), end: shifted(cursor.end), ..*cursor}));
+                }
+            }
+            edits.push((range, content));
+        }
+
+        self.transaction(|eb| {
+            eb.replace_all(edits);
+            for (id, cursor) in moved {
+                eb.set_cursor(id, cursor);
+            }
+        });
+    }
+
+    pub fn move_lines_up(&mut self) {
+        self.move_lines(true);
+    }
+
+    pub fn move_lines_down(&mut self) {
+        self.move_lines(false);
+    }
+
+    // delete every line touched by a cursor, with its newline
+    pub fn delete_lines(&mut self) {
+        let text = self.contents();
+        let edits = self.line_blocks(&text).into_iter().map(|block| {
+            let range = if block.end < text.len() {
+                block.start..block.end + 1
+            } else {
+                // the last line has no newline of its own, take the one before it
+                block.start.saturating_sub(1)..block.end
+            };
+            (range, Vec::new())
+        }).collect();
+        self.replace_all(edits);
+    }
+
+    // Join the lines of a selection, or the line of a caret with the next one.
+    // The whitespace around each newline becomes a single space.
+    pub fn join_lines(&mut self) {
+        let text = self.contents();
+        let mut edits = Vec::new();
+        for (_, cursor) in self.cursors() {
+            let range = cursor.range();
+            let mut newline = line_end(&text, range.start);
+            while newline < text.len() {
+                let start = line_start(&text, newline);
+                let before = start + text[start..newline].iter().rposition(|b| *b != b' ' && *b != b'\t').map_or(0, |i| i + 1);
+                let after = indent_end(&text, newline + 1);
+                let space = before > start && after < text.len() && text[after] != b'\n';
+                edits.push((before..after, if space {b" ".to_vec()} else {Vec::new()}));
+
+                newline = line_end(&text, newline + 1);
+                if newline >= range.end {
+                    break
+                }
+            }
+        }
+        self.replace_all(edits);
+    }
+
+    // Replace the lines of every cursor with f of them, then select each block of lines.
+    // Without a selection this works on the whole buffer, except for a final newline.
+    fn map_lines<F>(&mut self, f: F)
+        where F: Fn(Vec<&[u8]>) -> Vec<Vec<u8>>
+    {
+        let text = self.contents();
+        let blocks = if self.cursors().all(|(_, cursor)| cursor.is_caret()) {
+            let end = text.strip_suffix(b"\n").map_or(text.len(), |text| text.len());
+            std::iter::once(0..end).collect()
+        } else {
+            self.line_blocks(&text)
+        };
+
+        let mut edits = Vec::new();
+        let mut selections = Vec::new();
+        let mut shift = 0isize;
+        for block in blocks {
+            let lines = text[block.clone()].split(|b| *b == b'\n').collect();
+            let content = f(lines).join(&b'\n');
+            let start = (block.start as isize + shift) as usize;
+            selections.push(Cursor::new(start, start + content.len()));
+            shift += content.len() as isize - block.len() as isize;
+            edits.push((block, content));
+        }
+
+        self.transaction(|eb| {
+            eb.replace_all(edits);
+            let ids: Vec<CursorId> = eb.cursors().map(|(id, _)| id).collect();
+            for id in ids {
+                eb.remove_cursor(id);
+            }
+            for selection in selections {
+                eb.cursors.insert(selection);
+            }
+        });
+    }
+
+    pub fn sort_lines(&mut self, options: SortOptions) {
+        self.map_lines(|mut lines| {
+            let key = |line: &[u8]| if options.case_insensitive {line.to_ascii_lowercase()} else {line.to_vec()};
+            if options.numeric {
+                lines.sort_by(|a, b| {
+                    leading_number(a).partial_cmp(&leading_number(b)).unwrap_or(Ordering::Equal)
+                        .then_with(|| key(a).cmp(&key(b)))
+                });
+            } else {
+                lines.sort_by_key(|line| key(line));
+            }
+            if options.unique {
+                lines.dedup_by(|a, b| key(a) == key(b));
+            }
+            if options.reverse {
+                lines.reverse();
+            }
+            lines.into_iter().map(<[u8]>::to_vec).collect()
+        });
+    }
+
+    pub fn reverse_lines(&mut self) {
+        self.map_lines(|lines| lines.into_iter().rev().map(<[u8]>::to_vec).collect());
+    }
+
+    // Swap the characters on either side of every caret and move past them.
+    // At the end of a line the two characters before the caret are swapped.
+    // A swap overlapping the swap of an earlier caret is skipped.
+    pub fn transpose_chars(&mut self) {
+        self.edit_cursors(|text, cursor| {
+            let mut pos = cursor.end;
+            let start = line_start(text, pos);
+            if pos == line_end(text, pos) && pos > start {
+                pos = prev_grapheme(text, pos);
+            }
+            let (before, after) = (prev_grapheme(text, pos), next_grapheme(text, pos));
+            if before < start || pos == start || after == pos || text[pos] == b'\n' {
+                return (cursor.end..cursor.end, Vec::new())
+            }
+            (before..after, [&text[pos..after], &text[before..pos]].concat())
+        });
+    }
+
+    // Swap the word before every caret with the word after it, or the two words before it
+    // at the end of a line. A caret inside a word counts as being at its end.
+    pub fn transpose_words(&mut self) {
+        let is_word = |ch: char| char_class(ch) == CharClass::Word;
+        let not_word = |ch: char| char_class(ch) != CharClass::Word;
+        self.edit_cursors(|text, cursor| {
+            let pos = skip_while(text, cursor.end, is_word);
+            let mut a_end = skip_back_while(text, pos, not_word);
+            let mut a_start = skip_back_while(text, a_end, is_word);
+            let mut b_start = skip_while(text, pos, not_word);
+            let mut b_end = skip_while(text, b_start, is_word);
+            if b_start == b_end {
+                b_start = a_start;
+                b_end = a_end;
+                a_end = skip_back_while(text, a_start, not_word);
+                a_start = skip_back_while(text, a_end, is_word);
+            }
+            if a_start == a_end || b_start == b_end {
+                return (cursor.end..cursor.end, Vec::new())
+            }
+            let content = [&text[b_start..b_end], &text[a_end..b_start], &text[a_start..a_end]].concat();
+            (a_start..b_end, content)
+        });
+    }
+
+    // Change the case of every selection, or of the word at a caret. The new text stays selected.
+    pub fn convert_case(&mut self, case: Case) {
+        let text = self.contents();
+        let mut edits = Vec::new();
+        let mut selections = Vec::new();
+        let mut shift = 0isize;
+        for (id, cursor) in self.cursors() {
+            let range = if cursor.is_caret() {
+                match TextObject::Word.range(&text, cursor.range(), true) {
+                    Some(range) => range,
+                    None => continue
+                }
+            } else {
+                cursor.range()
+            };
+            let converted = convert_case(&String::from_utf8_lossy(&text[range.clone()]), case).into_bytes();
+            let start = (range.start as isize + shift) as usize;
+            let selection = if cursor.start > cursor.end {
+                Cursor::new(start + converted.len(), start)
+            } else {
+                Cursor::new(start, start + converted.len())
+            };
+            selections.push((id, selection));
+            shift += converted.len() as isize - range.len() as isize;
+            edits.push((range, converted));
+        }
+
+        self.transaction(|eb| {
+            eb.replace_all(edits);
+            for (id, selection) in selections {
+                eb.set_cursor(id, selection);
+            }
+        });
+    }
+
+    // Comment out the lines touched by every cursor at their smallest indentation,
+    // or uncomment them if every non blank line is already commented.
+    pub fn toggle_comment(&mut self) {
+        let text = self.contents();
+        let token = &self.comment.0;
+        let mut edits = Vec::new();
+        for block in self.line_blocks(&text) {
+            let mut lines = Vec::new();
+            let mut start = block.start;
+            while start <= block.end {
+                if !is_blank_line(&text, start) {
+                    lines.push((start, indent_end(&text, start)));
+                }
+                start = line_end(&text, start) + 1;
+            }
+
+            if lines.iter().all(|(_, indent)| text[*indent..].starts_with(token)) {
+                for (_, indent) in lines {
+                    let mut len = token.len();
+                    if text.get(indent + len) == Some(&b' ') {
+                        len += 1;
+                    }
+                    edits.push((indent..indent + len, Vec::new()));
+                }
+            } else {
+                let column = lines.iter().map(|(start, indent)| indent - start).min().unwrap_or(0);
+                for (start, _) in lines {
+                    edits.push((start + column..start + column, [token.as_slice(), b" "].concat()));
+                }
+            }
+        }
+        self.replace_all(edits);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn buffer(text: &str, cursors: &[(usize, usize)]) -> EditingBuffer {
+        let mut eb = EditingBuffer::from_bytes(text.as_bytes());
+        for (start, end) in cursors {
+            eb.add_cursor(Cursor::new(*start, *end));
+        }
+        eb
+    }
+
+    fn contents(eb: &EditingBuffer) -> String {
+        String::from_utf8(eb.contents()).unwrap()
+    }
+
+    fn ranges(eb: &EditingBuffer) -> Vec<Range<usize>> {
+        eb.cursors().map(|(_, c)| c.range()).collect()
+    }
+
+    #[test]
+    fn test_duplicate_move_delete() {
+        let mut eb = buffer("a\nbc\nd", &[(3, 3)]);
+        let transactions = eb.span_table.transaction_count();
+        eb.duplicate();
+        assert_eq!(contents(&eb), "a\nbc\nbc\nd");
+        assert_eq!(ranges(&eb), vec![6..6]);
+        assert_eq!(eb.span_table.transaction_count(), transactions + 1);
+
+        eb.move_lines_up();
+        eb.move_lines_up();
+        assert_eq!(contents(&eb), "bc\na\nbc\nd");
+        assert_eq!(ranges(&eb), vec![1..1]);
+        eb.move_lines_down();
+        assert_eq!(contents(&eb), "a\nbc\nbc\nd");
+        assert_eq!(ranges(&eb), vec![3..3]);
+
+        // two cursors on neighbouring lines move as one block
+        let mut eb = buffer("1\n2\n3\n4", &[(2, 2), (4, 5)]);
+        eb.move_lines_down();
+        assert_eq!(contents(&eb), "1\n4\n2\n3");
+        eb.delete_lines();
+        assert_eq!(contents(&eb), "1\n4");
+
+        let mut eb = buffer("ab cd", &[(1, 2)]);
+        eb.duplicate();
+        assert_eq!(contents(&eb), "abb cd");
+        assert_eq!(ranges(&eb), vec![2..3]);
+    }
+
+    #[test]
+    fn test_join_lines() {
+        let mut eb = buffer("a  \n   b\nc\n\nd", &[(0, 0), (10, 10)]);
+        eb.join_lines();
+        assert_eq!(contents(&eb), "a b\nc\nd");
+
+        let mut eb = buffer("x\ny\nz\nw", &[(0, 5)]);
+        eb.join_lines();
+        assert_eq!(contents(&eb), "x y z\nw");
+    }
+
+    #[test]
+    fn test_sort_lines() {
+        let text = "b\nC\na\n10\n9\nb\n";
+        let mut eb = buffer(text, &[(0, 0)]);
+        eb.sort_lines(SortOptions::default());
+        assert_eq!(contents(&eb), "10\n9\nC\na\nb\nb\n");
+
+        let mut eb = buffer(text, &[(0, 0)]);
+        eb.sort_lines(SortOptions {case_insensitive: true, unique: true, reverse: true, ..SortOptions::default()});
+        assert_eq!(contents(&eb), "C\nb\na\n9\n10\n");
+
+        let mut eb = buffer("x3\nx-1\nnone\nx20", &[(0, 0)]);
+        eb.sort_lines(SortOptions {numeric: true, ..SortOptions::default()});
+        assert_eq!(contents(&eb), "none\nx-1\nx3\nx20");
+
+        // only the selected lines, which stay selected
+        let mut eb = buffer("z\nc\nb\na", &[(2, 6)]);
+        eb.reverse_lines();
+        assert_eq!(contents(&eb), "z\nb\nc\na");
+        assert_eq!(ranges(&eb), vec![2..5]);
+    }
+
+    #[test]
+    fn test_transpose() {
+        let mut eb = buffer("abc\nxy", &[(1, 1), (6, 6)]);
+        eb.transpose_chars();
+        assert_eq!(contents(&eb), "bac\nyx");
+        assert_eq!(ranges(&eb), vec![2..2, 6..6]);
+
+        let mut eb = buffer("one, two three", &[(1, 1)]);
+        eb.transpose_words();
+        assert_eq!(contents(&eb), "two, one three");
+        assert_eq!(ranges(&eb), vec![8..8]);
+        let mut eb = buffer("one two", &[(7, 7)]);
+        eb.transpose_words();
+        assert_eq!(contents(&eb), "two one");
+
+        // a caret whose swap overlaps the one before it is left out
+        let mut eb = buffer("abcd", &[(1, 1), (2, 2)]);
+        eb.transpose_chars();
+        assert_eq!(contents(&eb), "bacd");
+        let mut eb = buffer("one two three", &[(1, 1), (5, 5)]);
+        eb.transpose_words();
+        assert_eq!(contents(&eb), "two one three");
+    }
+
+    #[test]
+    fn test_convert_case() {
+        assert_eq!(convert_case("hello wORLD-x", Case::Title), "Hello World-X");
+        assert_eq!(convert_case("  parseHTTPResponse code\n", Case::Snake), "  parse_http_response_code\n");
+        assert_eq!(convert_case("XMLHttpRequest2", Case::Snake), "xml_http_request2");
+        assert_eq!(convert_case("get_user id", Case::Camel), "getUserId");
+
+        let mut eb = buffer("let some value = 1", &[(4, 14), (17, 17)]);
+        eb.convert_case(Case::Snake);
+        assert_eq!(contents(&eb), "let some_value = 1");
+        eb.convert_case(Case::Upper);
+        assert_eq!(contents(&eb), "let SOME_VALUE = 1");
+        assert_eq!(ranges(&eb), vec![4..14, 17..18]);
+    }
+
+    #[test]
+    fn test_toggle_comment() {
+        let mut eb = buffer("fn a() {\n    b();\n\n  c();\n}", &[(9, 25)]);
+        eb.toggle_comment();
+        assert_eq!(contents(&eb), "fn a() {\n  //   b();\n\n  // c();\n}");
+        // the selection spans both edits, so its end moves with them
+        assert_eq!(ranges(&eb), vec![9..31]);
+        eb.toggle_comment();
+        assert_eq!(contents(&eb), "fn a() {\n    b();\n\n  c();\n}");
+        assert_eq!(ranges(&eb), vec![9..25]);
+
+        let mut eb = buffer("x = 1", &[(0, 0)]);
+        eb.set_comment_token(CommentToken::for_extension("py"));
+        eb.toggle_comment();
+        assert_eq!(contents(&eb), "# x = 1");
+    }
+}
@@ -1,53 +1,321 @@
+use crate::span_table::{Span, SpanTable};
 
-use crate::span_table::SpanTable;
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
 
-use std::rc::{Rc, Weak};
-use std::cell::Cell;
+mod anchor_map;
+use anchor_map::{Anchor, AnchorMap, Id};
+
+mod selection;
+mod motion;
+mod text_object;
+mod block;
+pub use block::ShortLines;
+mod copy_paste;
+mod command;
+pub use command::Command;
+mod indent;
+mod autopair;
+mod lines;
+mod reflow;
+pub use reflow::char_width;
+mod fold;
+pub use fold::{fold_at, FoldKind};
+
+pub type CursorId = Id<Cursor>;
+pub type MarkId = Id<Mark>;
 
 #[derive(Default)]
 pub struct EditingBuffer {
     buffer: Vec<u8>,
     span_table: SpanTable,
-    // TODO: sort by starting pos? what about same starting pos but different ending pos
     // the buffer needs to hold all marks inside of it so that it can apply offsets
-    // TODO: what about overlapping Cursors
-    // TODO: probably rework this later
-    // https://github.com/xi-editor/xi-editor/blob/master/rust/core-lib/src/selection.rs
-    cursors: Vec<Weak<Cell<Cursor>>>,
+    // sorted by (start, end)
+    // overlapping or touching cursors are merged after every command, see selection.rs
+    cursors: AnchorMap<Cursor>,
+    // sorted by (pos, gravity)
+    marks: AnchorMap<Mark>,
+    // set while a command is running so every edit it makes lands in one transaction
+    in_transaction: bool,
+    // the last paste, which yank_pop can replace
+    yank: Option<copy_paste::Yank>,
+    // used by auto indent, tab and backspace
+    indent: indent::IndentStyle,
+    pairs: autopair::PairRules,
+    // marks on closing characters inserted by auto pairing, which typing steps over
+    autopaired: Vec<(MarkId, u8)>,
+    comment: lines::CommentToken,
+    folds: Vec<fold::Fold>,
+    // how edits treat lines shorter than a block
+    short_lines: block::ShortLines,
+    // edits not yet seen by the layout, see take_edits
+    edits: Vec<EditDelta>,
 }
 
 // Don't let users outside the crate copy it
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Cursor {
     // byte positions
+    // start is the anchor and end is the end that moves when the selection is extended
     pub start: usize,
     pub end: usize,
-    // saved horizontal pos
+    // saved horizontal pos of end in pixels, kept while moving vertically so that
+    // passing through short lines returns to the same column
+    pub horizontal: Option<f32>,
+    // set for a rectangular selection, the horizontal pos of start in pixels.
+    // The rectangle spans the lines from start to end, between this and the horizontal pos of end.
+    pub block: Option<f32>,
 }
 
-impl EditingBuffer {
-    fn new_cursor(&mut self) -> Rc<Cell<Cursor>> {
-        let cursor = Cursor {
-            start: 0,
-            end: 0,
+// start..end of the contents was replaced with inserted bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EditDelta {
+    pub start: usize,
+    pub end: usize,
+    pub inserted: usize,
+}
+
+// Which side of an insertion made exactly at a mark the mark ends up on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gravity {
+    // stays before the inserted text
+    Left,
+    // moves after the inserted text
+    Right,
+}
+
+// A single tracked position, used for bookmarks, diagnostics, snippet tab-stops, etc
+#[derive(Clone, Copy, Debug)]
+pub struct Mark {
+    // byte position
+    pub pos: usize,
+    pub gravity: Gravity,
+}
+
+// Where pos ends up after start..end is replaced with inserted bytes.
+// Positions touching or inside the replaced range collapse onto it, then gravity picks a side.
+fn adjust_pos(pos: usize, start: usize, end: usize, inserted: usize, gravity: Gravity) -> usize {
+    if pos < start {
+        pos
+    } else if pos > end {
+        pos - (end - start) + inserted
+    } else {
+        match gravity {
+            Gravity::Left => start,
+            Gravity::Right => start + inserted,
+        }
+    }
+}
+
+impl Cursor {
+    pub fn new(start: usize, end: usize) -> Self {
+        Cursor {start, end, horizontal: None, block: None}
+    }
+
+    pub fn caret(pos: usize) -> Self {
+        Cursor::new(pos, pos)
+    }
+
+    pub fn is_caret(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start.min(self.end) .. self.start.max(self.end)
+    }
+}
+
+// text as a str with the same byte offsets, every invalid byte becomes a '?'
+pub fn lossy_str(text: &[u8]) -> Cow<'_, str> {
+    if let Ok(text) = std::str::from_utf8(text) {
+        return Cow::Borrowed(text)
+    }
+    let mut lossy = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Err(err) = std::str::from_utf8(rest) {
+        let (valid, invalid) = rest.split_at(err.valid_up_to());
+        lossy.push_str(std::str::from_utf8(valid).unwrap());
+        let bad = err.error_len().unwrap_or(invalid.len());
+        lossy.push_str(&"?".repeat(bad));
+        rest = &invalid[bad..];
+    }
+    lossy.push_str(std::str::from_utf8(rest).unwrap());
+    Cow::Owned(lossy)
+}
+
+// byte offset of the start of the line containing pos
+pub fn line_start(text: &[u8], pos: usize) -> usize {
+    text[..pos].iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1)
+}
+
+// byte offset of the newline ending the line containing pos, or the end of the text
+pub fn line_end(text: &[u8], pos: usize) -> usize {
+    text[pos..].iter().position(|b| *b == b'\n').map_or(text.len(), |i| pos + i)
+}
+
+impl Anchor for Cursor {
+    fn key(&self) -> (usize, usize) {
+        (self.start.min(self.end), self.start.max(self.end))
+    }
+
+    fn reach(&self) -> usize {
+        self.start.max(self.end)
+    }
+
+    fn adjust(&mut self, start: usize, end: usize, inserted: usize) {
+        // a selection keeps text inserted at either of its ends, a caret ends up after it
+        let gravity = |pos: usize, other: usize| {
+            if pos < other && start == end {Gravity::Left} else {Gravity::Right}
         };
+        let moved = Cursor::new(
+            adjust_pos(self.start, start, end, inserted, gravity(self.start, self.end)),
+            adjust_pos(self.end, start, end, inserted, gravity(self.end, self.start)),
+        );
+        // an edit before the cursor on its line would leave the saved position stale,
+        // a block keeps its columns since they are not tied to the text
+        if (moved.start, moved.end) != (self.start, self.end) {
+            *self = Cursor {block: self.block, ..moved};
+        }
+    }
+
+    fn shift(&mut self, delta: isize) {
+        let shift = |pos: usize| (pos as isize + delta) as usize;
+        *self = Cursor {block: self.block, ..Cursor::new(shift(self.start), shift(self.end))};
+    }
+}
+
+impl Anchor for Mark {
+    fn key(&self) -> (usize, usize) {
+        (self.pos, self.gravity as usize)
+    }
+
+    fn reach(&self) -> usize {
+        self.pos
+    }
+
+    fn adjust(&mut self, start: usize, end: usize, inserted: usize) {
+        self.pos = adjust_pos(self.pos, start, end, inserted, self.gravity);
+    }
+
+    fn shift(&mut self, delta: isize) {
+        self.pos = (self.pos as isize + delta) as usize;
+    }
+}
+
+impl EditingBuffer {
+    // buffer holding content, loading it is not an edit
+    pub fn from_bytes(content: &[u8]) -> Self {
+        let mut eb = EditingBuffer::default();
+        eb.buffer.extend(content);
+        eb.span_table.insert(Span {start: 0, end: content.len()}, 0);
+        if let Some(style) = indent::IndentStyle::detect(&eb.contents()) {
+            eb.indent = style;
+        }
+        eb
+    }
+
+    // buffer holding the file at path, with the auto pairing and comment rules of its extension
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut eb = EditingBuffer::from_bytes(&fs::read(path)?);
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        eb.pairs = autopair::PairRules::for_extension(extension);
+        eb.comment = lines::CommentToken::for_extension(extension);
+        Ok(eb)
+    }
+
+    pub fn new_cursor(&mut self) -> CursorId {
+        self.cursors.insert(Cursor::caret(0))
+    }
+
+    // panics if the cursor was removed
+    fn cursor(&self, id: CursorId) -> Cursor {
+        self.cursors.get(id).expect("use of removed cursor")
+    }
+
+    fn set_cursor(&mut self, id: CursorId, cursor: Cursor) {
+        let valid = self.cursors.set(id, cursor);
+        assert!(valid, "use of removed cursor");
+    }
+
+    fn remove_cursor(&mut self, id: CursorId) {
+        self.cursors.remove(id);
+    }
+
+    // cursors in position order
+    fn cursors(&self) -> impl Iterator<Item = (CursorId, Cursor)> + '_ {
+        self.cursors.iter()
+    }
+
+    fn new_mark(&mut self, pos: usize, gravity: Gravity) -> MarkId {
+        self.marks.insert(Mark {pos, gravity})
+    }
+
+    // panics if the mark was removed
+    fn mark(&self, id: MarkId) -> Mark {
+        self.marks.get(id).expect("use of removed mark")
+    }
+
+    fn remove_mark(&mut self, id: MarkId) {
+        self.marks.remove(id);
+    }
+
+    // marks with a position inside of range, in position order
+    fn marks_in(&self, range: Range<usize>) -> impl Iterator<Item = (MarkId, Mark)> + '_ {
+        self.marks.range(range)
+    }
+
+    pub fn len(&self) -> usize {
+        self.span_table.len()
+    }
 
-        let cursor = Rc::new(Cell::new(cursor));
-        self.cursors.push(Rc::downgrade(&cursor));
-        cursor
+    pub fn contents(&self) -> Vec<u8> {
+        self.span_table.contents(&self.buffer)
     }
 
     // All modifications go through the set operation.
     // Insertion: Cursor with no selection set to "some text"
     // Deletion: Cursor with selection set to ""
-    fn set(&mut self, cursor: &Rc<Cell<Cursor>>, content: &[u8]) {
-        let cursor_ref = Rc::clone(cursor);
-        let mut cursor = (*cursor_ref).get();
-        cursor.start = 5;
-        (*cursor_ref).set(cursor);
-        // we only handle carets right now
-        //if (cursor.start != cursor.end) {panic!()}
-        //let pos = self.span_table.byte_offset(cursor.start);
+    fn set(&mut self, id: CursorId, content: &[u8]) {
+        let range = self.cursor(id).range();
+        self.transaction(|eb| {
+            eb.replace(range.start, range.end, content);
+            // leave the edited cursor as a caret after the new content
+            eb.set_cursor(id, Cursor::caret(range.start + content.len()));
+        });
+    }
+
+    // run f as a single transaction, nested calls join the outer transaction
+    fn transaction<F: FnOnce(&mut Self)>(&mut self, f: F) {
+        if self.in_transaction {
+            return f(self)
+        }
+        self.span_table.start_transaction();
+        self.in_transaction = true;
+        f(self);
+        self.in_transaction = false;
+        self.merge_cursors();
+    }
+
+    fn replace(&mut self, start: usize, end: usize, content: &[u8]) {
+        self.span_table.delete(start, end);
+        let span = Span {
+            start: self.buffer.len(),
+            end: self.buffer.len() + content.len()
+        };
+        self.buffer.extend(content);
+        self.span_table.insert(span, start);
+        self.cursors.adjust(start, end, content.len());
+        self.marks.adjust(start, end, content.len());
+        self.edits.push(EditDelta {start, end, inserted: content.len()});
+    }
+
+    // Every edit since the last call, in the order they were made.
+    // Whatever caches positions in the contents, like the wrap layout, should take them after every command.
+    pub fn take_edits(&mut self) -> Vec<EditDelta> {
+        std::mem::take(&mut self.edits)
     }
 }
 
@@ -55,21 +323,99 @@ impl EditingBuffer {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("editor-test-{}.py", std::process::id()));
+        fs::write(&path, "if a:\n  b\n").unwrap();
+        let eb = EditingBuffer::load(&path);
+        fs::remove_file(&path).unwrap();
+        let eb = eb.unwrap();
+        assert_eq!(eb.indent, indent::IndentStyle::Spaces(2));
+        assert_eq!(eb.comment, lines::CommentToken(b"#".to_vec()));
+        assert_eq!(eb.pairs, autopair::PairRules::default());
+        assert!(EditingBuffer::load(&path).is_err());
+    }
+
     #[test]
     fn test_set() {
         let mut eb = EditingBuffer::default();
         let c = eb.new_cursor();
-        let mut c2 = c.get();
-        c2.end = 8;
-        c.set(c2);
+        eb.set(c, "hello world".as_bytes());
+        assert_eq!(eb.cursor(c).start, 11);
+
+        let mut c2 = eb.cursor(c);
+        c2.start = 0;
+        c2.end = 5;
+        eb.set_cursor(c, c2);
 
         let content = "test".as_bytes();
-        println!("{:?}", c);
-        eb.set(&c, content);
-        println!("{:?}", c);
-        assert_eq!(c.get().start, 5);
-        
+        eb.set(c, content);
+        assert_eq!(eb.contents(), "test world".as_bytes());
+        assert_eq!(eb.cursor(c).start, 4);
+        assert_eq!(eb.cursor(c).end, 4);
+    }
+
+    #[test]
+    fn test_mark_gravity() {
+        let mut eb = EditingBuffer::default();
+        let c = eb.new_cursor();
+        eb.set(c, "ab".as_bytes());
+
+        let left = eb.new_mark(1, Gravity::Left);
+        let right = eb.new_mark(1, Gravity::Right);
+        let after = eb.new_mark(2, Gravity::Left);
+
+        eb.set_cursor(c, Cursor::new(1, 1));
+        eb.set(c, "xyz".as_bytes());
+        assert_eq!(eb.contents(), "axyzb".as_bytes());
+        assert_eq!(eb.mark(left).pos, 1);
+        assert_eq!(eb.mark(right).pos, 4);
+        assert_eq!(eb.mark(after).pos, 5);
 
-        assert_eq!(c.get().end, 8);
+        // deleting over a mark collapses it to the start of the deletion
+        eb.set_cursor(c, Cursor::new(3, 5));
+        eb.set(c, &[]);
+        assert_eq!(eb.mark(right).pos, 3);
+        assert_eq!(eb.mark(after).pos, 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_marks_in() {
+        let mut eb = EditingBuffer::default();
+        let c = eb.new_cursor();
+        eb.set(c, "0123456789".as_bytes());
+
+        let a = eb.new_mark(7, Gravity::Left);
+        let b = eb.new_mark(2, Gravity::Right);
+        let removed = eb.new_mark(3, Gravity::Left);
+        eb.remove_mark(removed);
+        eb.new_mark(9, Gravity::Left);
+
+        let found: Vec<usize> = eb.marks_in(0..8).map(|(_, m)| m.pos).collect();
+        assert_eq!(found, vec![2, 7]);
+        assert_eq!(eb.marks_in(7..8).next().unwrap().0, a);
+        assert_eq!(eb.marks_in(2..3).next().unwrap().0, b);
+    }
+
+    #[test]
+    fn test_cursor_order() {
+        fn assert_send<T: Send>() {}
+        assert_send::<EditingBuffer>();
+
+        let mut eb = EditingBuffer::default();
+        let a = eb.new_cursor();
+        eb.set(a, "one two three".as_bytes());
+        let b = eb.new_cursor();
+        eb.set_cursor(b, Cursor::new(4, 7));
+        let c = eb.new_cursor();
+        eb.remove_cursor(c);
+
+        let order: Vec<CursorId> = eb.cursors().map(|(id, _)| id).collect();
+        assert_eq!(order, vec![b, a]);
+
+        eb.set(b, "2".as_bytes());
+        assert_eq!(eb.contents(), "one 2 three".as_bytes());
+        assert_eq!(eb.cursor(a).start, 11);
+        assert_eq!(eb.cursor(b).start, 5);
+    }
+}
@@ -0,0 +1,684 @@
+// Cursor motions over the buffer contents
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use swash::text::{ClusterBreak, Codepoint};
+
+use crate::layout::{LineShaper, WrapLayout};
+use crate::text_renderer::{Advance, line_x, line_index};
+
+use super::{Cursor, CursorId, EditingBuffer, line_start, line_end, lossy_str};
+use super::fold::{fold_at, skip_fold};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motion {
+    GraphemeLeft,
+    GraphemeRight,
+    WordLeft,
+    WordRight,
+    // parts of camelCase and snake_case words
+    SubwordLeft,
+    SubwordRight,
+    LineStart,
+    LineEnd,
+    // first non-blank character of the line, or the line start if already there
+    SmartHome,
+    ParagraphUp,
+    ParagraphDown,
+    DocumentStart,
+    DocumentEnd,
+    MatchingBracket,
+}
+
+impl Motion {
+    // where a cursor end at pos moves to
+    pub fn apply(self, text: &[u8], pos: usize) -> usize {
+        match self {
+            Motion::GraphemeLeft => prev_grapheme(text, pos),
+            Motion::GraphemeRight => next_grapheme(text, pos),
+            Motion::WordLeft => word_left(text, pos),
+            Motion::WordRight => word_right(text, pos),
+            Motion::SubwordLeft => subword_left(text, pos),
+            Motion::SubwordRight => subword_right(text, pos),
+            Motion::LineStart => line_start(text, pos),
+            Motion::LineEnd => line_end(text, pos),
+            Motion::SmartHome => smart_home(text, pos),
+            Motion::ParagraphUp => paragraph_up(text, pos),
+            Motion::ParagraphDown => paragraph_down(text, pos),
+            Motion::DocumentStart => 0,
+            Motion::DocumentEnd => text.len(),
+            Motion::MatchingBracket => matching_bracket(text, pos).unwrap_or(pos),
+        }
+    }
+}
+
+// character starting at pos and the position after it, invalid bytes decode to U+FFFD one at a time
+pub fn char_at(text: &[u8], pos: usize) -> Option<(char, usize)> {
+    if pos >= text.len() {
+        return None
+    }
+    let end = (pos + 4).min(text.len());
+    let valid = match std::str::from_utf8(&text[pos..end]) {
+        Ok(valid) => valid,
+        Err(e) => std::str::from_utf8(&text[pos..pos + e.valid_up_to()]).unwrap()
+    };
+    match valid.chars().next() {
+        Some(ch) => Some((ch, pos + ch.len_utf8())),
+        None => Some(('\u{fffd}', pos + 1))
+    }
+}
+
+// character ending at pos and the position it starts at
+pub fn char_before(text: &[u8], pos: usize) -> Option<(char, usize)> {
+    if pos == 0 {
+        return None
+    }
+    let mut start = pos - 1;
+    while start > 0 && pos - start < 4 && text[start] & 0xc0 == 0x80 {
+        start -= 1;
+    }
+    match char_at(text, start) {
+        Some((ch, end)) if end == pos => Some((ch, start)),
+        _ => Some(('\u{fffd}', pos - 1))
+    }
+}
+
+// Grapheme cluster boundary rules from UAX #29, true if there is no boundary between prev and next.
+// ri_run is the number of regional indicators ending at prev.
+fn joins(prev: char, next: char, ri_run: usize) -> bool {
+    use ClusterBreak::*;
+    match (prev.cluster_break(), next.cluster_break()) {
+        (CR, LF) => true,
+        (CN, _) | (CR, _) | (LF, _) | (_, CN) | (_, CR) | (_, LF) => false,
+        (L, L) | (L, V) | (L, LV) | (L, LVT) => true,
+        (LV, V) | (LV, T) | (V, V) | (V, T) => true,
+        (LVT, T) | (T, T) => true,
+        (_, EX) | (_, ZWJ) | (_, SM) | (PP, _) => true,
+        (ZWJ, _) => next.is_extended_pictographic(),
+        (RI, RI) => ri_run % 2 == 1,
+        _ => false,
+    }
+}
+
+pub fn next_grapheme(text: &[u8], pos: usize) -> usize {
+    let (mut prev, mut end) = match char_at(text, pos) {
+        Some(first) => first,
+        None => return text.len()
+    };
+    let mut ri_run = (prev.cluster_break() == ClusterBreak::RI) as usize;
+    while let Some((ch, next)) = char_at(text, end) {
+        if !joins(prev, ch, ri_run) {
+            break
+        }
+        ri_run = if ch.cluster_break() == ClusterBreak::RI {ri_run + 1} else {0};
+        prev = ch;
+        end = next;
+    }
+    end
+}
+
+pub fn prev_grapheme(text: &[u8], pos: usize) -> usize {
+    if pos == 0 {
+        return 0
+    }
+    // graphemes never span lines, so walk forward from the start of the line
+    let mut boundary = line_start(text, pos - 1);
+    loop {
+        let next = next_grapheme(text, boundary);
+        if next >= pos {
+            return boundary
+        }
+        boundary = next;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CharClass {
+    Space,
+    Word,
+    Punct,
+}
+
+pub fn char_class(ch: char) -> CharClass {
+    if ch.is_whitespace() {
+        CharClass::Space
+    } else if ch.is_alphanumeric() || ch == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punct
+    }
+}
+
+// skip spaces forward without leaving the line
+fn skip_spaces(text: &[u8], mut pos: usize, skip_underscore: bool) -> usize {
+    while let Some((ch, next)) = char_at(text, pos) {
+        if ch == '\n' || !(char_class(ch) == CharClass::Space || (skip_underscore && ch == '_')) {
+            break
+        }
+        pos = next;
+    }
+    pos
+}
+
+fn skip_spaces_back(text: &[u8], mut pos: usize, skip_underscore: bool) -> usize {
+    while let Some((ch, prev)) = char_before(text, pos) {
+        if ch == '\n' || !(char_class(ch) == CharClass::Space || (skip_underscore && ch == '_')) {
+            break
+        }
+        pos = prev;
+    }
+    pos
+}
+
+// skip characters forward while f holds
+pub fn skip_while<F: Fn(char) -> bool>(text: &[u8], mut pos: usize, f: F) -> usize {
+    while let Some((ch, next)) = char_at(text, pos) {
+        if !f(ch) {
+            break
+        }
+        pos = next;
+    }
+    pos
+}
+
+pub fn skip_back_while<F: Fn(char) -> bool>(text: &[u8], mut pos: usize, f: F) -> usize {
+    while let Some((ch, prev)) = char_before(text, pos) {
+        if !f(ch) {
+            break
+        }
+        pos = prev;
+    }
+    pos
+}
+
+// end of the next word or run of punctuation, line ends are a stop of their own
+pub fn word_right(text: &[u8], pos: usize) -> usize {
+    if text.get(pos) == Some(&b'\n') {
+        return pos + 1
+    }
+    let pos = skip_spaces(text, pos, false);
+    match char_at(text, pos) {
+        Some((ch, _)) if ch != '\n' => {
+            let class = char_class(ch);
+            skip_while(text, pos, |ch| char_class(ch) == class)
+        },
+        _ => pos
+    }
+}
+
+pub fn word_left(text: &[u8], pos: usize) -> usize {
+    if pos > 0 && text[pos - 1] == b'\n' {
+        return pos - 1
+    }
+    let pos = skip_spaces_back(text, pos, false);
+    match char_before(text, pos) {
+        Some((ch, _)) if ch != '\n' => {
+            let class = char_class(ch);
+            skip_back_while(text, pos, |ch| char_class(ch) == class)
+        },
+        _ => pos
+    }
+}
+
+fn is_subword(ch: char) -> bool {
+    char_class(ch) == CharClass::Word && ch != '_'
+}
+
+fn is_lower(ch: char) -> bool {
+    is_subword(ch) && !ch.is_uppercase()
+}
+
+// end of the next part of a word, "HTTPServer_name" has the parts HTTP, Server and name
+pub fn subword_right(text: &[u8], pos: usize) -> usize {
+    if text.get(pos) == Some(&b'\n') {
+        return pos + 1
+    }
+    let pos = skip_spaces(text, pos, true);
+    let (ch, next) = match char_at(text, pos) {
+        Some((ch, next)) if ch != '\n' => (ch, next),
+        _ => return pos
+    };
+
+    if !is_subword(ch) {
+        return skip_while(text, pos, |ch| char_class(ch) == CharClass::Punct)
+    }
+    if !ch.is_uppercase() {
+        return skip_while(text, pos, is_lower)
+    }
+    match char_at(text, next) {
+        // Capitalized
+        Some((ch, _)) if is_lower(ch) => skip_while(text, next, is_lower),
+        // UPPERCASE, stopping before the capital of a following Capitalized part
+        _ => {
+            let mut pos = next;
+            while let Some((ch, after)) = char_at(text, pos) {
+                let capitalized = matches!(char_at(text, after), Some((ch, _)) if is_lower(ch));
+                if !ch.is_uppercase() || capitalized {
+                    break
+                }
+                pos = after;
+            }
+            pos
+        }
+    }
+}
+
+pub fn subword_left(text: &[u8], pos: usize) -> usize {
+    if pos > 0 && text[pos - 1] == b'\n' {
+        return pos - 1
+    }
+    let pos = skip_spaces_back(text, pos, true);
+    let ch = match char_before(text, pos) {
+        Some((ch, _)) if ch != '\n' => ch,
+        _ => return pos
+    };
+
+    if !is_subword(ch) {
+        return skip_back_while(text, pos, |ch| char_class(ch) == CharClass::Punct)
+    }
+    if ch.is_uppercase() {
+        return skip_back_while(text, pos, |ch| is_subword(ch) && ch.is_uppercase())
+    }
+    let pos = skip_back_while(text, pos, is_lower);
+    match char_before(text, pos) {
+        Some((ch, prev)) if ch.is_uppercase() => prev,
+        _ => pos
+    }
+}
+
+pub fn smart_home(text: &[u8], pos: usize) -> usize {
+    let start = line_start(text, pos);
+    let first = start + text[start..line_end(text, start)].iter()
+        .take_while(|b| **b == b' ' || **b == b'\t')
+        .count();
+    if pos == first {start} else {first}
+}
+
+pub fn is_blank_line(text: &[u8], start: usize) -> bool {
+    text[start..line_end(text, start)].iter().all(|b| b.is_ascii_whitespace())
+}
+
+// start of the first blank line after the paragraph at or after pos
+pub fn paragraph_down(text: &[u8], pos: usize) -> usize {
+    let mut start = line_start(text, pos);
+    let mut seen_text = !is_blank_line(text, start);
+    loop {
+        let end = line_end(text, start);
+        if end == text.len() {
+            return text.len()
+        }
+        start = end + 1;
+        if !is_blank_line(text, start) {
+            seen_text = true;
+        } else if seen_text {
+            return start
+        }
+    }
+}
+
+// start of the first blank line before the paragraph at or before pos
+pub fn paragraph_up(text: &[u8], pos: usize) -> usize {
+    let mut start = line_start(text, pos);
+    let mut seen_text = !is_blank_line(text, start) && start != pos;
+    loop {
+        if start == 0 {
+            return 0
+        }
+        start = line_start(text, start - 1);
+        if !is_blank_line(text, start) {
+            seen_text = true;
+        } else if seen_text {
+            return start
+        }
+    }
+}
+
+pub const BRACKETS: [(u8, u8); 3] = [(b'(', b')'), (b'[', b']'), (b'{', b'}')];
+
+// position of the bracket matching the one at pos, or the one just before pos
+pub fn matching_bracket(text: &[u8], pos: usize) -> Option<usize> {
+    let candidates = [Some(pos), pos.checked_sub(1)];
+    for at in candidates.iter().flatten().filter(|at| **at < text.len()) {
+        for (open, close) in BRACKETS.iter() {
+            if text[*at] == *open {
+                return find_close(text, *at + 1, *open, *close)
+            }
+            if text[*at] == *close {
+                return find_open(text, *at, *open, *close)
+            }
+        }
+    }
+    None
+}
+
+// position of the close bracket ending the group that starts at from
+pub fn find_close(text: &[u8], from: usize, open: u8, close: u8) -> Option<usize> {
+    let mut depth = 0;
+    for (i, b) in text[from..].iter().enumerate() {
+        if *b == open {
+            depth += 1;
+        } else if *b == close {
+            if depth == 0 {
+                return Some(from + i)
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// position of the open bracket starting the group that ends at to
+pub fn find_open(text: &[u8], to: usize, open: u8, close: u8) -> Option<usize> {
+    let mut depth = 0;
+    for (i, b) in text[..to].iter().enumerate().rev() {
+        if *b == close {
+            depth += 1;
+        } else if *b == open {
+            if depth == 0 {
+                return Some(i)
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// the line starting at start, with the byte offsets of text
+pub fn line_str(text: &[u8], start: usize) -> Cow<'_, str> {
+    lossy_str(&text[start..line_end(text, start)])
+}
+
+// Start of the line lines away from the line starting at start, None if that is past the first or last line.
+// A folded line and the lines it hides count as one line.
+fn nth_line(text: &[u8], start: usize, lines: isize, folds: &[Range<usize>]) -> Option<usize> {
+    let mut start = start;
+    for _ in 0..lines.abs() {
+        if lines < 0 {
+            if start == 0 {
+                return None
+            }
+            start = line_start(text, start - 1);
+            if let Some(fold) = fold_at(folds, start) {
+                start = line_start(text, fold.start);
+            }
+        } else {
+            let end = line_end(text, start);
+            if end == text.len() {
+                return None
+            }
+            start = end + 1;
+            if let Some(fold) = fold_at(folds, start) {
+                if fold.end == text.len() {
+                    return None
+                }
+                start = fold.end + 1;
+            }
+        }
+    }
+    Some(start)
+}
+
+impl EditingBuffer {
+    // Move the end of every cursor, when extending the start stays where it is.
+    // Without extending, a selection collapses to its side before moving by grapheme.
+    // A cursor that would end up in a fold skips over it in the direction it moved.
+    pub fn move_cursors(&mut self, motion: Motion, extend: bool) {
+        let text = self.contents();
+        let folds = self.folds();
+        let moved: Vec<(CursorId, Cursor)> = self.cursors().map(|(id, cursor)| {
+            let head = match motion {
+                Motion::GraphemeLeft if !extend && !cursor.is_caret() => cursor.range().start,
+                Motion::GraphemeRight if !extend && !cursor.is_caret() => cursor.range().end,
+                _ => motion.apply(&text, cursor.end)
+            };
+            let head = skip_fold(&folds, text.len(), head, head > cursor.end);
+            let anchor = if extend {cursor.start} else {head};
+            let block = if extend {cursor.block} else {None};
+            (id, Cursor {block, ..Cursor::new(anchor, head)})
+        }).collect();
+
+        for (id, cursor) in moved {
+            self.set_cursor(id, cursor);
+        }
+        self.merge_cursors();
+    }
+
+    // Move every cursor up (negative lines) or down, keeping its saved horizontal pos.
+    // Moving past the first or last line goes to the start or end of the buffer.
+    pub fn move_vertical(&mut self, advance: &impl Advance, lines: isize, extend: bool) {
+        let text = self.contents();
+        let folds = self.folds();
+        let moved: Vec<(CursorId, Cursor)> = self.cursors().map(|(id, cursor)| {
+            let start = line_start(&text, cursor.end);
+            let x = cursor.horizontal.unwrap_or_else(|| {
+                let line = line_str(&text, start);
+                line_x(advance, &line, (cursor.end - start).min(line.len()))
+            });
+
+            let head = match nth_line(&text, start, lines, &folds) {
+                Some(target) => target + line_index(advance, &line_str(&text, target), x),
+                None if lines < 0 => 0,
+                None => text.len()
+            };
+            let head = skip_fold(&folds, text.len(), head, lines > 0);
+            let anchor = if extend {cursor.start} else {head};
+            let block = if extend {cursor.block} else {None};
+            (id, Cursor {start: anchor, end: head, horizontal: Some(x), block})
+        }).collect();
+
+        for (id, cursor) in moved {
+            self.set_cursor(id, cursor);
+        }
+        self.merge_cursors();
+    }
+
+    // bring the layout up to date with every edit made since the last call
+    pub fn sync_layout(&mut self, layout: &mut WrapLayout, shaper: &mut impl LineShaper) {
+        layout.apply_edits(&self.take_edits());
+        layout.set_folds(self.folds());
+        if layout.needs_update() {
+            layout.update(&self.contents(), shaper);
+        }
+    }
+
+    // Move every cursor by rows of a soft wrapped layout, which has to be up to date with the contents.
+    // Like move_vertical the horizontal pos is kept, measured from the left edge of the row.
+    pub fn move_visual(&mut self, layout: &WrapLayout, rows: isize, extend: bool) {
+        let len = self.len();
+        let moved: Vec<(CursorId, Cursor)> = self.cursors().map(|(id, cursor)| {
+            let (row, x) = layout.position(cursor.end);
+            let x = cursor.horizontal.unwrap_or(x);
+            let target = row as isize + rows;
+            let head = if target < 0 {
+                0
+            } else if target as usize >= layout.rows() {
+                len
+            } else {
+                layout.pos_at(target as usize, x)
+            };
+            let anchor = if extend {cursor.start} else {head};
+            let block = if extend {cursor.block} else {None};
+            (id, Cursor {start: anchor, end: head, horizontal: Some(x), block})
+        }).collect();
+
+        for (id, cursor) in moved {
+            self.set_cursor(id, cursor);
+        }
+        self.merge_cursors();
+    }
+
+    pub fn move_up(&mut self, advance: &impl Advance, extend: bool) {
+        self.move_vertical(advance, -1, extend);
+    }
+
+    pub fn move_down(&mut self, advance: &impl Advance, extend: bool) {
+        self.move_vertical(advance, 1, extend);
+    }
+
+    pub fn page_up(&mut self, advance: &impl Advance, page_lines: usize, extend: bool) {
+        self.move_vertical(advance, -(page_lines as isize), extend);
+    }
+
+    pub fn page_down(&mut self, advance: &impl Advance, page_lines: usize, extend: bool) {
+        self.move_vertical(advance, page_lines as isize, extend);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // proportional font where i is narrow and CJK is double width
+    pub struct TestAdvance;
+
+    impl Advance for TestAdvance {
+        fn advance(&self, ch: char) -> f32 {
+            match ch {
+                'i' => 4.,
+                '\u{1100}'..='\u{ffff}' => 20.,
+                _ => 10.,
+            }
+        }
+    }
+
+    fn head(eb: &EditingBuffer) -> usize {
+        eb.cursors().next().unwrap().1.end
+    }
+
+    // every stop of motion starting at pos
+    fn stops(text: &str, motion: Motion, mut pos: usize) -> Vec<usize> {
+        let mut stops = Vec::new();
+        loop {
+            let next = motion.apply(text.as_bytes(), pos);
+            if next == pos {
+                return stops
+            }
+            stops.push(next);
+            pos = next;
+        }
+    }
+
+    #[test]
+    fn test_graphemes() {
+        let text = "e\u{301}\r\n🇩🇪🇫🇷👩\u{200d}💻각";
+        assert_eq!(stops(text, Motion::GraphemeRight, 0), vec![3, 5, 13, 21, 32, 35]);
+        assert_eq!(stops(text, Motion::GraphemeLeft, text.len()), vec![32, 21, 13, 5, 3, 0]);
+    }
+
+    #[test]
+    fn test_words() {
+        let text = "let fooBar = HTTPServer_name;\n  x";
+        assert_eq!(stops(text, Motion::WordRight, 0), vec![3, 10, 12, 28, 29, 30, 33]);
+        assert_eq!(stops(text, Motion::WordLeft, 29), vec![28, 13, 11, 4, 0]);
+        assert_eq!(stops(text, Motion::SubwordRight, 4), vec![7, 10, 12, 17, 23, 28, 29, 30, 33]);
+        assert_eq!(stops(text, Motion::SubwordLeft, 28), vec![24, 17, 13, 11, 7, 4, 0]);
+    }
+
+    #[test]
+    fn test_lines_and_paragraphs() {
+        let text = "  one\ntwo\n\nthree\n\n\nfour";
+        assert_eq!(Motion::SmartHome.apply(text.as_bytes(), 4), 2);
+        assert_eq!(Motion::SmartHome.apply(text.as_bytes(), 2), 0);
+        assert_eq!(Motion::SmartHome.apply(text.as_bytes(), 0), 2);
+        assert_eq!(Motion::LineEnd.apply(text.as_bytes(), 7), 9);
+        assert_eq!(stops(text, Motion::ParagraphDown, 3), vec![10, 17, 23]);
+        assert_eq!(stops(text, Motion::ParagraphUp, 23), vec![18, 10, 0]);
+    }
+
+    #[test]
+    fn test_matching_bracket() {
+        let text = "f(a[0], {b}) ";
+        assert_eq!(Motion::MatchingBracket.apply(text.as_bytes(), 1), 11);
+        assert_eq!(Motion::MatchingBracket.apply(text.as_bytes(), 11), 1);
+        // the bracket just before the cursor
+        assert_eq!(Motion::MatchingBracket.apply(text.as_bytes(), 12), 1);
+        assert_eq!(Motion::MatchingBracket.apply(text.as_bytes(), 8), 10);
+        assert_eq!(Motion::MatchingBracket.apply(text.as_bytes(), 0), 0);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut eb = EditingBuffer::from_bytes("one two three".as_bytes());
+        eb.add_cursor(Cursor::caret(4));
+        eb.move_cursors(Motion::WordRight, true);
+        eb.move_cursors(Motion::WordRight, true);
+        let cursor = eb.cursors().next().unwrap().1;
+        assert_eq!((cursor.start, cursor.end), (4, 13));
+
+        eb.move_cursors(Motion::GraphemeLeft, false);
+        assert_eq!(head(&eb), 4);
+        eb.move_cursors(Motion::DocumentEnd, false);
+        assert_eq!(head(&eb), 13);
+    }
+
+    #[test]
+    fn test_sticky_column() {
+        let mut eb = EditingBuffer::from_bytes("hello world\nhi\n漢字漢字漢字\n\tab".as_bytes());
+        eb.add_cursor(Cursor::caret(8));
+
+        eb.move_down(&TestAdvance, false);
+        assert_eq!(head(&eb), 14);
+        // 80px is 4 wide characters in
+        eb.move_down(&TestAdvance, false);
+        assert_eq!(head(&eb), 15 + 12);
+        // the tab takes 40px, so 80px lands between a and b
+        eb.move_down(&TestAdvance, false);
+        assert_eq!(head(&eb), 34 + 3);
+
+        eb.page_up(&TestAdvance, 3, false);
+        assert_eq!(head(&eb), 8);
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        // every invalid byte takes a column, so positions after them still index the line
+        let mut eb = EditingBuffer::from_bytes(b"a\xff\xfeb\nabcd\n\xe6\xbc");
+        eb.add_cursor(Cursor::caret(3));
+        eb.move_down(&TestAdvance, false);
+        assert_eq!(head(&eb), 8);
+        eb.move_down(&TestAdvance, false);
+        assert_eq!(head(&eb), 12);
+        eb.move_up(&TestAdvance, false);
+        assert_eq!(head(&eb), 8);
+        assert_eq!(line_str(b"a\xff\xfeb", 0), "a??b");
+    }
+
+    #[test]
+    fn test_visual_rows() {
+        let text = "one two three
+four";
+        let mut eb = EditingBuffer::from_bytes(text.as_bytes());
+        eb.add_cursor(Cursor::caret(1));
+        let mut layout = WrapLayout::new(80., false);
+        layout.update(&eb.contents(), &mut TestAdvance);
+
+        // rows are "one two ", "three" and "four"
+        eb.move_visual(&layout, 1, false);
+        assert_eq!(head(&eb), 9);
+        eb.move_visual(&layout, 1, false);
+        assert_eq!(head(&eb), 15);
+        eb.move_visual(&layout, -2, true);
+        let cursor = eb.cursors().next().unwrap().1;
+        assert_eq!((cursor.start, cursor.end), (15, 1));
+    }
+
+    #[test]
+    fn test_proportional() {
+        let mut eb = EditingBuffer::from_bytes("iiiiiiiiii\nabcdef".as_bytes());
+        eb.add_cursor(Cursor::caret(10));
+        eb.move_down(&TestAdvance, true);
+        let cursor = eb.cursors().next().unwrap().1;
+        assert_eq!((cursor.start, cursor.end), (10, 15));
+
+        // past the last line goes to the end, and the column is remembered on the way back
+        eb.move_down(&TestAdvance, false);
+        assert_eq!(head(&eb), 17);
+        eb.move_up(&TestAdvance, false);
+        assert_eq!(head(&eb), 10);
+
+        // any edit forgets the column
+        eb.insert("x".as_bytes());
+        assert_eq!(eb.cursors().next().unwrap().1.horizontal, None);
+    }
+}
@@ -0,0 +1,260 @@
+// Hard wrapping paragraphs to a column, for commit messages, markdown and comments
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::text_renderer::TAB_SIZE;
+
+use super::{EditingBuffer, line_start, line_end};
+
+// Columns taken by ch in a monospace terminal style grid.
+// Combining marks take none and east asian wide characters and emoji take two.
+pub fn char_width(ch: char) -> usize {
+    match ch as u32 {
+        0x0300..=0x036f | 0x200b..=0x200f | 0xfe00..=0xfe0f => 0,
+        0x1100..=0x115f | 0x2e80..=0x303e | 0x3041..=0x33ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf | 0xac00..=0xd7a3 | 0xf900..=0xfaff | 0xfe30..=0xfe4f | 0xff00..=0xff60
+        | 0xffe0..=0xffe6 | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x3fffd => 2,
+        _ => 1
+    }
+}
+
+// column reached after text starting at column, tabs go to the next tab stop
+pub fn display_width(text: &str, column: usize) -> usize {
+    text.chars().fold(column, |column, ch| match ch {
+        '\t' => (column / TAB_SIZE + 1) * TAB_SIZE,
+        ch => column + char_width(ch),
+    })
+}
+
+// A line split into the parts reflowing keeps and the words it moves around
+struct Line<'a> {
+    range: Range<usize>,
+    // indentation and comment markers such as //, # and >, with the spaces after them
+    prefix: &'a str,
+    // list bullet such as -, * or 1. with the spaces after it, empty if there is none
+    bullet: &'a str,
+    content: &'a str,
+}
+
+impl Line<'_> {
+    // lines with the same key can be joined into one paragraph
+    fn key(&self) -> String {
+        self.prefix.chars().filter(|ch| !ch.is_whitespace()).collect()
+    }
+
+    fn is_blank(&self) -> bool {
+        self.content.trim().is_empty() && self.bullet.is_empty()
+    }
+}
+
+fn skip_spaces(line: &str, pos: usize) -> usize {
+    pos + line[pos..].len() - line[pos..].trim_start_matches([' ', '\t']).len()
+}
+
+fn parse_line(line: &str, range: Range<usize>) -> Line<'_> {
+    let mut pos = skip_spaces(line, 0);
+    loop {
+        let rest = &line[pos..];
+        let marker = if rest.starts_with("//") {
+            rest.len() - rest.trim_start_matches(['/', '!']).len()
+        } else if rest.starts_with('#') {
+            rest.len() - rest.trim_start_matches('#').len()
+        } else if rest.starts_with('>') {
+            1
+        } else {
+            break
+        };
+        pos = skip_spaces(line, pos + marker);
+    }
+    let prefix_end = pos;
+
+    let rest = &line[pos..];
+    let digits = rest.len() - rest.trim_start_matches(|ch: char| ch.is_ascii_digit()).len();
+    let bullet = if rest.starts_with(['-', '*', '+']) {
+        1
+    } else if digits > 0 && rest[digits..].starts_with(['.', ')']) {
+        digits + 1
+    } else {
+        0
+    };
+    // a bullet has to be followed by a space, or it is just the start of the text
+    let bullet_end = if bullet > 0 && rest[bullet..].starts_with([' ', '\t']) {
+        skip_spaces(line, pos + bullet)
+    } else {
+        pos
+    };
+
+    Line {
+        range,
+        prefix: &line[..prefix_end],
+        bullet: &line[prefix_end..bullet_end],
+        content: &line[bullet_end..],
+    }
+}
+
+// Fill the words of a paragraph into lines of at most width columns.
+// The first line keeps its prefix and bullet, the others get the prefix and spaces under the bullet.
+fn fill(lines: &[Line], width: usize) -> String {
+    let first = &lines[0];
+    let lead = format!("{}{}", first.prefix, first.bullet);
+    let hanging = format!(
+        "{}{}",
+        first.prefix,
+        " ".repeat(display_width(&lead, 0) - display_width(first.prefix, 0))
+    );
+
+    let mut out = lead.clone();
+    let mut column = display_width(&lead, 0);
+    let mut line_empty = true;
+    for word in lines.iter().flat_map(|line| line.content.split_whitespace()) {
+        let end = display_width(word, column + !line_empty as usize);
+        if !line_empty && end > width {
+            out.push('\n');
+            out.push_str(&hanging);
+            column = display_width(&hanging, 0);
+            line_empty = true;
+        }
+        if !line_empty {
+            out.push(' ');
+        }
+        out.push_str(word);
+        column = display_width(word, column + !line_empty as usize);
+        line_empty = false;
+    }
+    out.trim_end().to_string()
+}
+
+// paragraphs made of the lines of text in range, blank lines are left out
+fn paragraphs<'a>(text: &'a str, range: Range<usize>) -> Vec<Vec<Line<'a>>> {
+    let mut paragraphs: Vec<Vec<Line>> = Vec::new();
+    let mut start = range.start;
+    while start <= range.end {
+        let end = line_end(text.as_bytes(), start);
+        let line = parse_line(&text[start..end], start..end);
+        if line.is_blank() {
+            paragraphs.push(Vec::new());
+        } else {
+            match paragraphs.last_mut() {
+                Some(last) if !last.is_empty() && line.bullet.is_empty() && last[0].key() == line.key() => {
+                    last.push(line)
+                }
+                _ => paragraphs.push(vec![line]),
+            }
+        }
+        start = end + 1;
+    }
+    paragraphs.retain(|paragraph| !paragraph.is_empty());
+    paragraphs
+}
+
+// the lines of the paragraph around the line starting at start, or None on a blank line
+fn paragraph_around(text: &str, start: usize) -> Option<Range<usize>> {
+    let bytes = text.as_bytes();
+    let parse = |start: usize| parse_line(&text[start..line_end(bytes, start)], start..line_end(bytes, start));
+    let line = parse(start);
+    if line.is_blank() {
+        return None
+    }
+    let key = line.key();
+
+    let mut first = line.range.clone();
+    while line.bullet.is_empty() && first.start > 0 {
+        let prev = parse(line_start(bytes, first.start - 1));
+        if prev.is_blank() || prev.key() != key {
+            break
+        }
+        first = prev.range;
+        if !prev.bullet.is_empty() {
+            break
+        }
+    }
+
+    let mut last = line.range;
+    while last.end < text.len() {
+        let next = parse(last.end + 1);
+        if next.is_blank() || next.key() != key || !next.bullet.is_empty() {
+            break
+        }
+        last = next.range;
+    }
+    Some(first.start..last.end)
+}
+
+impl EditingBuffer {
+    // Rewrap the paragraph around every caret, or the paragraphs in every selection,
+    // so no line is longer than width columns unless it holds a single longer word.
+    pub fn reflow(&mut self, width: usize) {
+        let bytes = self.contents();
+        let text = String::from_utf8_lossy(&bytes);
+        // lossy decoding must not move any positions
+        if text.len() != bytes.len() {
+            return
+        }
+
+        let mut ranges: BTreeMap<usize, usize> = BTreeMap::new();
+        for (_, cursor) in self.cursors() {
+            let range = cursor.range();
+            if cursor.is_caret() {
+                if let Some(paragraph) = paragraph_around(&text, line_start(&bytes, range.start)) {
+                    ranges.insert(paragraph.start, paragraph.end);
+                }
+            } else {
+                let end = if range.end == line_start(&bytes, range.end) {range.end.saturating_sub(1).max(range.start)} else {range.end};
+                ranges.insert(line_start(&bytes, range.start), line_end(&bytes, end));
+            }
+        }
+
+        let edits = ranges.into_iter()
+            .flat_map(|(start, end)| paragraphs(&text, start..end))
+            .map(|lines| {
+                let range = lines[0].range.start..lines[lines.len() - 1].range.end;
+                (range, fill(&lines, width).into_bytes())
+            })
+            .collect();
+        self.replace_all(edits);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::Cursor;
+
+    fn reflowed(text: &str, cursor: Cursor, width: usize) -> String {
+        let mut eb = EditingBuffer::from_bytes(text.as_bytes());
+        eb.add_cursor(cursor);
+        let transactions = eb.span_table.transaction_count();
+        eb.reflow(width);
+        assert!(eb.span_table.transaction_count() <= transactions + 1);
+        String::from_utf8(eb.contents()).unwrap()
+    }
+
+    #[test]
+    fn test_reflow_paragraph() {
+        let text = "Subject\n\nthe quick brown fox\njumps over\nthe lazy dog\n\nnext";
+        assert_eq!(reflowed(text, Cursor::caret(12), 15),
+            "Subject\n\nthe quick brown\nfox jumps over\nthe lazy dog\n\nnext");
+        // a word longer than the width gets a line of its own
+        assert_eq!(reflowed("a verylongword b", Cursor::caret(0), 5), "a\nverylongword\nb");
+    }
+
+    #[test]
+    fn test_reflow_prefixes() {
+        let text = "    // one two three\n    // four\nfn x() {}";
+        assert_eq!(reflowed(text, Cursor::caret(5), 20), "    // one two three\n    // four\nfn x() {}");
+        assert_eq!(reflowed(text, Cursor::caret(5), 40), "    // one two three four\nfn x() {}");
+
+        let text = "> - first item that\n>   wraps\n> - second";
+        assert_eq!(reflowed(text, Cursor::new(0, text.len()), 14),
+            "> - first item\n>   that wraps\n> - second");
+    }
+
+    #[test]
+    fn test_display_width() {
+        assert_eq!(display_width("a\tb", 0), 5);
+        assert_eq!(display_width("日本", 1), 5);
+        // wide characters fill two columns each
+        assert_eq!(reflowed("日本語 日本語 日本語", Cursor::caret(0), 14), "日本語 日本語\n日本語");
+    }
+}
@@ -0,0 +1,400 @@
+// Multiple cursor handling, modelled after
+// https://github.com/xi-editor/xi-editor/blob/master/rust/core-lib/src/selection.rs
+// Every cursor is a region in the buffer, and regions that overlap or touch are merged into one.
+
+use std::ops::Range;
+
+use super::{Cursor, CursorId, EditingBuffer, line_start, line_end};
+use super::indent::indent_backspace;
+use super::autopair::pair_backspace;
+
+fn is_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+// number of characters between the start of the line and pos
+pub fn char_column(text: &[u8], pos: usize) -> usize {
+    text[line_start(text, pos)..pos].iter().filter(|b| !is_continuation(**b)).count()
+}
+
+// byte offset column characters into the line starting at start, clamped to the end of the line
+pub fn column_pos(text: &[u8], start: usize, column: usize) -> usize {
+    let end = line_end(text, start);
+    (start..end)
+        .filter(|pos| !is_continuation(text[*pos]))
+        .nth(column)
+        .unwrap_or(end)
+}
+
+// starts of needle in text from from on, then from the start up to from
+fn occurrences<'a>(text: &'a [u8], needle: &'a [u8], from: usize) -> impl Iterator<Item = usize> + 'a {
+    (from..=text.len()).chain(0..from).filter(move |pos| text[*pos..].starts_with(needle))
+}
+
+fn prev_char(text: &[u8], pos: usize) -> usize {
+    let mut pos = pos.saturating_sub(1);
+    while pos > 0 && is_continuation(text[pos]) {
+        pos -= 1;
+    }
+    pos
+}
+
+fn next_char(text: &[u8], pos: usize) -> usize {
+    let mut pos = (pos + 1).min(text.len());
+    while pos < text.len() && is_continuation(text[pos]) {
+        pos += 1;
+    }
+    pos
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+
+impl EditingBuffer {
+    // merge cursors that overlap or touch, the earlier cursor survives and keeps its direction
+    pub(super) fn merge_cursors(&mut self) {
+        let cursors: Vec<(CursorId, Cursor)> = self.cursors().collect();
+        let mut cursors = cursors.into_iter();
+        let (mut keep_id, mut keep) = match cursors.next() {
+            Some(first) => first,
+            None => return
+        };
+
+        for (id, cursor) in cursors {
+            let (a, b) = (keep.range(), cursor.range());
+            if b.start > a.end {
+                keep_id = id;
+                keep = cursor;
+                continue
+            }
+
+            let end = a.end.max(b.end);
+            keep = if keep.start > keep.end {
+                Cursor::new(end, a.start)
+            } else {
+                Cursor::new(a.start, end)
+            };
+            self.remove_cursor(id);
+            self.set_cursor(keep_id, keep);
+        }
+    }
+
+    // add a cursor, merging it with any cursor it overlaps
+    pub fn add_cursor(&mut self, cursor: Cursor) {
+        self.cursors.insert(cursor);
+        self.merge_cursors();
+    }
+
+    // Replace a range around every cursor as one transaction.
+    // f is called for the cursors in order and sees the contents from before any edit.
+    // Deletions that overlap are merged, any other edit overlapping an earlier one is dropped.
+    pub(super) fn edit_cursors<F>(&mut self, mut f: F)
+        where F: FnMut(&[u8], Cursor) -> (Range<usize>, Vec<u8>)
+    {
+        let text = self.contents();
+        let mut ranges: Vec<(CursorId, Range<usize>, Vec<u8>)> = self.cursors()
+            .map(|(id, cursor)| {
+                let (range, content) = f(&text, cursor);
+                (id, range, content)
+            })
+            .collect();
+        ranges.sort_by_key(|(_, range, _)| (range.start, range.end));
+        let mut edits: Vec<(CursorId, Range<usize>, Vec<u8>)> = Vec::with_capacity(ranges.len());
+        for (id, range, content) in ranges {
+            match edits.last_mut() {
+                Some((_, last, deleted)) if range.start < last.end => {
+                    if deleted.is_empty() && content.is_empty() {
+                        last.end = last.end.max(range.end);
+                    }
+                }
+                _ => edits.push((id, range, content))
+            }
+        }
+
+        self.transaction(|eb| {
+            // back to front so the ranges of the remaining edits stay valid
+            for (id, range, content) in edits.into_iter().rev() {
+                eb.set_cursor(id, Cursor::new(range.start, range.end));
+                eb.set(id, &content);
+            }
+        });
+    }
+
+    // replace every selection with content
+    pub fn insert(&mut self, content: &[u8]) {
+        self.edit_cursors(|_, cursor| (cursor.range(), content.to_vec()));
+    }
+
+    // in leading spaces a caret deletes back to the previous indent level,
+    // and between an empty pair of brackets or quotes it deletes both
+    pub fn delete_backward(&mut self) {
+        let style = self.indent;
+        let pairs = self.pairs.clone();
+        self.edit_cursors(|text, cursor| {
+            if cursor.is_caret() {
+                if let Some(range) = pair_backspace(text, cursor.end, &pairs) {
+                    return (range, Vec::new())
+                }
+                let start = indent_backspace(text, cursor.end, style).unwrap_or_else(|| prev_char(text, cursor.end));
+                (start .. cursor.end, Vec::new())
+            } else {
+                (cursor.range(), Vec::new())
+            }
+        });
+    }
+
+    pub fn delete_forward(&mut self) {
+        self.edit_cursors(|text, cursor| {
+            if cursor.is_caret() {
+                (cursor.end .. next_char(text, cursor.end), Vec::new())
+            } else {
+                (cursor.range(), Vec::new())
+            }
+        });
+    }
+
+    pub fn add_cursor_above(&mut self) {
+        self.add_cursor_vertical(true);
+    }
+
+    pub fn add_cursor_below(&mut self) {
+        self.add_cursor_vertical(false);
+    }
+
+    // add a copy of every cursor on the neighbouring line, at the same column
+    fn add_cursor_vertical(&mut self, up: bool) {
+        let text = self.contents();
+        let added: Vec<Cursor> = self.cursors().filter_map(|(_, cursor)| {
+            let start = line_start(&text, cursor.end);
+            let target = if up {
+                if start == 0 {
+                    return None
+                }
+                line_start(&text, start - 1)
+            } else {
+                let end = line_end(&text, cursor.end);
+                if end == text.len() {
+                    return None
+                }
+                end + 1
+            };
+
+            // selections within one line are copied, anything else becomes a caret
+            let head = column_pos(&text, target, char_column(&text, cursor.end));
+            if line_start(&text, cursor.start) == start {
+                let anchor = column_pos(&text, target, char_column(&text, cursor.start));
+                Some(Cursor::new(anchor, head))
+            } else {
+                Some(Cursor::caret(head))
+            }
+        }).collect();
+
+        for cursor in added {
+            self.cursors.insert(cursor);
+        }
+        self.merge_cursors();
+    }
+
+    // Select the next occurrence of the last selection after it, wrapping around.
+    // A caret is first expanded to the word around it.
+    pub fn add_next_occurrence(&mut self) {
+        let text = self.contents();
+        let (id, last) = match self.cursors().last() {
+            Some(last) => last,
+            None => return
+        };
+
+        if last.is_caret() {
+            let mut start = last.end;
+            while start > 0 && is_word_byte(text[start - 1]) {
+                start -= 1;
+            }
+            let mut end = last.end;
+            while end < text.len() && is_word_byte(text[end]) {
+                end += 1;
+            }
+            self.set_cursor(id, Cursor::new(start, end));
+            return
+        }
+
+        let needle = &text[last.range()];
+        for pos in occurrences(&text, needle, last.range().end) {
+            let range = pos .. pos + needle.len();
+            let taken = self.cursors().any(|(_, c)| c.range().start < range.end && range.start < c.range().end);
+            if !taken {
+                self.add_cursor(Cursor::new(range.start, range.end));
+                return
+            }
+        }
+    }
+
+    // Select the next occurrence of needle after the last cursor, wrapping around.
+    // Returns false if there is none.
+    pub fn search(&mut self, needle: &[u8]) -> bool {
+        let text = self.contents();
+        let (id, last) = match self.cursors().last() {
+            Some(last) => last,
+            None => return false
+        };
+        if needle.is_empty() {
+            return false
+        }
+        let pos = match occurrences(&text, needle, last.range().end).next() {
+            Some(pos) => pos,
+            None => return false
+        };
+        self.set_cursor(id, Cursor::new(pos, pos + needle.len()));
+        self.merge_cursors();
+        true
+    }
+
+    // split every selection spanning several lines into one selection per line
+    pub fn split_into_lines(&mut self) {
+        let text = self.contents();
+        let cursors: Vec<(CursorId, Cursor)> = self.cursors().collect();
+        for (id, cursor) in cursors {
+            let range = cursor.range();
+            let mut start = range.start;
+            let mut first = true;
+            loop {
+                let end = line_end(&text, start).min(range.end);
+                let line = Cursor::new(start, end);
+                if first {
+                    self.set_cursor(id, line);
+                    first = false;
+                } else {
+                    self.cursors.insert(line);
+                }
+                // a selection ending right after a newline does not select the next line
+                if end >= range.end || end + 1 >= range.end {
+                    break
+                }
+                start = end + 1;
+            }
+        }
+        self.merge_cursors();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ranges(eb: &EditingBuffer) -> Vec<(usize, usize)> {
+        eb.cursors().map(|(_, c)| (c.start, c.end)).collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut eb = EditingBuffer::from_bytes("0123456789".as_bytes());
+        eb.add_cursor(Cursor::new(1, 3));
+        eb.add_cursor(Cursor::new(6, 4));
+        eb.add_cursor(Cursor::caret(8));
+        assert_eq!(ranges(&eb), vec![(1, 3), (6, 4), (8, 8)]);
+
+        // touching
+        eb.add_cursor(Cursor::new(3, 4));
+        assert_eq!(ranges(&eb), vec![(1, 6), (8, 8)]);
+
+        // overlapping a backwards selection keeps its direction
+        eb.add_cursor(Cursor::new(9, 7));
+        assert_eq!(ranges(&eb), vec![(1, 6), (9, 7)]);
+        eb.add_cursor(Cursor::new(8, 10));
+        assert_eq!(ranges(&eb), vec![(1, 6), (10, 7)]);
+    }
+
+    #[test]
+    fn test_multi_edit() {
+        let mut eb = EditingBuffer::from_bytes("a\nb\nc".as_bytes());
+        eb.add_cursor(Cursor::caret(1));
+        eb.add_cursor(Cursor::caret(3));
+        eb.add_cursor(Cursor::caret(5));
+        let transactions = eb.span_table.transaction_count();
+
+        eb.insert("xy".as_bytes());
+        assert_eq!(eb.contents(), "axy\nbxy\ncxy".as_bytes());
+        assert_eq!(ranges(&eb), vec![(3, 3), (7, 7), (11, 11)]);
+        assert_eq!(eb.span_table.transaction_count(), transactions + 1);
+
+        eb.delete_backward();
+        eb.delete_backward();
+        eb.delete_backward();
+        assert_eq!(eb.contents(), "\n\n".as_bytes());
+        assert_eq!(ranges(&eb), vec![(0, 0), (1, 1), (2, 2)]);
+
+        // deleting the newlines makes the carets meet and merge
+        eb.delete_forward();
+        assert_eq!(eb.contents(), "".as_bytes());
+        assert_eq!(ranges(&eb), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_overlapping_edits() {
+        // overlapping deletions delete everything either one covers
+        let mut eb = EditingBuffer::from_bytes("abcdef".as_bytes());
+        eb.add_cursor(Cursor::caret(2));
+        eb.add_cursor(Cursor::caret(3));
+        eb.edit_cursors(|_, cursor| (cursor.end - 2..cursor.end, Vec::new()));
+        assert_eq!(eb.contents(), "def".as_bytes());
+        assert_eq!(ranges(&eb), vec![(0, 0)]);
+
+        // any other edit overlapping an earlier one is left out
+        let mut eb = EditingBuffer::from_bytes("abcdef".as_bytes());
+        eb.add_cursor(Cursor::caret(2));
+        eb.add_cursor(Cursor::caret(3));
+        eb.edit_cursors(|_, cursor| (cursor.end - 1..cursor.end + 1, b"X".to_vec()));
+        assert_eq!(eb.contents(), "aXdef".as_bytes());
+    }
+
+    #[test]
+    fn test_add_vertical() {
+        let mut eb = EditingBuffer::from_bytes("hello\nhi\nworld".as_bytes());
+        eb.add_cursor(Cursor::caret(4));
+        eb.add_cursor_below();
+        assert_eq!(ranges(&eb), vec![(4, 4), (8, 8)]);
+        eb.add_cursor_below();
+        assert_eq!(ranges(&eb), vec![(4, 4), (8, 8), (11, 11)]);
+        // the carets on the lower lines are clamped to the short line, nothing fits above the first
+        eb.add_cursor_above();
+        assert_eq!(ranges(&eb), vec![(2, 2), (4, 4), (8, 8), (11, 11)]);
+    }
+
+    #[test]
+    fn test_next_occurrence() {
+        let mut eb = EditingBuffer::from_bytes("foo bar foo foobar".as_bytes());
+        eb.add_cursor(Cursor::caret(9));
+        eb.add_next_occurrence();
+        assert_eq!(ranges(&eb), vec![(8, 11)]);
+        eb.add_next_occurrence();
+        assert_eq!(ranges(&eb), vec![(8, 11), (12, 15)]);
+        eb.add_next_occurrence();
+        assert_eq!(ranges(&eb), vec![(0, 3), (8, 11), (12, 15)]);
+        eb.add_next_occurrence();
+        assert_eq!(ranges(&eb).len(), 3);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut eb = EditingBuffer::from_bytes("foo bar foo foobar".as_bytes());
+        eb.add_cursor(Cursor::caret(1));
+        assert!(eb.search(b"foo"));
+        assert_eq!(ranges(&eb), vec![(8, 11)]);
+        assert!(eb.search(b"foo"));
+        assert!(eb.search(b"foo"));
+        // wrapped around to the start
+        assert_eq!(ranges(&eb), vec![(0, 3)]);
+        assert!(!eb.search(b"baz"));
+        assert!(!eb.search(b""));
+        assert_eq!(ranges(&eb), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn test_split_into_lines() {
+        let mut eb = EditingBuffer::from_bytes("one\ntwo\nthree\n".as_bytes());
+        eb.add_cursor(Cursor::new(1, 14));
+        eb.split_into_lines();
+        assert_eq!(ranges(&eb), vec![(1, 3), (4, 7), (8, 13)]);
+    }
+}
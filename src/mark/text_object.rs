@@ -0,0 +1,337 @@
+// Vim style text objects, the regions a cursor can be expanded to select
+
+use std::ops::Range;
+
+use crate::text_renderer::TAB_SIZE;
+
+use super::{Cursor, CursorId, EditingBuffer, line_start, line_end};
+use super::motion::{CharClass, char_at, char_before, char_class, find_close, find_open, is_blank_line, skip_back_while, skip_while};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextObject {
+    // run of word characters, punctuation or whitespace
+    Word,
+    // run of anything but whitespace
+    BigWord,
+    Sentence,
+    Paragraph,
+    // quoted string on the cursor's line, the byte is the quote character
+    Quote(u8),
+    // innermost group delimited by a pair of brackets, such as (b'(', b')')
+    Bracket(u8, u8),
+    // lines indented at least as far as the cursor's line
+    Indent,
+}
+
+impl TextObject {
+    pub const PARENS: TextObject = TextObject::Bracket(b'(', b')');
+    pub const BRACKETS: TextObject = TextObject::Bracket(b'[', b']');
+    pub const BRACES: TextObject = TextObject::Bracket(b'{', b'}');
+    pub const ANGLE_BRACKETS: TextObject = TextObject::Bracket(b'<', b'>');
+
+    // Range of the object around the selection, inner leaves out the surrounding whitespace or delimiters.
+    // A selection that already covers a bracket object grows to the enclosing pair.
+    pub fn range(self, text: &[u8], selection: Range<usize>, inner: bool) -> Option<Range<usize>> {
+        let pos = selection.start;
+        match self {
+            TextObject::Word => Some(word(text, pos, inner, char_class)),
+            TextObject::BigWord => Some(word(text, pos, inner, |ch| match char_class(ch) {
+                CharClass::Space => CharClass::Space,
+                _ => CharClass::Word,
+            })),
+            TextObject::Sentence => sentence(text, pos, inner),
+            TextObject::Paragraph => Some(paragraph(text, pos, inner)),
+            TextObject::Quote(quote_char) => quote(text, pos, quote_char, inner),
+            TextObject::Bracket(open, close) => bracket(text, selection, open, close, inner),
+            TextObject::Indent => Some(indent(text, pos, inner)),
+        }
+    }
+}
+
+// Run of characters in the same class as the one at pos, around also takes the whitespace after it,
+// or before it when there is none after.
+fn word<F: Fn(char) -> CharClass>(text: &[u8], pos: usize, inner: bool, class_of: F) -> Range<usize> {
+    // a caret at the end of a line selects the word before it
+    let at = match char_at(text, pos) {
+        Some((ch, _)) if ch != '\n' => pos,
+        _ => match char_before(text, pos) {
+            Some((ch, prev)) if ch != '\n' => prev,
+            _ => return pos..pos
+        }
+    };
+    let class = class_of(char_at(text, at).unwrap().0);
+    let same = |ch: char| ch != '\n' && class_of(ch) == class;
+    let start = skip_back_while(text, at, same);
+    let end = skip_while(text, at, same);
+    if inner {
+        return start..end
+    }
+
+    let space = |ch: char| ch != '\n' && char_class(ch) == CharClass::Space;
+    if class == CharClass::Space {
+        let next = match char_at(text, end) {
+            Some((ch, _)) if ch != '\n' => class_of(ch),
+            _ => return start..end
+        };
+        return start..skip_while(text, end, |ch| ch != '\n' && class_of(ch) == next)
+    }
+    let trailing = skip_while(text, end, space);
+    if trailing > end {
+        start..trailing
+    } else {
+        skip_back_while(text, start, space)..end
+    }
+}
+
+// first and last line of the run of lines around pos that are all blank or all not blank
+fn line_run(text: &[u8], pos: usize) -> (usize, usize) {
+    let blank = is_blank_line(text, line_start(text, pos));
+    let mut first = line_start(text, pos);
+    while first > 0 && is_blank_line(text, line_start(text, first - 1)) == blank {
+        first = line_start(text, first - 1);
+    }
+    let mut last = line_start(text, pos);
+    while line_end(text, last) < text.len() && is_blank_line(text, line_end(text, last) + 1) == blank {
+        last = line_end(text, last) + 1;
+    }
+    (first, last)
+}
+
+// end of the line starting at start including its newline
+fn line_end_inclusive(text: &[u8], start: usize) -> usize {
+    (line_end(text, start) + 1).min(text.len())
+}
+
+fn paragraph(text: &[u8], pos: usize, inner: bool) -> Range<usize> {
+    let (first, last) = line_run(text, pos);
+    let end = line_end_inclusive(text, last);
+    if inner {
+        return first..end
+    }
+    // the blank lines after it, or before it at the end of the buffer
+    if end < text.len() {
+        let (_, next_last) = line_run(text, end);
+        first..line_end_inclusive(text, next_last)
+    } else if first > 0 {
+        let (prev_first, _) = line_run(text, first - 1);
+        prev_first..end
+    } else {
+        first..end
+    }
+}
+
+// Sentences end at ., ! or ? followed by whitespace, and never cross a paragraph.
+fn sentence(text: &[u8], pos: usize, inner: bool) -> Option<Range<usize>> {
+    let (first, last) = line_run(text, pos);
+    if is_blank_line(text, first) {
+        return None
+    }
+    let para_end = line_end(text, last);
+
+    let is_space = |b: u8| b.is_ascii_whitespace();
+    let mut start = first + text[first..para_end].iter().take_while(|b| is_space(**b)).count();
+    let mut i = start;
+    while i < para_end {
+        let terminated = matches!(text[i], b'.' | b'!' | b'?')
+            && (i + 1 == para_end || is_space(text[i + 1]));
+        i += 1;
+        if !terminated && i < para_end {
+            continue
+        }
+        let next = i + text[i..para_end].iter().take_while(|b| is_space(**b)).count();
+        if pos < next || next == para_end {
+            return Some(if inner {start..i} else {start..next})
+        }
+        start = next;
+        i = next;
+    }
+    None
+}
+
+// Quoted string on the line containing pos, or the first one after pos. Quotes escaped with \ are skipped.
+fn quote(text: &[u8], pos: usize, quote_char: u8, inner: bool) -> Option<Range<usize>> {
+    let end = line_end(text, pos);
+    let mut quotes = Vec::new();
+    let mut i = line_start(text, pos);
+    while i < end {
+        if text[i] == b'\\' {
+            i += 2;
+            continue
+        }
+        if text[i] == quote_char {
+            quotes.push(i);
+        }
+        i += 1;
+    }
+
+    let pair = quotes.chunks_exact(2).find(|pair| pos <= pair[1])?;
+    let (open, close) = (pair[0], pair[1]);
+    Some(if inner {open + 1..close} else {open..close + 1})
+}
+
+fn bracket(text: &[u8], selection: Range<usize>, open: u8, close: u8, inner: bool) -> Option<Range<usize>> {
+    // a caret on an open bracket selects that group
+    let mut open_pos = if selection.start == selection.end && text.get(selection.start) == Some(&open) {
+        selection.start
+    } else {
+        find_open(text, selection.start, open, close)?
+    };
+    loop {
+        let close_pos = find_close(text, open_pos + 1, open, close)?;
+        let range = if inner {open_pos + 1..close_pos} else {open_pos..close_pos + 1};
+        let covers = if selection.is_empty() {
+            open_pos <= selection.start && selection.start <= close_pos
+        } else {
+            range.start <= selection.start && selection.end <= range.end
+        };
+        if covers && range != selection {
+            return Some(range)
+        }
+        open_pos = find_open(text, open_pos, open, close)?;
+    }
+}
+
+fn indent_width(text: &[u8], start: usize) -> usize {
+    let mut width = 0;
+    for b in &text[start..line_end(text, start)] {
+        match b {
+            b' ' => width += 1,
+            b'\t' => width = (width / TAB_SIZE + 1) * TAB_SIZE,
+            _ => break
+        }
+    }
+    width
+}
+
+// Lines around pos indented at least as far as its line, around also takes the line above it.
+fn indent(text: &[u8], pos: usize, inner: bool) -> Range<usize> {
+    let line = line_start(text, pos);
+    let level = indent_width(text, line);
+    let inside = |start: usize| is_blank_line(text, start) || indent_width(text, start) >= level;
+
+    let mut first = line;
+    while first > 0 && inside(line_start(text, first - 1)) {
+        first = line_start(text, first - 1);
+    }
+    let mut last = line;
+    while line_end(text, last) < text.len() && inside(line_end(text, last) + 1) {
+        last = line_end(text, last) + 1;
+    }
+    // blank lines at the edges belong to the surrounding code
+    while first < line && is_blank_line(text, first) {
+        first = line_end(text, first) + 1;
+    }
+    while last > line && is_blank_line(text, last) {
+        last = line_start(text, last - 1);
+    }
+
+    if !inner && first > 0 {
+        first = line_start(text, first - 1);
+    }
+    first..line_end_inclusive(text, last)
+}
+
+impl EditingBuffer {
+    // object around a cursor, None if there is no such object
+    pub fn text_object(&self, id: CursorId, object: TextObject, inner: bool) -> Option<Cursor> {
+        let range = object.range(&self.contents(), self.cursor(id).range(), inner)?;
+        Some(Cursor::new(range.start, range.end))
+    }
+
+    // select the object around every cursor, cursors without one are left alone
+    pub fn select_text_object(&mut self, object: TextObject, inner: bool) {
+        let text = self.contents();
+        let selected: Vec<(CursorId, Cursor)> = self.cursors()
+            .filter_map(|(id, cursor)| {
+                let range = object.range(&text, cursor.range(), inner)?;
+                Some((id, Cursor::new(range.start, range.end)))
+            })
+            .collect();
+
+        for (id, cursor) in selected {
+            self.set_cursor(id, cursor);
+        }
+        self.merge_cursors();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn select(text: &str, pos: usize, object: TextObject, inner: bool) -> &str {
+        let range = object.range(text.as_bytes(), pos..pos, inner).unwrap();
+        &text[range]
+    }
+
+    #[test]
+    fn test_words() {
+        let text = "let foo_bar = x.len();";
+        assert_eq!(select(text, 5, TextObject::Word, true), "foo_bar");
+        assert_eq!(select(text, 5, TextObject::Word, false), "foo_bar ");
+        assert_eq!(select(text, 16, TextObject::Word, true), "len");
+        assert_eq!(select(text, 16, TextObject::BigWord, true), "x.len();");
+        // no whitespace after, so the whitespace before is taken
+        assert_eq!(select(text, 16, TextObject::BigWord, false), " x.len();");
+        assert_eq!(select(text, 3, TextObject::Word, false), " foo_bar");
+    }
+
+    #[test]
+    fn test_sentences_and_paragraphs() {
+        let text = "One two. Three four!  Five\nsix.\n\nNext para.\n";
+        assert_eq!(select(text, 11, TextObject::Sentence, true), "Three four!");
+        assert_eq!(select(text, 11, TextObject::Sentence, false), "Three four!  ");
+        assert_eq!(select(text, 24, TextObject::Sentence, true), "Five\nsix.");
+        assert_eq!(select(text, 2, TextObject::Paragraph, true), "One two. Three four!  Five\nsix.\n");
+        assert_eq!(select(text, 2, TextObject::Paragraph, false), "One two. Three four!  Five\nsix.\n\n");
+        assert_eq!(select(text, 36, TextObject::Paragraph, false), "\nNext para.\n");
+    }
+
+    #[test]
+    fn test_quotes() {
+        let text = r#"print("a \"b\"", 'c') "d""#;
+        assert_eq!(select(text, 8, TextObject::Quote(b'"'), true), r#"a \"b\""#);
+        assert_eq!(select(text, 8, TextObject::Quote(b'"'), false), r#""a \"b\"""#);
+        // before the first quote selects the next string
+        assert_eq!(select(text, 0, TextObject::Quote(b'\''), true), "c");
+        assert_eq!(select(text, 20, TextObject::Quote(b'"'), true), "d");
+    }
+
+    #[test]
+    fn test_brackets() {
+        let text = "f(a, [b, c], <T>) { g(); }";
+        assert_eq!(select(text, 7, TextObject::BRACKETS, true), "b, c");
+        assert_eq!(select(text, 7, TextObject::PARENS, false), "(a, [b, c], <T>)");
+        assert_eq!(select(text, 1, TextObject::PARENS, true), "a, [b, c], <T>");
+        assert_eq!(select(text, 14, TextObject::ANGLE_BRACKETS, true), "T");
+        assert_eq!(select(text, 20, TextObject::BRACES, true), " g(); ");
+        assert!(TextObject::BRACES.range(text.as_bytes(), 3..3, true).is_none());
+
+        // selecting again grows the selection to the enclosing group
+        let text = "((a))";
+        let inner = TextObject::PARENS.range(text.as_bytes(), 2..2, true).unwrap();
+        assert_eq!(inner, 2..3);
+        let outer = TextObject::PARENS.range(text.as_bytes(), inner, true).unwrap();
+        assert_eq!(outer, 1..4);
+    }
+
+    #[test]
+    fn test_indent() {
+        let text = "fn f() {\n    a;\n\n    if x {\n        b;\n    }\n}\n";
+        assert_eq!(select(text, 12, TextObject::Indent, true), "    a;\n\n    if x {\n        b;\n    }\n");
+        assert_eq!(select(text, 36, TextObject::Indent, true), "        b;\n");
+        assert_eq!(select(text, 36, TextObject::Indent, false), "    if x {\n        b;\n");
+    }
+
+    #[test]
+    fn test_select_all_cursors() {
+        let mut eb = EditingBuffer::from_bytes("(one) (two)".as_bytes());
+        let a = eb.new_cursor();
+        eb.set_cursor(a, Cursor::caret(2));
+        eb.add_cursor(Cursor::caret(8));
+        eb.select_text_object(TextObject::PARENS, true);
+        let ranges: Vec<Range<usize>> = eb.cursors().map(|(_, c)| c.range()).collect();
+        assert_eq!(ranges, vec![1..4, 7..10]);
+        assert_eq!(eb.text_object(a, TextObject::PARENS, false), Some(Cursor::new(0, 5)));
+    }
+}
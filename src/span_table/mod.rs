@@ -46,6 +46,8 @@ pub struct SpanTable {
     spans: Vec<Span>,
     // TODO: rename to operations
     commands: Vec<Operation>,
+    // command index that each transaction starts at
+    transactions: Vec<usize>,
     // TODO: last edited span for contigous edits
 }
 
@@ -60,10 +62,24 @@ impl SpanTable {
         self.commands.len()
     }
 
+    // group every command from here until the next transaction
+    pub fn start_transaction(&mut self) {
+        self.transactions.push(self.commands.len());
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
     pub fn span_len(&self) -> usize {
         self.spans.len()
     }
 
+    // total number of bytes covered by the spans
+    pub fn len(&self) -> usize {
+        self.spans.iter().map(Span::len).sum()
+    }
+
     // TODO: write tests
     pub fn byte_offset(&self, offset: usize) -> SpanPos {
         if offset == 0 {
@@ -111,6 +127,41 @@ impl SpanTable {
         self.commands.push(Operation::SplitSpan {span: original_span, index, byte_offset});
     }
 
+    // make sure a span boundary lies at offset, and return the index of the span starting there
+    pub fn split_at(&mut self, offset: usize) -> usize {
+        if offset == 0 {
+            return 0
+        }
+
+        let pos = self.byte_offset(offset);
+        if pos.byte_offset == self.spans[pos.span_index].len() {
+            return pos.span_index + 1
+        }
+        self.split_span(pos.span_index, pos.byte_offset);
+        pos.span_index + 1
+    }
+
+    // insert span at a byte offset into the content
+    pub fn insert(&mut self, span: Span, offset: usize) {
+        if span.len() == 0 {
+            return
+        }
+        let index = self.split_at(offset);
+        self.insert_span(span, index);
+    }
+
+    // remove the bytes in start..end from the content
+    pub fn delete(&mut self, start: usize, end: usize) {
+        if start == end {
+            return
+        }
+        let first = self.split_at(start);
+        let last = self.split_at(end);
+        for _ in first..last {
+            self.remove_span(first);
+        }
+    }
+
     pub fn contents(&self, buffer: &Vec<u8>) -> Vec<u8> {
         let mut contents: Vec<u8> = Vec::new();
         for span in &self.spans {
@@ -227,4 +278,28 @@ mod test {
         stb.assert_spans_equal(&["123", "hello", "abc", "world"]);
     }
 
+    #[test]
+    fn test_insert_delete_offset() {
+        let mut stb = SpanTableBuffer::default();
+
+        let span = stb.span("helloworld");
+        stb.st.insert(span, 0);
+        let span = stb.span(" ");
+        stb.st.insert(span, 5);
+        stb.assert_spans_equal(&["hello", " ", "world"]);
+        assert_eq!(stb.st.len(), 11);
+
+        let span = stb.span("!");
+        stb.st.insert(span, 11);
+        stb.assert_span_table_equals("hello world!");
+
+        stb.st.delete(3, 8);
+        stb.assert_span_table_equals("helrld!");
+        stb.assert_spans_equal(&["hel", "rld", "!"]);
+
+        stb.st.delete(0, 7);
+        stb.assert_span_table_equals("");
+        assert_eq!(stb.st.span_len(), 0);
+    }
+
 }
\ No newline at end of file
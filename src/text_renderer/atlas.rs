@@ -0,0 +1,432 @@
+// Glyph atlas, which keeps the cached glyph masks in a few large SDL textures so drawing a run of
+// glyphs is a list of copies instead of a texture per glyph.
+// Masks are packed on shelves, rows as tall as the first glyph placed on them. Space is not freed when
+// a glyph leaves the glyph cache, instead once every page is full they are all rebuilt with just the
+// glyphs still in the cache.
+//
+// Subpixel masks need a different weight per channel, which no SDL blend mode has. Every glyph is drawn
+// in two copies instead, which also works on the software renderer:
+// first one minus the mask multiplied with what is under it, then the mask in the text color added on top.
+// Color glyphs keep their own colors and are one copy of their premultiplied pixels from the mask texture.
+// A glyph too big for a page is composited over the background color on the cpu and drawn from a texture of its own.
+
+use std::collections::HashMap;
+
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, RenderTarget, Texture, TextureCreator};
+use sdl2::sys::{SDL_BlendFactor, SDL_BlendOperation, SDL_ComposeCustomBlendMode, SDL_SetTextureBlendMode};
+
+use super::{subpixel_position, FontSize, GlyphCacheKey, GlyphRenderer, ImageBuffer, PositionedGlyph, RenderOptions};
+
+// width and height of a page in pixels
+const PAGE_SIZE: u32 = 1024;
+// space around every glyph so scaling the canvas never samples a neighbour
+const PADDING: u32 = 1;
+
+// Draw texture as premultiplied colors, dst = src + dst * (1 - src alpha).
+// Returns false if the renderer can not blend like that, the software renderer only has the fixed modes.
+fn set_premultiplied_blend(texture: &mut Texture) -> bool {
+  use SDL_BlendFactor::{SDL_BLENDFACTOR_ONE, SDL_BLENDFACTOR_ONE_MINUS_SRC_ALPHA};
+  use SDL_BlendOperation::SDL_BLENDOPERATION_ADD;
+  unsafe {
+    // a custom mode is none of the variants of SDL_BlendMode, so it is only handed back to SDL and never matched on
+    let mode = SDL_ComposeCustomBlendMode(
+      SDL_BLENDFACTOR_ONE, SDL_BLENDFACTOR_ONE_MINUS_SRC_ALPHA, SDL_BLENDOPERATION_ADD,
+      SDL_BLENDFACTOR_ONE, SDL_BLENDFACTOR_ONE_MINUS_SRC_ALPHA, SDL_BLENDOPERATION_ADD,
+    );
+    SDL_SetTextureBlendMode(texture.raw(), mode) == 0
+  }
+}
+
+// a premultiplied color back to straight alpha
+fn unpremultiply(px: &[u8]) -> [u8; 4] {
+  let alpha = px[3] as u16;
+  if alpha == 0 {
+    return [0; 4]
+  }
+  let channel = |c: u8| (c as u16 * 255 / alpha).min(255) as u8;
+  [channel(px[0]), channel(px[1]), channel(px[2]), px[3]]
+}
+
+struct Shelf {
+  y: u32,
+  height: u32,
+  // where the next glyph on the shelf goes
+  x: u32,
+}
+
+// Packs rectangles into a page on shelves
+pub struct ShelfPacker {
+  width: u32,
+  height: u32,
+  shelves: Vec<Shelf>,
+}
+
+impl ShelfPacker {
+  pub fn new(width: u32, height: u32) -> Self {
+    ShelfPacker {width, height, shelves: Vec::new()}
+  }
+
+  // top left corner of a free width by height area, None if the page is full
+  pub fn alloc(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+    let page_width = self.width;
+    // the shelf that wastes the least height
+    let shelf = self.shelves.iter_mut()
+      .filter(|shelf| shelf.height >= height && page_width - shelf.x >= width)
+      .min_by_key(|shelf| shelf.height - height);
+    if let Some(shelf) = shelf {
+      let x = shelf.x;
+      shelf.x += width;
+      return Some((x, shelf.y))
+    }
+
+    let y = self.shelves.last().map_or(0, |shelf| shelf.y + shelf.height);
+    if width > self.width || y + height > self.height {
+      return None
+    }
+    self.shelves.push(Shelf {y, height, x: width});
+    Some((0, y))
+  }
+
+  pub fn clear(&mut self) {
+    self.shelves.clear();
+  }
+}
+
+struct Page<'r> {
+  // the mask with an opaque alpha, added in the text color, or the pixels of a color glyph
+  mask: Texture<'r>,
+  // one minus the mask, multiplied with what is under the glyph.
+  // It does not depend on the text color, so it assumes the text is opaque.
+  inverse: Texture<'r>,
+  packer: ShelfPacker,
+}
+
+// where a glyph is in the atlas
+#[derive(Clone, Copy)]
+struct Slot {
+  page: usize,
+  x: u32,
+  y: u32,
+}
+
+pub struct GlyphAtlas<'r, T> {
+  texture_creator: &'r TextureCreator<T>,
+  pages: Vec<Page<'r>>,
+  slots: HashMap<GlyphCacheKey, Slot>,
+  // pixels being uploaded, kept between uploads so they do not allocate
+  opaque: Vec<u8>,
+  inverse: Vec<u8>,
+  // whether the renderer blends premultiplied colors, found out with the first page.
+  // Without it color glyphs are stored with straight alpha and alpha blended.
+  premultiplied: bool,
+  // what the glyphs in the pages were rendered with
+  options: RenderOptions,
+  // the last glyph too big for a page, composited over the background
+  large: ImageBuffer,
+}
+
+impl<'r, T> GlyphAtlas<'r, T> {
+  pub fn new(texture_creator: &'r TextureCreator<T>) -> Self {
+    GlyphAtlas {
+      texture_creator,
+      pages: Vec::new(),
+      slots: HashMap::new(),
+      opaque: Vec::new(),
+      inverse: Vec::new(),
+      premultiplied: false,
+      options: RenderOptions::default(),
+      large: ImageBuffer::new(0, 0, [0; 4]),
+    }
+  }
+
+  fn new_page(&mut self) -> Result<(), String> {
+    let mut mask = self.texture_creator.create_texture_static(PixelFormatEnum::ABGR8888, PAGE_SIZE, PAGE_SIZE)
+      .map_err(|err| err.to_string())?;
+    if self.pages.is_empty() {
+      self.premultiplied = set_premultiplied_blend(&mut mask);
+    }
+    mask.set_blend_mode(BlendMode::Add);
+    let mut inverse = self.texture_creator.create_texture_static(PixelFormatEnum::ABGR8888, PAGE_SIZE, PAGE_SIZE)
+      .map_err(|err| err.to_string())?;
+    inverse.set_blend_mode(BlendMode::Mod);
+    self.pages.push(Page {mask, inverse, packer: ShelfPacker::new(PAGE_SIZE, PAGE_SIZE)});
+    Ok(())
+  }
+
+  // find room for mask in a page and copy it there, None if no page has room
+  fn upload(&mut self, mask: &ImageBuffer) -> Result<Option<Slot>, String> {
+    let (width, height) = (mask.width + PADDING, mask.height + PADDING);
+    let found = self.pages.iter_mut().enumerate()
+      .find_map(|(i, page)| Some((i, page.packer.alloc(width, height)?)));
+    let (page, (x, y)) = match found {
+      Some(found) => found,
+      None => return Ok(None)
+    };
+
+    let rect = Rect::new(x as i32, y as i32, mask.width, mask.height);
+    let pitch = mask.width as usize * 4;
+    self.opaque.clear();
+    if mask.color {
+      // only the mask texture is drawn for a color glyph
+      if self.premultiplied {
+        self.opaque.extend(&mask.data);
+      } else {
+        self.opaque.extend(mask.data.chunks_exact(4).flat_map(unpremultiply));
+      }
+      self.pages[page].mask.update(rect, &self.opaque, pitch).map_err(|err| err.to_string())?;
+      return Ok(Some(Slot {page, x, y}))
+    }
+    self.opaque.extend(mask.data.chunks_exact(4).flat_map(|px| [px[0], px[1], px[2], 255]));
+    self.inverse.clear();
+    self.inverse.extend(mask.data.chunks_exact(4).flat_map(|px| [255 - px[0], 255 - px[1], 255 - px[2], 255]));
+    self.pages[page].mask.update(rect, &self.opaque, pitch).map_err(|err| err.to_string())?;
+    self.pages[page].inverse.update(rect, &self.inverse, pitch).map_err(|err| err.to_string())?;
+    Ok(Some(Slot {page, x, y}))
+  }
+
+  // Pack again every glyph still in the glyph cache, dropping the ones it evicted.
+  // Returns false if they do not fit anymore, then a page has to be added.
+  fn rebuild(&mut self, renderer: &GlyphRenderer) -> Result<bool, String> {
+    for page in &mut self.pages {
+      page.packer.clear();
+    }
+    let keys: Vec<GlyphCacheKey> = self.slots.drain().map(|(key, _)| key).collect();
+    for key in keys {
+      let mask = match renderer.glyph_cache.peek(&key) {
+        Some(mask) => mask,
+        None => continue
+      };
+      match self.upload(mask)? {
+        Some(slot) => {
+          self.slots.insert(key, slot);
+        }
+        None => return Ok(false)
+      }
+    }
+    Ok(true)
+  }
+
+  // add a glyph from the glyph cache that is not in the atlas yet, None if it is too big for a page
+  fn slot(&mut self, renderer: &GlyphRenderer, key: &GlyphCacheKey) -> Result<Option<Slot>, String> {
+    let mask = match renderer.glyph_cache.peek(key) {
+      Some(mask) => mask,
+      None => return Ok(None)
+    };
+    if mask.width + PADDING > PAGE_SIZE || mask.height + PADDING > PAGE_SIZE {
+      return Ok(None)
+    }
+
+    let mut slot = self.upload(mask)?;
+    if slot.is_none() && !self.pages.is_empty() && self.rebuild(renderer)? {
+      slot = self.upload(mask)?;
+    }
+    if slot.is_none() {
+      self.new_page()?;
+      slot = self.upload(mask)?;
+    }
+    if let Some(slot) = slot {
+      self.slots.insert(key.clone(), slot);
+    }
+    Ok(slot)
+  }
+
+  // draw a glyph too big for a page at dst, in a texture made for it
+  #[allow(clippy::too_many_arguments)]
+  fn draw_large<R: RenderTarget>(
+    &mut self,
+    canvas: &mut Canvas<R>,
+    renderer: &mut GlyphRenderer,
+    font: swash::FontRef,
+    size: FontSize,
+    glyph: swash::GlyphId,
+    colors: ([u8; 4], [u8; 4]),
+    dst: Rect,
+  ) -> Result<(), String> {
+    let (textcolor, background) = colors;
+    renderer.render_glyph(font, size, glyph, textcolor, background, &mut self.large);
+    let mut texture = self.texture_creator.create_texture_static(PixelFormatEnum::ABGR8888, self.large.width, self.large.height)
+      .map_err(|err| err.to_string())?;
+    texture.update(None, &self.large.data, self.large.width as usize * 4).map_err(|err| err.to_string())?;
+    texture.set_blend_mode(BlendMode::None);
+    canvas.copy(&texture, None, dst)
+  }
+
+  // Copy a run of shaped glyphs to the canvas, origin is the x and baseline the run starts at.
+  // colors are the text color and the background under the run, which only glyphs too big for a page are drawn over.
+  // The glyphs are rendered into the glyph cache of renderer first if they are not in it.
+  #[allow(clippy::too_many_arguments)]
+  pub fn draw_run<R: RenderTarget>(
+    &mut self,
+    canvas: &mut Canvas<R>,
+    renderer: &mut GlyphRenderer,
+    font: swash::FontRef,
+    size: FontSize,
+    glyphs: &[PositionedGlyph],
+    colors: ([u8; 4], [u8; 4]),
+    origin: (f32, f32),
+  ) -> Result<(), String> {
+    let textcolor = colors.0;
+    // glyphs rendered with other options are not drawn anymore, so they give up their room
+    if renderer.render_options() != self.options {
+      self.options = renderer.render_options();
+      self.slots.clear();
+      for page in &mut self.pages {
+        page.packer.clear();
+      }
+    }
+
+    let (x, baseline) = origin;
+    for glyph in glyphs {
+      let (pixel, bin) = subpixel_position(x + glyph.x);
+      let key = renderer.cache_key(font, size, glyph.id, bin);
+      // looking the mask up keeps the glyph recently used in the cache
+      let mask = renderer.glyph_mask(font, size, glyph.id, bin);
+      if mask.width == 0 || mask.height == 0 {
+        continue
+      }
+      let (width, height, left, top, color) = (mask.width, mask.height, mask.left, mask.top, mask.color);
+      let dst = Rect::new(pixel + left, (baseline - glyph.y).round() as i32 - top, width, height);
+      let slot = match self.slots.get(&key) {
+        Some(slot) => *slot,
+        None => match self.slot(renderer, &key)? {
+          Some(slot) => slot,
+          None => {
+            self.draw_large(canvas, renderer, font, size, glyph.id, colors, dst)?;
+            continue
+          }
+        }
+      };
+
+      let src = Rect::new(slot.x as i32, slot.y as i32, width, height);
+      let page = &mut self.pages[slot.page];
+      if color {
+        page.mask.set_color_mod(255, 255, 255);
+        page.mask.set_alpha_mod(255);
+        if !self.premultiplied || !set_premultiplied_blend(&mut page.mask) {
+          page.mask.set_blend_mode(BlendMode::Blend);
+        }
+        canvas.copy(&page.mask, src, dst)?;
+        page.mask.set_blend_mode(BlendMode::Add);
+        continue
+      }
+      canvas.copy(&page.inverse, src, dst)?;
+      page.mask.set_color_mod(textcolor[0], textcolor[1], textcolor[2]);
+      page.mask.set_alpha_mod(textcolor[3]);
+      canvas.copy(&page.mask, src, dst)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use sdl2::pixels::Color;
+  use sdl2::surface::Surface;
+  use super::super::Antialias;
+
+  // a mask covering all of a width by height glyph
+  fn full_mask(width: u32, height: u32) -> ImageBuffer {
+    ImageBuffer {left: 0, top: height as i32, ..ImageBuffer::new(width, height, [255; 4])}
+  }
+
+  #[test]
+  fn test_shelf_packer() {
+    let mut packer = ShelfPacker::new(10, 10);
+    assert_eq!(packer.alloc(4, 3), Some((0, 0)));
+    assert_eq!(packer.alloc(4, 2), Some((4, 0)));
+    // too wide for the first shelf, so a new one starts under it
+    assert_eq!(packer.alloc(4, 3), Some((0, 3)));
+    // the shelf wasting the least height
+    assert_eq!(packer.alloc(2, 3), Some((8, 0)));
+    assert_eq!(packer.alloc(11, 1), None);
+    assert_eq!(packer.alloc(5, 5), None);
+    assert_eq!(packer.alloc(5, 4), Some((0, 6)));
+    assert_eq!(packer.alloc(5, 4), Some((5, 6)));
+    assert_eq!(packer.alloc(1, 1), Some((4, 3)));
+    assert_eq!(packer.alloc(3, 4), None);
+
+    packer.clear();
+    assert_eq!(packer.alloc(10, 10), Some((0, 0)));
+  }
+
+  #[test]
+  fn test_draw_run() {
+    // the software renderer, which needs no window
+    let mut canvas = Surface::new(8, 4, PixelFormatEnum::ABGR8888).unwrap().into_canvas().unwrap();
+    let texture_creator = canvas.texture_creator();
+    let mut atlas = GlyphAtlas::new(&texture_creator);
+    let mut renderer = GlyphRenderer::default();
+    renderer.set_cache_budget(usize::MAX);
+    // cached glyphs never touch the font data
+    let font = swash::FontRef {data: &[], offset: 0, key: swash::CacheKey::new()};
+    let glyph = |id| PositionedGlyph {id, x: 0., y: 0.};
+
+    renderer.glyph_cache.put(renderer.cache_key(font, 12, 1, 0), full_mask(2, 2));
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+    canvas.clear();
+    atlas.draw_run(&mut canvas, &mut renderer, font, 12, &[glyph(1)], ([255, 0, 0, 255], [0, 0, 0, 255]), (1., 2.)).unwrap();
+    let pixels = canvas.read_pixels(None, PixelFormatEnum::ABGR8888).unwrap();
+    let pixel = |x: usize, y: usize| &pixels[(y * 8 + x) * 4..(y * 8 + x) * 4 + 3];
+    for (x, y) in [(1, 0), (2, 0), (1, 1), (2, 1)] {
+      assert_eq!(pixel(x, y), [255, 0, 0]);
+    }
+    for (x, y) in [(0, 0), (3, 0), (1, 2)] {
+      assert_eq!(pixel(x, y), [0, 0, 0]);
+    }
+
+    // two of these fill the page of an empty atlas, with the padding
+    let mut atlas = GlyphAtlas::new(&texture_creator);
+    let big = full_mask((PAGE_SIZE - PADDING) / 2, PAGE_SIZE - PADDING);
+    for id in 2..7 {
+      if id == 5 {
+        // the first page is full of glyphs that are all still cached, so a page was added
+        assert_eq!(atlas.pages.len(), 2);
+        // once they leave the glyph cache the pages are packed again instead
+        for id in 2..4 {
+          renderer.glyph_cache.pop(&renderer.cache_key(font, 12, id, 0));
+        }
+      }
+      renderer.glyph_cache.put(renderer.cache_key(font, 12, id, 0), big.clone());
+      atlas.draw_run(&mut canvas, &mut renderer, font, 12, &[glyph(id)], ([255, 0, 0, 255], [0, 0, 0, 255]), (0., 0.)).unwrap();
+    }
+    assert_eq!(atlas.pages.len(), 2);
+    let mut cached: Vec<u16> = atlas.slots.keys().map(|key| key.2).collect();
+    cached.sort_unstable();
+    assert_eq!(cached, vec![4, 5, 6]);
+
+    // other options render the glyphs again, in the room of the old ones
+    renderer.set_render_options(RenderOptions {antialias: Antialias::Grayscale, ..RenderOptions::default()});
+    renderer.glyph_cache.put(renderer.cache_key(font, 12, 4, 0), big.clone());
+    atlas.draw_run(&mut canvas, &mut renderer, font, 12, &[glyph(4)], ([255, 0, 0, 255], [0, 0, 0, 255]), (0., 0.)).unwrap();
+    assert_eq!(atlas.pages.len(), 2);
+    assert_eq!(atlas.slots.len(), 1);
+    assert!(atlas.slots.contains_key(&renderer.cache_key(font, 12, 4, 0)));
+  }
+
+  #[test]
+  fn test_draw_large() {
+    let mut canvas = Surface::new(8, 4, PixelFormatEnum::ABGR8888).unwrap().into_canvas().unwrap();
+    let texture_creator = canvas.texture_creator();
+    let mut atlas = GlyphAtlas::new(&texture_creator);
+    let mut renderer = GlyphRenderer::default();
+    let font = swash::FontRef {data: &[], offset: 0, key: swash::CacheKey::new()};
+
+    // wider than a page, so it is composited over the background instead
+    renderer.glyph_cache.put(renderer.cache_key(font, 12, 1, 0), full_mask(PAGE_SIZE, 2));
+    canvas.set_draw_color(Color::RGBA(0, 0, 255, 255));
+    canvas.clear();
+    let glyph = PositionedGlyph {id: 1, x: 0., y: 0.};
+    atlas.draw_run(&mut canvas, &mut renderer, font, 12, &[glyph], ([255, 0, 0, 255], [0, 255, 0, 255]), (0., 3.)).unwrap();
+    assert!(atlas.slots.is_empty());
+    let pixels = canvas.read_pixels(None, PixelFormatEnum::ABGR8888).unwrap();
+    let pixel = |x: usize, y: usize| &pixels[(y * 8 + x) * 4..(y * 8 + x) * 4 + 3];
+    // the fixed point compositing rounds full coverage down by one
+    assert_eq!(pixel(0, 1), [254, 0, 0]);
+    assert_eq!(pixel(7, 2), [254, 0, 0]);
+    assert_eq!(pixel(0, 0), [0, 0, 255]);
+    assert_eq!(pixel(0, 3), [0, 0, 255]);
+  }
+}
@@ -0,0 +1,448 @@
+// Compositing subpixel glyph masks over a background.
+// The background is either one color, written over the mask in place (apply_mask), or the pixels
+// already in a destination buffer (blend_mask).
+// The scalar path is the reference, the simd paths process 4 or 8 pixels at a time and must give
+// bit-identical results, with the pixels left over at the end done by the next narrower path.
+
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+use core::arch::x86_64::*;
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+use core::arch::aarch64::*;
+
+// signatures shared by every path, so the one to use can be picked at runtime
+pub type MaskFn = fn(&mut [u8], [u8; 4], [u8; 4]);
+pub type BlendFn = fn(&mut [u8], &[u8], [u8; 4]);
+
+/*
+  https://github.com/servo/webrender/blob/master/webrender/doc/text-rendering.md
+  Calculate the following equation using fixed point:
+    textcolor * maskcolor + (1.0 - textalpha * maskcolor) * destcolor * 255.0
+
+    TODO: cache based on destcolor and alpha so that the right side of the equation can be completely cached
+    (textcolor * maskcolor) + (destcolor * (0xff00 - ((textalpha * maskcolor) >> 8))) >> 8
+
+  textalpha * maskcolor is at most 0xfe01, so the subtraction never wraps.
+  The sum can overflow 16 bits when the text color is brighter than its alpha allows, so it saturates
+  like the simd adds do.
+*/
+pub fn composite_color(textcolor: u8, textalpha: u8, maskcolor: u8, destcolor: u8) -> u8 {
+  let text = textcolor as u16 * maskcolor as u16;
+  let dest = destcolor as u16 * ((0xff00 - textalpha as u16 * maskcolor as u16) >> 8);
+  (text.saturating_add(dest) >> 8) as u8
+}
+
+// one ABGR pixel, the alpha channel is blended with the text alpha as its color
+fn composite_pixel(out: &mut [u8], mask: [u8; 4], textcolor: [u8; 4], destcolor: [u8; 4]) {
+  out[3] = composite_color(textcolor[3], textcolor[3], mask[3], destcolor[3]);
+
+  out[0] = composite_color(textcolor[0], textcolor[3], mask[0], destcolor[0]);
+  out[1] = composite_color(textcolor[1], textcolor[3], mask[1], destcolor[1]);
+  out[2] = composite_color(textcolor[2], textcolor[3], mask[2], destcolor[2]);
+}
+
+pub fn apply_mask_scalar(data: &mut [u8], textcolor: [u8; 4], destcolor: [u8; 4]) {
+  for p in data.chunks_exact_mut(4) {
+    let mask = [p[0], p[1], p[2], p[3]];
+    composite_pixel(p, mask, textcolor, destcolor);
+  }
+}
+
+pub fn blend_mask_scalar(dest: &mut [u8], mask: &[u8], textcolor: [u8; 4]) {
+  for (d, m) in dest.chunks_exact_mut(4).zip(mask.chunks_exact(4)) {
+    let destcolor = [d[0], d[1], d[2], d[3]];
+    composite_pixel(d, [m[0], m[1], m[2], m[3]], textcolor, destcolor);
+  }
+}
+
+// Composite 8 channels of the mask and background widened to 16 bits.
+// The text color holds two pixels to line up with them.
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+#[inline]
+unsafe fn composite_sse2(vsubpx_mask: __m128i, vtext_color: __m128i, vtext_alpha: __m128i, vdest_color: __m128i) -> __m128i {
+  // fixed point representation of one
+  // 0xff00 == -256
+  let fixpt_one = _mm_set1_epi16(-256i16);
+
+  let left = _mm_mullo_epi16(vsubpx_mask, vtext_color);
+
+  let right = _mm_mullo_epi16(vtext_alpha, vsubpx_mask);
+  let right = _mm_sub_epi16(fixpt_one, right);
+  let right = _mm_srli_epi16(right, 8);
+  let right = _mm_mullo_epi16(right, vdest_color);
+
+  _mm_srli_epi16(_mm_adds_epu16(left, right), 8)
+}
+
+// a color repeated for two pixels and widened to 16 bits
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+unsafe fn widen_color_sse2(color: [u8; 4]) -> __m128i {
+  _mm_unpacklo_epi8(_mm_set1_epi32(i32::from_ne_bytes(color)), _mm_setzero_si128())
+}
+
+// sse2 is part of x86_64, so this is always available there
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+pub fn apply_mask_sse2(data: &mut [u8], textcolor: [u8; 4], destcolor: [u8; 4]) {
+  let mut chunks = data.chunks_exact_mut(16);
+  unsafe {
+    let vzero = _mm_setzero_si128();
+    let vtext_color = widen_color_sse2(textcolor);
+    let vdest_color = widen_color_sse2(destcolor);
+    let vtext_alpha = _mm_set1_epi16(textcolor[3] as i16);
+
+    for px in &mut chunks {
+      let vmask = _mm_loadu_si128(px.as_ptr() as *const __m128i);
+      let results_lo = composite_sse2(_mm_unpacklo_epi8(vmask, vzero), vtext_color, vtext_alpha, vdest_color);
+      let results_hi = composite_sse2(_mm_unpackhi_epi8(vmask, vzero), vtext_color, vtext_alpha, vdest_color);
+      // every lane is at most 0xff after the shift, so the saturating pack only narrows
+      let results = _mm_packus_epi16(results_lo, results_hi);
+      _mm_storeu_si128(px.as_mut_ptr() as *mut __m128i, results);
+    }
+  }
+  apply_mask_scalar(chunks.into_remainder(), textcolor, destcolor);
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+pub fn blend_mask_sse2(dest: &mut [u8], mask: &[u8], textcolor: [u8; 4]) {
+  let len = dest.len().min(mask.len()) / 16 * 16;
+  let (dest, dest_rest) = dest.split_at_mut(len);
+  let (mask, mask_rest) = mask.split_at(len);
+  unsafe {
+    let vzero = _mm_setzero_si128();
+    let vtext_color = widen_color_sse2(textcolor);
+    let vtext_alpha = _mm_set1_epi16(textcolor[3] as i16);
+
+    for (d, m) in dest.chunks_exact_mut(16).zip(mask.chunks_exact(16)) {
+      let vmask = _mm_loadu_si128(m.as_ptr() as *const __m128i);
+      let vdest = _mm_loadu_si128(d.as_ptr() as *const __m128i);
+      let results_lo = composite_sse2(
+        _mm_unpacklo_epi8(vmask, vzero), vtext_color, vtext_alpha, _mm_unpacklo_epi8(vdest, vzero)
+      );
+      let results_hi = composite_sse2(
+        _mm_unpackhi_epi8(vmask, vzero), vtext_color, vtext_alpha, _mm_unpackhi_epi8(vdest, vzero)
+      );
+      _mm_storeu_si128(d.as_mut_ptr() as *mut __m128i, _mm_packus_epi16(results_lo, results_hi));
+    }
+  }
+  blend_mask_scalar(dest_rest, mask_rest, textcolor);
+}
+
+// Same as composite_sse2 on both 128 bit lanes. The unpacks and the pack stay inside of their lane,
+// so the pixels come back out in order.
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn composite_avx2(vsubpx_mask: __m256i, vtext_color: __m256i, vtext_alpha: __m256i, vdest_color: __m256i) -> __m256i {
+  let fixpt_one = _mm256_set1_epi16(-256i16);
+
+  let left = _mm256_mullo_epi16(vsubpx_mask, vtext_color);
+
+  let right = _mm256_mullo_epi16(vtext_alpha, vsubpx_mask);
+  let right = _mm256_sub_epi16(fixpt_one, right);
+  let right = _mm256_srli_epi16(right, 8);
+  let right = _mm256_mullo_epi16(right, vdest_color);
+
+  _mm256_srli_epi16(_mm256_adds_epu16(left, right), 8)
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+#[target_feature(enable = "avx2")]
+unsafe fn widen_color_avx2(color: [u8; 4]) -> __m256i {
+  _mm256_unpacklo_epi8(_mm256_set1_epi32(i32::from_ne_bytes(color)), _mm256_setzero_si256())
+}
+
+// Only reachable through Compositor::detect, which checks the cpu supports avx2 first
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+fn apply_mask_avx2(data: &mut [u8], textcolor: [u8; 4], destcolor: [u8; 4]) {
+  #[target_feature(enable = "avx2")]
+  unsafe fn kernel(data: &mut [u8], textcolor: [u8; 4], destcolor: [u8; 4]) -> &mut [u8] {
+    let mut chunks = data.chunks_exact_mut(32);
+    let vzero = _mm256_setzero_si256();
+    let vtext_color = widen_color_avx2(textcolor);
+    let vdest_color = widen_color_avx2(destcolor);
+    let vtext_alpha = _mm256_set1_epi16(textcolor[3] as i16);
+
+    for px in &mut chunks {
+      let vmask = _mm256_loadu_si256(px.as_ptr() as *const __m256i);
+      let results_lo = composite_avx2(_mm256_unpacklo_epi8(vmask, vzero), vtext_color, vtext_alpha, vdest_color);
+      let results_hi = composite_avx2(_mm256_unpackhi_epi8(vmask, vzero), vtext_color, vtext_alpha, vdest_color);
+      _mm256_storeu_si256(px.as_mut_ptr() as *mut __m256i, _mm256_packus_epi16(results_lo, results_hi));
+    }
+    chunks.into_remainder()
+  }
+
+  let rest = unsafe { kernel(data, textcolor, destcolor) };
+  apply_mask_sse2(rest, textcolor, destcolor);
+}
+
+// Only reachable through Compositor::detect, which checks the cpu supports avx2 first
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+fn blend_mask_avx2(dest: &mut [u8], mask: &[u8], textcolor: [u8; 4]) {
+  #[target_feature(enable = "avx2")]
+  unsafe fn kernel(dest: &mut [u8], mask: &[u8], textcolor: [u8; 4]) {
+    let vzero = _mm256_setzero_si256();
+    let vtext_color = widen_color_avx2(textcolor);
+    let vtext_alpha = _mm256_set1_epi16(textcolor[3] as i16);
+
+    for (d, m) in dest.chunks_exact_mut(32).zip(mask.chunks_exact(32)) {
+      let vmask = _mm256_loadu_si256(m.as_ptr() as *const __m256i);
+      let vdest = _mm256_loadu_si256(d.as_ptr() as *const __m256i);
+      let results_lo = composite_avx2(
+        _mm256_unpacklo_epi8(vmask, vzero), vtext_color, vtext_alpha, _mm256_unpacklo_epi8(vdest, vzero)
+      );
+      let results_hi = composite_avx2(
+        _mm256_unpackhi_epi8(vmask, vzero), vtext_color, vtext_alpha, _mm256_unpackhi_epi8(vdest, vzero)
+      );
+      _mm256_storeu_si256(d.as_mut_ptr() as *mut __m256i, _mm256_packus_epi16(results_lo, results_hi));
+    }
+  }
+
+  let len = dest.len().min(mask.len()) / 32 * 32;
+  let (dest, dest_rest) = dest.split_at_mut(len);
+  let (mask, mask_rest) = mask.split_at(len);
+  unsafe { kernel(dest, mask, textcolor) };
+  blend_mask_sse2(dest_rest, mask_rest, textcolor);
+}
+
+// Composite 8 channels of the mask and background widened to 16 bits.
+// The text color holds two pixels to line up with them.
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+#[inline]
+unsafe fn composite_neon(vsubpx_mask: uint16x8_t, vtext_color: uint16x8_t, vtext_alpha: uint16x8_t, vdest_color: uint16x8_t) -> uint16x8_t {
+  let fixpt_one = vdupq_n_u16(0xff00);
+
+  let left = vmulq_u16(vsubpx_mask, vtext_color);
+
+  let right = vsubq_u16(fixpt_one, vmulq_u16(vtext_alpha, vsubpx_mask));
+  let right = vmulq_u16(vshrq_n_u16::<8>(right), vdest_color);
+
+  vshrq_n_u16::<8>(vqaddq_u16(left, right))
+}
+
+// a color repeated for two pixels and widened to 16 bits
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+unsafe fn widen_color_neon(color: [u8; 4]) -> uint16x8_t {
+  vmovl_u8(vreinterpret_u8_u32(vdup_n_u32(u32::from_ne_bytes(color))))
+}
+
+// neon is part of aarch64, so this is always available there
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+pub fn apply_mask_neon(data: &mut [u8], textcolor: [u8; 4], destcolor: [u8; 4]) {
+  let mut chunks = data.chunks_exact_mut(16);
+  unsafe {
+    let vtext_color = widen_color_neon(textcolor);
+    let vdest_color = widen_color_neon(destcolor);
+    let vtext_alpha = vdupq_n_u16(textcolor[3] as u16);
+
+    for px in &mut chunks {
+      let vmask = vld1q_u8(px.as_ptr());
+      let results_lo = composite_neon(vmovl_u8(vget_low_u8(vmask)), vtext_color, vtext_alpha, vdest_color);
+      let results_hi = composite_neon(vmovl_high_u8(vmask), vtext_color, vtext_alpha, vdest_color);
+      vst1q_u8(px.as_mut_ptr(), vcombine_u8(vmovn_u16(results_lo), vmovn_u16(results_hi)));
+    }
+  }
+  apply_mask_scalar(chunks.into_remainder(), textcolor, destcolor);
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+pub fn blend_mask_neon(dest: &mut [u8], mask: &[u8], textcolor: [u8; 4]) {
+  let len = dest.len().min(mask.len()) / 16 * 16;
+  let (dest, dest_rest) = dest.split_at_mut(len);
+  let (mask, mask_rest) = mask.split_at(len);
+  unsafe {
+    let vtext_color = widen_color_neon(textcolor);
+    let vtext_alpha = vdupq_n_u16(textcolor[3] as u16);
+
+    for (d, m) in dest.chunks_exact_mut(16).zip(mask.chunks_exact(16)) {
+      let vmask = vld1q_u8(m.as_ptr());
+      let vdest = vld1q_u8(d.as_ptr());
+      let results_lo = composite_neon(
+        vmovl_u8(vget_low_u8(vmask)), vtext_color, vtext_alpha, vmovl_u8(vget_low_u8(vdest))
+      );
+      let results_hi = composite_neon(vmovl_high_u8(vmask), vtext_color, vtext_alpha, vmovl_high_u8(vdest));
+      vst1q_u8(d.as_mut_ptr(), vcombine_u8(vmovn_u16(results_lo), vmovn_u16(results_hi)));
+    }
+  }
+  blend_mask_scalar(dest_rest, mask_rest, textcolor);
+}
+
+// one channel of a premultiplied color pixel drawn over dest
+fn over(color: u8, alpha: u8, dest: u8) -> u8 {
+  color.saturating_add((dest as u16 * (255 - alpha) as u16 / 255) as u8)
+}
+
+// Color glyphs keep their own colors, so the text color is not used. They are rare enough to not need simd.
+pub fn apply_color(data: &mut [u8], destcolor: [u8; 4]) {
+  for p in data.chunks_exact_mut(4) {
+    for i in 0..4 {
+      p[i] = over(p[i], p[3], destcolor[i]);
+    }
+  }
+}
+
+pub fn blend_color(dest: &mut [u8], color: &[u8], _textcolor: [u8; 4]) {
+  for (d, c) in dest.chunks_exact_mut(4).zip(color.chunks_exact(4)) {
+    for i in 0..4 {
+      d[i] = over(c[i], c[3], d[i]);
+    }
+  }
+}
+
+// the fastest path this target was built with, see Compositor::detect for one using what the cpu supports
+pub fn apply_mask(data: &mut [u8], textcolor: [u8; 4], destcolor: [u8; 4]) {
+  #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+  return apply_mask_sse2(data, textcolor, destcolor);
+
+  #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+  return apply_mask_neon(data, textcolor, destcolor);
+
+  #[allow(unreachable_code)]
+  apply_mask_scalar(data, textcolor, destcolor)
+}
+
+pub fn blend_mask(dest: &mut [u8], mask: &[u8], textcolor: [u8; 4]) {
+  #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+  return blend_mask_sse2(dest, mask, textcolor);
+
+  #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+  return blend_mask_neon(dest, mask, textcolor);
+
+  #[allow(unreachable_code)]
+  blend_mask_scalar(dest, mask, textcolor)
+}
+
+// The compositing paths picked for the cpu running this
+#[derive(Clone, Copy)]
+pub struct Compositor {
+  pub apply_mask: MaskFn,
+  pub blend_mask: BlendFn,
+}
+
+impl Compositor {
+  // The fastest paths on the cpu running this, falling back to sse2 and then scalar.
+  // Detection is not free, so it is done once and the result kept.
+  pub fn detect() -> Self {
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    {
+      if is_x86_feature_detected!("avx2") {
+        return Compositor {apply_mask: apply_mask_avx2, blend_mask: blend_mask_avx2}
+      }
+    }
+    Compositor {apply_mask, blend_mask}
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // xorshift, so the tests are random but repeatable
+  struct Rng(u64);
+
+  impl Rng {
+    fn next(&mut self) -> u8 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      (self.0 >> 24) as u8
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+      (0..len).map(|_| self.next()).collect()
+    }
+
+    fn color(&mut self) -> [u8; 4] {
+      [self.next(), self.next(), self.next(), self.next()]
+    }
+  }
+
+  // the extremes of every channel, then random colors
+  fn colors(rng: &mut Rng, pixels: usize) -> ([u8; 4], [u8; 4]) {
+    match pixels % 4 {
+      0 => ([255; 4], [0; 4]),
+      1 => ([0; 4], [255; 4]),
+      _ => (rng.color(), rng.color()),
+    }
+  }
+
+  // run a path on random masks and colors and compare it to the scalar path
+  fn check(path: MaskFn) {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for pixels in 0..200 {
+      let mask = rng.bytes(pixels * 4);
+      let (textcolor, destcolor) = colors(&mut rng, pixels);
+
+      let mut expected = mask.clone();
+      apply_mask_scalar(&mut expected, textcolor, destcolor);
+      let mut result = mask.clone();
+      path(&mut result, textcolor, destcolor);
+      assert_eq!(result, expected, "{} pixels, text {:?}, dest {:?}", pixels, textcolor, destcolor);
+    }
+  }
+
+  fn check_blend(path: BlendFn) {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for pixels in 0..200 {
+      let mask = rng.bytes(pixels * 4);
+      let dest = rng.bytes(pixels * 4);
+      let (textcolor, _) = colors(&mut rng, pixels);
+
+      let mut expected = dest.clone();
+      blend_mask_scalar(&mut expected, &mask, textcolor);
+      let mut result = dest.clone();
+      path(&mut result, &mask, textcolor);
+      assert_eq!(result, expected, "{} pixels, text {:?}", pixels, textcolor);
+    }
+  }
+
+  #[test]
+  fn test_composite_color() {
+    // an empty mask leaves the background and a full one the text color, rounded down
+    assert_eq!(composite_color(30, 255, 0, 230), 229);
+    assert_eq!(composite_color(30, 255, 255, 230), 29);
+    // a transparent bright text color saturates instead of wrapping
+    assert_eq!(composite_color(255, 0, 255, 255), 255);
+  }
+
+  #[test]
+  fn test_apply_mask() {
+    check(apply_mask);
+    check(Compositor::detect().apply_mask);
+  }
+
+  #[test]
+  fn test_blend_mask() {
+    // over a single color blending is the same as applying the mask
+    let mask = [10, 200, 30, 200, 255, 255, 255, 255];
+    let mut applied = mask;
+    apply_mask_scalar(&mut applied, [30, 30, 30, 255], [230, 230, 230, 255]);
+    let mut blended = [230, 230, 230, 255, 230, 230, 230, 255];
+    blend_mask_scalar(&mut blended, &mask, [30, 30, 30, 255]);
+    assert_eq!(blended, applied);
+
+    check_blend(blend_mask);
+    check_blend(Compositor::detect().blend_mask);
+  }
+
+  #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+  #[test]
+  fn test_sse2() {
+    check(apply_mask_sse2);
+    check_blend(blend_mask_sse2);
+  }
+
+  #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+  #[test]
+  fn test_avx2() {
+    if is_x86_feature_detected!("avx2") {
+      check(apply_mask_avx2);
+      check_blend(blend_mask_avx2);
+    }
+  }
+
+  #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+  #[test]
+  fn test_neon() {
+    check(apply_mask_neon);
+    check_blend(blend_mask_neon);
+  }
+}
@@ -0,0 +1,202 @@
+// Font fallback, which draws the characters the primary font has no glyph for, like CJK, emoji and
+// math symbols, with the first font of a chain that has one.
+// Text is split into runs by font and every run is shaped with its own font, so its glyphs are cached
+// under that font. The primary font sets the grid: clusters from other fonts are centered in as many
+// of its cells as their characters are wide, so columns line up like they do without fallback.
+
+use std::ops::Range;
+
+use swash::Charmap;
+use swash::shape::ShapeContext;
+use swash::text::{Codepoint, Script};
+
+use crate::mark::char_width;
+
+use super::{FontData, PositionedGlyph};
+
+// The primary font followed by the fonts tried for characters it does not have
+pub struct FontChain {
+  fonts: Vec<FontData>,
+}
+
+// Glyphs of shaped text, with the font of the chain each run of them is drawn with
+#[derive(Default)]
+pub struct ShapedText {
+  pub glyphs: Vec<PositionedGlyph>,
+  // font index and range of glyphs
+  runs: Vec<(usize, Range<usize>)>,
+}
+
+impl ShapedText {
+  pub fn clear(&mut self) {
+    self.glyphs.clear();
+    self.runs.clear();
+  }
+
+  fn push(&mut self, font: usize, glyph: PositionedGlyph) {
+    let index = self.glyphs.len();
+    self.glyphs.push(glyph);
+    match self.runs.last_mut() {
+      Some((last, range)) if *last == font => range.end = index + 1,
+      _ => self.runs.push((font, index..index + 1)),
+    }
+  }
+
+  // font index and glyphs of every run, in order
+  pub fn runs(&self) -> impl Iterator<Item = (usize, &[PositionedGlyph])> + '_ {
+    self.runs.iter().map(move |(font, range)| (*font, &self.glyphs[range.clone()]))
+  }
+}
+
+// Characters that stay in the run before them, so a cluster is never split between fonts.
+// These are the zero width ones: combining marks, joiners and variation selectors.
+fn joins_previous(ch: char) -> bool {
+  char_width(ch) == 0
+}
+
+// split text into runs of characters font_for gives the same font
+fn split_runs<F: Fn(char) -> usize>(text: &str, font_for: F) -> Vec<(usize, Range<usize>)> {
+  let mut runs: Vec<(usize, Range<usize>)> = Vec::new();
+  for (i, ch) in text.char_indices() {
+    let end = i + ch.len_utf8();
+    match runs.last_mut() {
+      Some((_, range)) if joins_previous(ch) => range.end = end,
+      Some((font, range)) if *font == font_for(ch) => range.end = end,
+      _ => runs.push((font_for(ch), i..end)),
+    }
+  }
+  runs
+}
+
+impl FontChain {
+  pub fn new(primary: FontData) -> Self {
+    FontChain {fonts: vec![primary]}
+  }
+
+  // try font for characters none of the fonts before it have
+  pub fn push(&mut self, font: FontData) {
+    self.fonts.push(font);
+  }
+
+  pub fn primary(&self) -> &FontData {
+    &self.fonts[0]
+  }
+
+  pub fn font(&self, index: usize) -> &FontData {
+    &self.fonts[index]
+  }
+
+  pub fn len(&self) -> usize {
+    self.fonts.len()
+  }
+
+  // the first font with a glyph for ch, the primary font if none has one
+  fn font_for(charmaps: &[Charmap], ch: char) -> usize {
+    if ch.is_control() {
+      return 0
+    }
+    charmaps.iter().position(|charmap| charmap.map(ch) != 0).unwrap_or(0)
+  }
+
+  // font index and byte range of the runs of text that are drawn with the same font
+  pub fn runs(&self, text: &str) -> Vec<(usize, Range<usize>)> {
+    let charmaps: Vec<Charmap> = self.fonts.iter().map(|font| font.charmap()).collect();
+    split_runs(text, |ch| Self::font_for(&charmaps, ch))
+  }
+
+  // Shape text with the fonts of the chain and call f with the font index, the byte offset of every cluster,
+  // its glyphs, the x its glyphs are offset by and its advance, in order.
+  pub fn shape_with<F>(&self, ctx: &mut ShapeContext, size: f32, text: &str, mut f: F)
+  where F: FnMut(usize, usize, &[swash::shape::cluster::Glyph], f32, f32) {
+    let primary = self.primary().as_ref();
+    let cell = primary.glyph_metrics(&[]).scale(size).advance_width(primary.charmap().map(' '));
+    for (font, range) in self.runs(text) {
+      let run = &text[range.clone()];
+      let script = run.chars()
+        .map(|ch| ch.script())
+        .find(|script| !matches!(script, Script::Common | Script::Inherited | Script::Unknown))
+        .unwrap_or(Script::Latin);
+      let mut shaper = ctx.builder(self.fonts[font].as_ref())
+        .script(script)
+        .size(size)
+        .build();
+      shaper.add_str(run);
+      shaper.shape_with(|cluster| {
+        let source = cluster.source.start as usize..cluster.source.end as usize;
+        let advance: f32 = cluster.glyphs.iter().map(|glyph| glyph.advance).sum();
+        let missing = cluster.glyphs.iter().any(|glyph| glyph.id == 0);
+        if font == 0 && !missing {
+          f(font, range.start + source.start, cluster.glyphs, 0., advance);
+          return
+        }
+        // on the grid of the primary font, like FontMetrics measures it
+        let cells = run[source.clone()].chars().map(char_width).sum::<usize>().max(1) as f32;
+        f(font, range.start + source.start, cluster.glyphs, (cells * cell - advance) / 2., cells * cell);
+      });
+    }
+  }
+
+  // Shape text starting at x and append its glyphs, tabs go to the next tab stop after x.
+  // Returns the x after the text.
+  pub fn shape(&self, ctx: &mut ShapeContext, size: f32, tab: f32, text: &str, x: f32, shaped: &mut ShapedText) -> f32 {
+    let mut pen = x;
+    self.shape_with(ctx, size, text, |font, start, glyphs, offset, advance| {
+      if text.as_bytes()[start] == b'\t' && tab > 0. {
+        pen = x + (((pen - x) / tab).floor() + 1.) * tab;
+        return
+      }
+      let mut glyph_pen = pen + offset;
+      for glyph in glyphs {
+        shaped.push(font, PositionedGlyph {id: glyph.id, x: glyph_pen + glyph.x, y: glyph.y});
+        glyph_pen += glyph.advance;
+      }
+      pen += advance;
+    });
+    pen
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use super::super::registry::bundled_font;
+
+  #[test]
+  fn test_runs() {
+    // latin in the primary font, CJK in the first fallback and emoji in the second
+    let font_for = |ch: char| match ch as u32 {
+      0x4e00..=0x9fff => 1,
+      0x1f300..=0x1f9ff => 2,
+      _ => 0,
+    };
+    assert_eq!(split_runs("ab漢字c", font_for), vec![(0, 0..2), (1, 2..8), (0, 8..9)]);
+    // a joiner and a variation selector stay with the emoji they are part of
+    assert_eq!(split_runs("a👩\u{200d}💻\u{fe0f}", font_for), vec![(0, 0..1), (2, 1..15)]);
+    // a combining mark stays with the letter it is on
+    assert_eq!(split_runs("漢\u{301}", font_for), vec![(1, 0..5)]);
+    assert!(split_runs("", font_for).is_empty());
+
+    // nothing has the CJK characters, so they stay with the primary font
+    let mut chain = FontChain::new(bundled_font());
+    chain.push(bundled_font());
+    assert_eq!(chain.runs("a漢字b"), vec![(0, 0..8)]);
+  }
+
+  #[test]
+  fn test_grid() {
+    let chain = FontChain::new(bundled_font());
+    let mut ctx = ShapeContext::new();
+    let mut shaped = ShapedText::default();
+    let end = chain.shape(&mut ctx, 10., 0., "ab", 0., &mut shaped);
+    let cell = end / 2.;
+    assert_eq!(shaped.glyphs.len(), 2);
+    assert_eq!(shaped.glyphs[1].x, cell);
+
+    // a wide character with no glyph takes two cells
+    shaped.clear();
+    let end = chain.shape(&mut ctx, 10., 0., "a漢b", 0., &mut shaped);
+    assert_eq!(end, 4. * cell);
+    assert_eq!(shaped.glyphs[2].x, 3. * cell);
+    assert_eq!(shaped.runs().map(|(font, glyphs)| (font, glyphs.len())).collect::<Vec<_>>(), vec![(0, 3)]);
+  }
+}
@@ -2,14 +2,27 @@ extern crate sdl2;
 extern crate swash;
 extern crate lru;
 
-use::core::arch::x86_64::*;
+mod composite;
+mod atlas;
+pub use atlas::GlyphAtlas;
+mod registry;
+pub use registry::{FontRegistry, FontRequest};
+mod fallback;
+pub use fallback::{FontChain, ShapedText};
+mod options;
+pub use options::{Antialias, RenderOptions};
+use composite::{BlendFn, Compositor};
+
+use std::collections::HashSet;
 
 use swash::{Attributes, CacheKey, Charmap, FontRef, scale};
 
-// max number of glyphs in glyph cache
-const GLYPH_CACHE_SIZE: usize = 256;
-// TODO: profile latency of creating a scaler and the memory usage, and maybe scale this based on the number of fonts
-const SCALER_CACHE_SIZE: usize = 16;
+// bytes the masks in the glyph cache may take up, a 72px emoji costs as much as hundreds of periods
+const GLYPH_CACHE_BUDGET: usize = 4 << 20;
+// most fonts the scale context keeps scalers for, swash caps it here
+const MAX_SCALERS: usize = 64;
+// fractional x offsets glyphs are rendered at, so spacing keeps the fractional advances of the font
+pub const SUBPIXEL_BINS: u8 = 4;
 
 pub type FontId = swash::CacheKey;
 // pixels per em
@@ -25,9 +38,12 @@ pub struct FontData {
 }
 
 impl FontData {
-  pub fn from_file(path: &str, index: usize) -> Option<Self> {
+  pub fn from_file<P: AsRef<std::path::Path>>(path: P, index: usize) -> Option<Self> {
       // Read the full font file
-      let data = std::fs::read(path).ok()?;
+      Self::from_bytes(std::fs::read(path).ok()?, index)
+  }
+
+  pub fn from_bytes(data: Vec<u8>, index: usize) -> Option<Self> {
       // Create a temporary font reference for the first font in the file.
       // This will do some basic validation, compute the necessary offset
       // and generate a fresh cache key for us.
@@ -62,28 +78,209 @@ impl FontData {
   }
 }
 
+// number of spaces in a tab stop
+pub const TAB_SIZE: usize = 4;
+
+// Horizontal advances of characters, used to place cursors without shaping the whole line
+pub trait Advance {
+  // advance of ch in pixels
+  fn advance(&self, ch: char) -> f32;
+
+  // distance between tab stops in pixels
+  fn tab_width(&self) -> f32 {
+    TAB_SIZE as f32 * self.advance(' ')
+  }
+}
+
+// Advances taken from a font's hmtx table at a size.
+// Characters the font has no glyph for are drawn by a fallback font on its grid, one cell for every column they take up.
+pub struct FontMetrics<'a> {
+  charmap: Charmap<'a>,
+  metrics: swash::GlyphMetrics<'a>,
+  cell: f32,
+}
+
+impl<'a> FontMetrics<'a> {
+  pub fn new(font: swash::FontRef<'a>, size: FontSize) -> Self {
+    let charmap = font.charmap();
+    let metrics = font.glyph_metrics(&[]).scale(size as f32);
+    let cell = metrics.advance_width(charmap.map(' '));
+    FontMetrics {charmap, metrics, cell}
+  }
+}
+
+impl Advance for FontMetrics<'_> {
+  fn advance(&self, ch: char) -> f32 {
+    match self.charmap.map(ch) {
+      0 if !ch.is_control() => crate::mark::char_width(ch) as f32 * self.cell,
+      glyph => self.metrics.advance_width(glyph),
+    }
+  }
+}
+
+// advance of ch starting at x, tabs jump to the next tab stop
+fn char_advance(advance: &impl Advance, ch: char, x: f32) -> f32 {
+  if ch == '\t' {
+    let tab = advance.tab_width();
+    ((x / tab).floor() + 1.) * tab - x
+  } else {
+    advance.advance(ch)
+  }
+}
+
+// x position in pixels of the byte offset index into line
+pub fn line_x(advance: &impl Advance, line: &str, index: usize) -> f32 {
+  let mut x = 0.;
+  for ch in line[..index].chars() {
+    x += char_advance(advance, ch, x);
+  }
+  x
+}
+
+// byte offset of the character boundary in line closest to x
+pub fn line_index(advance: &impl Advance, line: &str, x: f32) -> usize {
+  let mut pen = 0.;
+  for (index, ch) in line.char_indices() {
+    let width = char_advance(advance, ch, pen);
+    if x < pen + width / 2. {
+      return index
+    }
+    pen += width;
+  }
+  line.len()
+}
+
+// the subpixel bin the glyph is rendered at follows the glyph, then the render options
 #[derive(Hash, PartialEq, Eq, Clone)]
-struct GlyphCacheKey (FontId, FontSize, swash::GlyphId);
+struct GlyphCacheKey (FontId, FontSize, swash::GlyphId, u8, (Antialias, bool, [u32; 3]));
+
+// The whole pixel x and subpixel bin to draw a glyph at x at.
+// Offsets that round up to the next pixel are drawn there at bin 0.
+pub fn subpixel_position(x: f32) -> (i32, u8) {
+  let pixel = x.floor();
+  let bin = ((x - pixel) * SUBPIXEL_BINS as f32).round() as u8;
+  if bin == SUBPIXEL_BINS {
+    (pixel as i32 + 1, 0)
+  } else {
+    (pixel as i32, bin)
+  }
+}
 
-// ABGR buffer
-// TODO: cow ptr
+// ABGR buffer, the glyph cache owns the masks and hands out references to them
 #[derive(Clone)]
 pub struct ImageBuffer {
   pub data: Vec<u8>,
   pub width: u32,
   pub height: u32,
   pub left: i32,
-  pub top: i32
+  pub top: i32,
+  // premultiplied colors of a color glyph such as an emoji, drawn as they are instead of in the text color
+  pub color: bool,
+}
+
+impl ImageBuffer {
+  // width by height pixels of one color, to draw runs over
+  pub fn new(width: u32, height: u32, color: [u8; 4]) -> Self {
+    ImageBuffer {data: color.repeat((width * height) as usize), width, height, left: 0, top: 0, color: false}
+  }
+
+  // memory the buffer takes up in the glyph cache
+  pub fn bytes(&self) -> usize {
+    self.data.len() + std::mem::size_of::<ImageBuffer>()
+  }
+
+  // make this a copy of other, reusing the allocation when it is big enough
+  pub fn copy_from(&mut self, other: &ImageBuffer) {
+    self.data.clear();
+    self.data.extend_from_slice(&other.data);
+    self.width = other.width;
+    self.height = other.height;
+    self.left = other.left;
+    self.top = other.top;
+    self.color = other.color;
+  }
+}
+
+// A shaped glyph, x and y are its offset from the start of the run on the baseline in pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PositionedGlyph {
+  pub id: swash::GlyphId,
+  pub x: f32,
+  // up is positive, like swash offsets
+  pub y: f32,
+}
+
+// Blend mask into dest with its top left corner at left, top, clipping the parts outside of dest
+fn blit(dest: &mut ImageBuffer, mask: &ImageBuffer, left: i32, top: i32, textcolor: [u8; 4], blend: BlendFn) {
+  let blend = if mask.color {composite::blend_color} else {blend};
+  let x0 = left.max(0);
+  let x1 = (left + mask.width as i32).min(dest.width as i32);
+  if x0 >= x1 {
+    return
+  }
+  let len = (x1 - x0) as usize * 4;
+  for row in 0..mask.height as i32 {
+    let y = top + row;
+    if y < 0 || y >= dest.height as i32 {
+      continue
+    }
+    let dest_start = (y as usize * dest.width as usize + x0 as usize) * 4;
+    let mask_start = (row as usize * mask.width as usize + (x0 - left) as usize) * 4;
+    blend(&mut dest.data[dest_start..dest_start + len], &mask.data[mask_start..mask_start + len], textcolor);
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+  // glyph cache lookups that found the mask and that had to scale it
+  pub hits: u64,
+  pub misses: u64,
+  // masks dropped to stay in the budget
+  pub evictions: u64,
+  pub entries: usize,
+  pub bytes: usize,
+  pub budget: usize,
+  // fonts rendered with so far and how many of them the scale context keeps scalers for
+  pub fonts: usize,
+  pub scalers: usize,
+}
+
+// Mirror img across its diagonal from the bottom left to the top right, which turns a glyph rendered
+// with x and y swapped back. Pixel x of row y moves to x height - 1 - y of row width - 1 - x.
+fn mirror(img: &ImageBuffer) -> ImageBuffer {
+  let (width, height) = (img.width as usize, img.height as usize);
+  let mut data = vec![0; img.data.len()];
+  for y in 0..height {
+    for x in 0..width {
+      let src = (y * width + x) * 4;
+      let dest = ((width - 1 - x) * height + height - 1 - y) * 4;
+      data[dest..dest + 4].copy_from_slice(&img.data[src..src + 4]);
+    }
+  }
+  ImageBuffer {
+    data,
+    width: img.height,
+    height: img.width,
+    left: img.top - img.height as i32,
+    top: img.left + img.width as i32,
+    color: img.color,
+  }
 }
 
 // Manages loaded fonts, and keeps a glyph cache
 pub struct GlyphRenderer {
   //fonts: HashMap<FontId, FontData>,
-  // TODO: will glyphs die if evicted from the cache because of the lifetime?
-  // stores cached subpixel masks
+  // stores cached subpixel masks, unbounded by count and kept under budget bytes instead
   glyph_cache: lru::LruCache<GlyphCacheKey, ImageBuffer>,
-  // TODO: does scaler get dropped if its evicted from the cache?
-  scale_context: swash::scale::ScaleContext
+  budget: usize,
+  // counters and bytes in use for stats
+  stats: CacheStats,
+  // scalers live in the scale context, which drops the least recently used font once it is full
+  scale_context: swash::scale::ScaleContext,
+  fonts: HashSet<FontId>,
+  // fastest compositing paths the cpu supports, detected once
+  compositor: Compositor,
+  options: RenderOptions,
 }
 
 //TODO: move to FontRefs everywhere
@@ -91,156 +288,396 @@ impl GlyphRenderer {
   pub fn default() -> Self {
     GlyphRenderer {
       //fonts: HashMap::new(),
-      glyph_cache: lru::LruCache::new(GLYPH_CACHE_SIZE),
-      scale_context: scale::ScaleContext::new()
-    }
-  }
-
-  // TODO: maybe user provided buffer?
-  pub fn render_glyph(&mut self, font: swash::FontRef, size: FontSize, glyph: swash::GlyphId, textcolor: [u8; 4], destcolor: [u8; 4]) -> ImageBuffer {
-    let mut img = self.get_glyph_mask(font, size, glyph);
-    Self::apply_mask(&mut img, textcolor, destcolor);
-    img
-  }
-
-  // TODO: make this return an immutable reference to the glyph mask
-  fn get_glyph_mask<'a>(&mut self, font: swash::FontRef, size: FontSize, glyph_id: swash::GlyphId) -> ImageBuffer {
-    let key = GlyphCacheKey (font.key, size, glyph_id);
-    let x = match self.glyph_cache.get(&key) {
-        Some(img) => img,
-        None => {
-          let mut scaler = self.scale_context.builder(font)
-            .size(size as f32)
-            .hint(false)
-            .build();
-          let img = Self::scale_glyph(&mut scaler, glyph_id);
-          // TODO: fix this. so stupid
-          self.glyph_cache.put(key.clone(), img);
-          self.glyph_cache.get(&key).unwrap()
-        }
-    };
-    x.clone()
+      glyph_cache: lru::LruCache::unbounded(),
+      budget: GLYPH_CACHE_BUDGET,
+      stats: CacheStats::default(),
+      // entries are only created for fonts that are rendered with, so the full cap costs nothing up front
+      scale_context: scale::ScaleContext::with_max_entries(MAX_SCALERS),
+      fonts: HashSet::new(),
+      compositor: Compositor::detect(),
+      options: RenderOptions::default(),
+    }
+  }
+
+  pub fn render_options(&self) -> RenderOptions {
+    self.options
   }
 
-  fn scale_glyph(scaler: &mut swash::scale::Scaler<'_>, glyph_id: swash::GlyphId) -> ImageBuffer {
-    let offset = zeno::Vector::new(0., 0.);
+  // Masks rendered with other options stay in the cache under their own keys until they are evicted,
+  // so switching back and forth does not render everything again
+  pub fn set_render_options(&mut self, options: RenderOptions) {
+    self.options = options;
+  }
+
+  fn cache_key(&self, font: swash::FontRef, size: FontSize, glyph_id: swash::GlyphId, bin: u8) -> GlyphCacheKey {
+    GlyphCacheKey (font.key, size, glyph_id, bin, self.options.key())
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      entries: self.glyph_cache.len(),
+      budget: self.budget,
+      fonts: self.fonts.len(),
+      scalers: self.fonts.len().min(MAX_SCALERS),
+      ..self.stats
+    }
+  }
+
+  // change how many bytes of masks the glyph cache keeps, evicting right away if it is over the new budget
+  pub fn set_cache_budget(&mut self, bytes: usize) {
+    self.budget = bytes;
+    self.evict();
+  }
+
+  // drop the least recently used masks until the cache fits the budget, always keeping the newest one
+  fn evict(&mut self) {
+    while self.stats.bytes > self.budget && self.glyph_cache.len() > 1 {
+      let (_, img) = self.glyph_cache.pop_lru().unwrap();
+      self.stats.bytes -= img.bytes();
+      self.stats.evictions += 1;
+    }
+  }
+
+  fn cache_glyph(&mut self, key: GlyphCacheKey, img: ImageBuffer) {
+    self.stats.bytes += img.bytes();
+    if let Some(old) = self.glyph_cache.put(key, img) {
+      self.stats.bytes -= old.bytes();
+    }
+    self.evict();
+  }
+
+  // Count the fonts rendered with for the stats, the scale context keeps one scaler per font.
+  // Past the cap of swash it drops the least recently used scalers, which are only created again
+  // the next time their font misses the glyph cache.
+  fn track_font(&mut self, font: FontId) {
+    self.fonts.insert(font);
+  }
+
+  // Render a glyph over destcolor into out, which only allocates when out is smaller than the glyph.
+  // Keeping out around between calls makes cached glyphs free to render.
+  pub fn render_glyph(&mut self, font: swash::FontRef, size: FontSize, glyph: swash::GlyphId, textcolor: [u8; 4], destcolor: [u8; 4], out: &mut ImageBuffer) {
+    let apply = self.compositor.apply_mask;
+    out.copy_from(self.glyph_mask(font, size, glyph, 0));
+    if out.color {
+      return composite::apply_color(&mut out.data, destcolor)
+    }
+    // TODO: better type than array of 4 u8s for color
+    apply(&mut out.data, textcolor, destcolor);
+  }
+
+  // Composite a run of shaped glyphs into dest over the pixels already there, so overlapping
+  // glyphs from kerning and ligatures blend with each other instead of with a fixed color.
+  // origin is the x and baseline the run starts at in dest.
+  pub fn render_run(&mut self, font: swash::FontRef, size: FontSize, glyphs: &[PositionedGlyph], textcolor: [u8; 4], dest: &mut ImageBuffer, origin: (f32, f32)) {
+    let (x, baseline) = origin;
+    let blend = self.compositor.blend_mask;
+    for glyph in glyphs {
+      let (pixel, bin) = subpixel_position(x + glyph.x);
+      let mask = self.glyph_mask(font, size, glyph.id, bin);
+      let left = pixel + mask.left;
+      let top = (baseline - glyph.y).round() as i32 - mask.top;
+      blit(dest, mask, left, top, textcolor, blend);
+    }
+  }
+
+  // the cached mask of a glyph shifted right by bin subpixel bins, scaling it first if it is not in the cache
+  pub fn glyph_mask(&mut self, font: swash::FontRef, size: FontSize, glyph_id: swash::GlyphId, bin: u8) -> &ImageBuffer {
+    let key = self.cache_key(font, size, glyph_id, bin);
+    if self.glyph_cache.get(&key).is_some() {
+      self.stats.hits += 1;
+    } else {
+      self.stats.misses += 1;
+      self.track_font(font.key);
+      let mut scaler = self.scale_context.builder(font)
+        .size(size as f32)
+        .hint(self.options.hinting)
+        .build();
+      let img = Self::scale_glyph(&mut scaler, glyph_id, bin as f32 / SUBPIXEL_BINS as f32, &self.options);
+      self.cache_glyph(key.clone(), img);
+    }
+    // the borrow of get cannot be returned from one branch, peek hands it out without touching the lru order again
+    self.glyph_cache.peek(&key).unwrap()
+  }
+
+  fn scale_glyph(scaler: &mut swash::scale::Scaler<'_>, glyph_id: swash::GlyphId, x_offset: f32, options: &RenderOptions) -> ImageBuffer {
+    let format = if options.subpixel() {zeno::Format::CustomSubpixel(options.offsets())} else {zeno::Format::Alpha};
+    // vertical subpixels are rendered from the glyph mirrored across its diagonal, so they are side by side
+    let (offset, transform) = if options.vertical() {
+      (zeno::Vector::new(0., x_offset), Some(zeno::Transform::new(0., 1., 1., 0., 0., 0.)))
+    } else {
+      (zeno::Vector::new(x_offset, 0.), None)
+    };
     let img = scale::Render::new(&[
       // list of sources in the font for the renderer to try to find
       scale::Source::ColorOutline(0),
       scale::Source::ColorBitmap(scale::StrikeWith::BestFit),
       scale::Source::Outline,
     ])
-    //.format(zeno::Format::Subpixel)
-    .format(zeno::Format::CustomSubpixel([0.3, 0., -0.3]))
+    .format(format)
     .offset(offset)
+    .transform(transform)
     .default_color([255, 255, 255, 255])
     .render(scaler, glyph_id).unwrap();
+    // bitmaps are not transformed
+    let mirrored = transform.is_some() && matches!(img.source, scale::Source::Outline | scale::Source::ColorOutline(_));
 
     // TODO: align this array for SIMD
-    let mut img_data = img.data.to_vec();
-    // set alpha channel to the green channel, which is the original outline
-    for px in img_data.chunks_mut(4) {
-      px[3] = px[1];
-    }
-
-    ImageBuffer {
+    let img_data = match img.content {
+      // the same coverage for every channel
+      scale::image::Content::Mask => img.data.iter().flat_map(|&a| [a; 4]).collect(),
+      // set alpha channel to the green channel, which is the original outline
+      scale::image::Content::SubpixelMask => {
+        let mut data = img.data;
+        for px in data.chunks_mut(4) {
+          px[3] = px[1];
+        }
+        data
+      }
+      // layers of color outlines are blended over nothing, which premultiplies them already
+      scale::image::Content::Color if matches!(img.source, scale::Source::ColorBitmap(_)) => {
+        let mut data = img.data;
+        for px in data.chunks_mut(4) {
+          for i in 0..3 {
+            px[i] = (px[i] as u16 * px[3] as u16 / 255) as u8;
+          }
+        }
+        data
+      }
+      scale::image::Content::Color => img.data,
+    };
+    let img = ImageBuffer {
       data: img_data,
       width: img.placement.width,
       height: img.placement.height,
       left: img.placement.left,
-      top: img.placement.top
-    }
-  }
-
-  // TODO: better type than array of 4 u8s for color
-  // TODO: avx2
-  // TODO: feature detection
-  fn apply_mask(img: &mut ImageBuffer, textcolor: [u8; 4], destcolor: [u8; 4]) {
-    fn composite_color(textcolor: u8, textalpha: u8, maskcolor: u8, destcolor: u8) -> u8 {
-      /*
-        https://github.com/servo/webrender/blob/master/webrender/doc/text-rendering.md
-        Calculate the following equation using fixed point:
-          textcolor * maskcolor + (1.0 - textalpha * maskcolor) * destcolor * 255.0
-    
-          TODO: cache based on destcolor and alpha so that the right side of the equation can be completely cached
-          (textcolor * maskcolor) + (destcolor * (0xff00 - ((textalpha * maskcolor) >> 8))) >> 8
-    
-      */
-      
-      ((textcolor as u16 * maskcolor as u16) + 
-        (destcolor as u16 * 
-          ((0xff00 - (textalpha as u16 * maskcolor as u16)) >> 8))
-         >> 8) as u8
-    }
-
-    // TODO: document simd code and make it feature detecting
-    unsafe {
-      // constants
-      let vzero = _mm_setzero_si128();
-
-      let vtext_color = _mm_set1_epi32(i32::from_ne_bytes(textcolor));
-      let vtext_color = _mm_unpacklo_epi8(vtext_color, vzero);
-
-      let vdest_color = _mm_set1_epi32(i32::from_ne_bytes(destcolor));
-      let vdest_color = _mm_unpacklo_epi8(vdest_color, vzero);
-
-      let vtext_alpha = _mm_set1_epi16(textcolor[3] as i16);
-
-      // fixed point representation of one
-      // 0xff00 == -256
-      let fixpt_one = _mm_set1_epi16(-256i16);
-
-      // TODO: look at what this generates
-      for px in img.data.chunks_mut(16) {
-        // TODO: remove this branch and add it to the end
-       if px.len() < 16 {
-            // todo: handle not exactly 4
-            for p in px.chunks_mut(4) {
-              //println!("{}", p.len());
-              p[3] = composite_color(textcolor[3], textcolor[3], p[3], destcolor[3]);
-
-              p[0] = composite_color(textcolor[0], textcolor[3], p[0], destcolor[0]);
-              p[1] = composite_color(textcolor[1], textcolor[3], p[1], destcolor[1]);
-              p[2] = composite_color(textcolor[2], textcolor[3], p[2], destcolor[2]);
-            }
-        
-        } else {
-          // TODO: refactor to another function or something
-          // TODO: accept a run of glyphs and composite all of them at once
-          // TODO: change all to unsigned
-          let vsubpx_mask = _mm_loadu_si128(&px[0] as *const _ as *const __m128i);
-          let vsubpx_mask = _mm_unpacklo_epi8(vsubpx_mask, vzero);
-
-          let left = _mm_mullo_epi16(vsubpx_mask, vtext_color);
-
-          let results = _mm_mullo_epi16(vtext_alpha, vsubpx_mask);
-          let results = _mm_subs_epu16(results, fixpt_one);
-          let results = _mm_srli_epi16(results, 8);
-          let results = _mm_mullo_epi16(results, vdest_color);
-          let results = _mm_adds_epu16(results, left);
-          let results_lo = _mm_srli_epi16(results, 8);
-
-          // repeat of above with the high bits of the mask
-          // TODO: remove the second load and have vsubpx_mask_lo and vsubpx_mask_hi
-          let vsubpx_mask = _mm_loadu_si128(&px[0] as *const _ as *const __m128i);
-          let vsubpx_mask = _mm_unpackhi_epi8(vsubpx_mask, vzero);
-
-          let left = _mm_mullo_epi16(vsubpx_mask, vtext_color);
-
-          let results = _mm_mullo_epi16(vtext_alpha, vsubpx_mask);
-          // TODO: why do we need saturating subtraction here? also the arguments are reversed???
-          let results = _mm_subs_epu16(results, fixpt_one);
-          let results = _mm_srli_epi16(results, 8);
-          let results = _mm_mullo_epi16(results, vdest_color);
-          let results = _mm_adds_epu16(results, left);
-          let results_hi = _mm_srli_epi16(results, 8);
-
-          let results = _mm_packus_epi16(results_lo, results_hi);
-
-          _mm_storeu_si128(&mut px[0] as *mut _ as *mut __m128i, results);
-        }
+      top: img.placement.top,
+      color: img.content == scale::image::Content::Color,
+    };
+
+    if mirrored {mirror(&img)} else {img}
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_blit() {
+    // a 2x2 mask that is full on the left and empty on the right
+    let mask = ImageBuffer {data: [[255; 4], [0; 4], [255; 4], [0; 4]].concat(), width: 2, height: 2, left: 0, top: 0, color: false};
+    let blend = composite::blend_mask_scalar;
+    let red = [255, 0, 0, 255];
+    let blue = [0, 0, 255, 255];
+
+    let mut dest = ImageBuffer::new(3, 2, [0, 0, 0, 255]);
+    blit(&mut dest, &mask, 0, 0, red, blend);
+    // overlapping the first one, blending with what it drew
+    blit(&mut dest, &mask, 1, 0, blue, blend);
+    // the empty part of a mask still rounds the alpha it covers down
+    let row: Vec<u8> = [[254, 0, 0, 254], [0, 0, 254, 254], [0, 0, 0, 254]].concat();
+    assert_eq!(&dest.data[..12], &row[..]);
+    assert_eq!(&dest.data[12..], &row[..]);
+
+    // clipped on every side
+    let mut dest = ImageBuffer::new(1, 1, [0, 0, 0, 255]);
+    blit(&mut dest, &mask, -1, 1, red, blend);
+    assert_eq!(dest.data, vec![0, 0, 0, 255]);
+    blit(&mut dest, &mask, 0, -1, red, blend);
+    assert_eq!(dest.data, vec![254, 0, 0, 254]);
+    blit(&mut dest, &mask, 5, 5, red, blend);
+    assert_eq!(dest.data, vec![254, 0, 0, 254]);
+  }
+
+  #[test]
+  fn test_render_glyph() {
+    let mut renderer = GlyphRenderer::default();
+    // a cached glyph never touches the font data
+    let font = swash::FontRef {data: &[], offset: 0, key: CacheKey::new()};
+    let mask = ImageBuffer {data: [[255; 4], [0; 4], [128; 4], [0; 4]].concat(), width: 2, height: 2, left: 1, top: 2, color: false};
+    renderer.glyph_cache.put(renderer.cache_key(font, 12, 1, 0), mask.clone());
+
+    let textcolor = [30, 30, 30, 255];
+    let destcolor = [230, 230, 230, 255];
+    let mut expected = mask.data.clone();
+    composite::apply_mask_scalar(&mut expected, textcolor, destcolor);
+
+    let mut out = ImageBuffer::new(4, 4, [0; 4]);
+    let ptr = out.data.as_ptr();
+    renderer.render_glyph(font, 12, 1, textcolor, destcolor, &mut out);
+    assert_eq!(out.data, expected);
+    assert_eq!((out.width, out.height, out.left, out.top), (2, 2, 1, 2));
+    // the buffer was big enough, so it was reused
+    assert_eq!(out.data.as_ptr(), ptr);
+    renderer.render_glyph(font, 12, 1, textcolor, destcolor, &mut out);
+    assert_eq!(out.data, expected);
+    assert_eq!(out.data.as_ptr(), ptr);
+  }
+
+  #[test]
+  fn test_color_glyph() {
+    let mut renderer = GlyphRenderer::default();
+    let font = swash::FontRef {data: &[], offset: 0, key: CacheKey::new()};
+    // opaque red and half transparent green, premultiplied
+    let glyph = ImageBuffer {data: [[255, 0, 0, 255], [0, 128, 0, 128]].concat(), width: 2, height: 1, left: 0, top: 1, color: true};
+    renderer.glyph_cache.put(renderer.cache_key(font, 12, 1, 0), glyph);
+    let background = [100, 100, 100, 255];
+    let expected: Vec<u8> = [[255, 0, 0, 255], [49, 177, 49, 255]].concat();
+
+    // not tinted by the text color, whatever it is
+    for textcolor in [[30, 30, 30, 255], [0, 0, 255, 128]] {
+      let mut dest = ImageBuffer::new(2, 1, background);
+      renderer.render_run(font, 12, &[PositionedGlyph {id: 1, x: 0., y: 0.}], textcolor, &mut dest, (0., 1.));
+      assert_eq!(dest.data, expected);
+
+      let mut out = ImageBuffer::new(0, 0, [0; 4]);
+      renderer.render_glyph(font, 12, 1, textcolor, background, &mut out);
+      assert_eq!(out.data, expected);
+    }
+  }
+
+  #[test]
+  fn test_cache_budget() {
+    let mut renderer = GlyphRenderer::default();
+    let font = swash::FontRef {data: &[], offset: 0, key: CacheKey::new()};
+    let mask = |side: u32| ImageBuffer::new(side, side, [255; 4]);
+    let small = mask(2).bytes();
+    let big = mask(8).bytes();
+    renderer.set_cache_budget(3 * small + big);
+
+    for id in 0..3 {
+      renderer.cache_glyph(renderer.cache_key(font, 12, id, 0), mask(2));
+    }
+    renderer.glyph_mask(font, 12, 0, 0);
+    renderer.glyph_mask(font, 12, 1, 0);
+    // one big mask pushes out the least recently used small one
+    renderer.cache_glyph(renderer.cache_key(font, 12, 3, 0), mask(8));
+    renderer.cache_glyph(renderer.cache_key(font, 12, 4, 0), mask(2));
+    assert!(!renderer.glyph_cache.contains(&renderer.cache_key(font, 12, 2, 0)));
+    assert!(renderer.glyph_cache.contains(&renderer.cache_key(font, 12, 0, 0)));
+    let stats = renderer.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 0, 1));
+    assert_eq!((stats.entries, stats.bytes), (4, 3 * small + big));
+
+    // a mask bigger than the whole budget still stays until the next one comes
+    renderer.set_cache_budget(small);
+    assert_eq!(renderer.stats().entries, 1);
+    renderer.cache_glyph(renderer.cache_key(font, 12, 5, 0), mask(16));
+    assert_eq!(renderer.stats().entries, 1);
+    assert_eq!(renderer.stats().bytes, mask(16).bytes());
+    assert_eq!(renderer.stats().evictions, 5);
+  }
+
+  #[test]
+  fn test_scaler_cache() {
+    let mut renderer = GlyphRenderer::default();
+    let fonts: Vec<FontId> = (0..18).map(|_| CacheKey::new()).collect();
+    for font in &fonts {
+      renderer.track_font(*font);
+    }
+    renderer.track_font(fonts[0]);
+    assert_eq!(renderer.stats().fonts, 18);
+    assert_eq!(renderer.stats().scalers, 18);
+
+    // no more than swash keeps
+    for _ in 0..MAX_SCALERS {
+      renderer.track_font(CacheKey::new());
+    }
+    assert_eq!(renderer.stats().fonts, 18 + MAX_SCALERS);
+    assert_eq!(renderer.stats().scalers, MAX_SCALERS);
+  }
+
+  #[test]
+  fn test_subpixel_position() {
+    assert_eq!(subpixel_position(3.), (3, 0));
+    assert_eq!(subpixel_position(3.3), (3, 1));
+    assert_eq!(subpixel_position(3.5), (3, 2));
+    assert_eq!(subpixel_position(3.9), (4, 0));
+    assert_eq!(subpixel_position(-0.25), (-1, 3));
+
+    // every bin is a mask of its own, shifted right by a fraction of a pixel
+    let font = registry::bundled_font();
+    let font = font.as_ref();
+    let glyph = font.charmap().map('l');
+    let mut renderer = GlyphRenderer::default();
+    let column_weight = |mask: &ImageBuffer| -> f32 {
+      let (mut sum, mut weighted) = (0., 0.);
+      for (i, px) in mask.data.chunks(4).enumerate() {
+        let x = (i as u32 % mask.width) as f32 + mask.left as f32;
+        sum += px[3] as f32;
+        weighted += px[3] as f32 * x;
+      }
+      weighted / sum
+    };
+    let whole = column_weight(&renderer.glyph_mask(font, 16, glyph, 0).clone());
+    let half = column_weight(&renderer.glyph_mask(font, 16, glyph, 2).clone());
+    assert!((half - whole - 0.5).abs() < 0.1, "{} {}", whole, half);
+    assert_eq!(renderer.stats().entries, 2);
+  }
+
+  #[test]
+  fn test_render_options() {
+    let font = registry::bundled_font();
+    let font = font.as_ref();
+    let glyph = font.charmap().map('g');
+    let mut renderer = GlyphRenderer::default();
+    let mut render = |antialias: Antialias| {
+      renderer.set_render_options(RenderOptions {antialias, ..RenderOptions::default()});
+      renderer.glyph_mask(font, 16, glyph, 0).clone()
+    };
+    let gray = render(Antialias::Grayscale);
+    let masks: Vec<ImageBuffer> = [Antialias::Rgb, Antialias::Bgr, Antialias::Vrgb, Antialias::Vbgr].iter().map(|aa| render(*aa)).collect();
+    assert_eq!(renderer.stats().entries, 5);
+
+    // the pixel at x, y from the origin of the glyph, y down
+    let at = |mask: &ImageBuffer, x: i32, y: i32| -> [u8; 4] {
+      let (col, row) = (x - mask.left, y + mask.top);
+      if col < 0 || row < 0 || col >= mask.width as i32 || row >= mask.height as i32 {
+        return [0; 4]
+      }
+      let i = (row as usize * mask.width as usize + col as usize) * 4;
+      [mask.data[i], mask.data[i + 1], mask.data[i + 2], mask.data[i + 3]]
+    };
+    let pixels = |mask: &ImageBuffer| {
+      let (left, top, width) = (mask.left, -mask.top, mask.width as i32);
+      (top - 2..top + mask.height as i32 + 2).flat_map(move |y| (left - 2..left + width + 2).map(move |x| (x, y)))
+    };
+
+    assert!(gray.data.chunks(4).all(|px| px.iter().all(|c| *c == px[0])));
+    for mask in &masks {
+      for (x, y) in pixels(&gray).chain(pixels(mask)) {
+        // green is sampled where grayscale is, and is the alpha of subpixel masks
+        let (px, a) = (at(mask, x, y), at(&gray, x, y)[3]);
+        assert!((px[1] as i32 - a as i32).abs() <= 2, "{} {} {:?} {}", x, y, px, a);
+        assert_eq!(px[3], px[1]);
       }
     }
+    let (rgb, bgr, vrgb, vbgr) = (&masks[0], &masks[1], &masks[2], &masks[3]);
+    for (x, y) in pixels(rgb).chain(pixels(vrgb)) {
+      // the other order swaps red and blue
+      assert_eq!(at(rgb, x, y)[0], at(bgr, x, y)[2]);
+      assert_eq!(at(vrgb, x, y)[0], at(vbgr, x, y)[2]);
+    }
+    // red is sampled left of green for rgb and above it for vrgb,
+    // so red is the glyph moved right or down by the filter offset, y down
+    let centroid = |mask: &ImageBuffer, channel: usize| {
+      let (mut sum, mut x_sum, mut y_sum) = (0., 0., 0.);
+      for (x, y) in pixels(mask) {
+        let c = at(mask, x, y)[channel] as f32;
+        sum += c;
+        x_sum += c * x as f32;
+        y_sum += c * y as f32;
+      }
+      (x_sum / sum, y_sum / sum)
+    };
+    let shift = |mask: &ImageBuffer| {
+      let (red, green) = (centroid(mask, 0), centroid(mask, 1));
+      (red.0 - green.0, red.1 - green.1)
+    };
+    let near = |(x, y): (f32, f32), expected: (f32, f32)| (x - expected.0).abs() < 0.1 && (y - expected.1).abs() < 0.1;
+    assert!(near(shift(rgb), (0.3, 0.)), "{:?}", shift(rgb));
+    assert!(near(shift(bgr), (-0.3, 0.)), "{:?}", shift(bgr));
+    assert!(near(shift(vrgb), (0., 0.3)), "{:?}", shift(vrgb));
+    assert!(near(shift(vbgr), (0., -0.3)), "{:?}", shift(vbgr));
   }
 }
-
@@ -0,0 +1,69 @@
+// How glyphs are rasterized: grayscale or subpixel antialiasing in the order of the subpixels of the
+// screen, hinting, and how far apart the subpixels are sampled.
+
+// Antialiasing of glyph masks. Subpixel masks have a coverage for each of the red, green and blue
+// subpixels of the screen, named in their order from left to right or top to bottom.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Antialias {
+  Grayscale,
+  Rgb,
+  Bgr,
+  Vrgb,
+  Vbgr,
+}
+
+impl Antialias {
+  // the kind after this one, to cycle through them
+  pub fn next(self) -> Self {
+    match self {
+      Antialias::Grayscale => Antialias::Rgb,
+      Antialias::Rgb => Antialias::Bgr,
+      Antialias::Bgr => Antialias::Vrgb,
+      Antialias::Vrgb => Antialias::Vbgr,
+      Antialias::Vbgr => Antialias::Grayscale,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderOptions {
+  pub antialias: Antialias,
+  // snap outlines to the pixel grid with the font's hinting instructions
+  pub hinting: bool,
+  // Offset in pixels each of the red, green and blue subpixels is sampled at, in the order of Rgb.
+  // The other orders reverse or rotate it. Offsets further apart make sharper but more colorful edges.
+  pub filter: [f32; 3],
+}
+
+impl Default for RenderOptions {
+  fn default() -> Self {
+    RenderOptions {antialias: Antialias::Rgb, hinting: false, filter: [0.3, 0., -0.3]}
+  }
+}
+
+impl RenderOptions {
+  pub fn subpixel(&self) -> bool {
+    self.antialias != Antialias::Grayscale
+  }
+
+  // subpixels stacked top to bottom, which are rendered sideways and turned back
+  pub fn vertical(&self) -> bool {
+    matches!(self.antialias, Antialias::Vrgb | Antialias::Vbgr)
+  }
+
+  // the subpixel offsets to render with, red first in the mask whatever the order on screen
+  pub fn offsets(&self) -> [f32; 3] {
+    let [red, green, blue] = self.filter;
+    match self.antialias {
+      Antialias::Rgb | Antialias::Vbgr => [red, green, blue],
+      Antialias::Bgr | Antialias::Vrgb => [blue, green, red],
+      Antialias::Grayscale => [0.; 3],
+    }
+  }
+
+  // the options as a part of a cache key, the filter does not matter for grayscale
+  pub(super) fn key(&self) -> (Antialias, bool, [u32; 3]) {
+    let filter = if self.subpixel() {self.filter.map(f32::to_bits)} else {[0; 3]};
+    (self.antialias, self.hinting, filter)
+  }
+}
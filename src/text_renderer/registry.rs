@@ -0,0 +1,547 @@
+// Font registry, which indexes the fonts installed on the system so fonts can be asked for by name,
+// like "monospace 14 bold", instead of by path.
+// The font directories and the generic family aliases come from fontconfig's config files when they exist,
+// and a font bundled with the editor is used when nothing matches.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use swash::{FontDataRef, FontRef, Stretch, Style, Weight, StringId};
+
+use super::{FontChain, FontData};
+
+// font used when no installed font matches a request
+const BUNDLED_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSansMono.ttf");
+pub const DEFAULT_FONT_SIZE: f32 = 14.;
+// config fontconfig reads first, which includes the rest
+const FONTCONFIG_FILE: &str = "/etc/fonts/fonts.conf";
+// includes nested deeper than this are skipped, in case configs include each other
+const MAX_INCLUDE_DEPTH: usize = 8;
+const FONT_EXTENSIONS: [&str; 4] = ["ttf", "otf", "ttc", "otc"];
+
+// A font face in a file, collections have one for every index
+#[derive(Clone)]
+pub struct FontEntry {
+  pub path: PathBuf,
+  pub index: usize,
+  pub family: String,
+  pub weight: Weight,
+  pub style: Style,
+  pub stretch: Stretch,
+  pub monospace: bool,
+}
+
+impl FontEntry {
+  // the same family, weight, style and width, whichever file it is in
+  pub fn same_face(&self, other: &FontEntry) -> bool {
+    self.family.eq_ignore_ascii_case(&other.family) && self.weight == other.weight && self.style == other.style && self.stretch == other.stretch
+  }
+}
+
+// A font asked for by family, size and style, parsed from strings like "DejaVu Sans Mono 12 bold italic"
+#[derive(Clone, Debug, PartialEq)]
+pub struct FontRequest {
+  // a family name or a generic family like monospace
+  pub family: String,
+  pub size: f32,
+  pub weight: u16,
+  pub style: Style,
+}
+
+impl FontRequest {
+  // The family is everything but a trailing size and style words, an empty family means monospace
+  pub fn parse(text: &str) -> Self {
+    let mut words: Vec<&str> = text.split_whitespace().collect();
+    let mut request = FontRequest {family: String::new(), size: DEFAULT_FONT_SIZE, weight: Weight::NORMAL.0, style: Style::Normal};
+    // only the last number is the size, the ones before it belong to the family
+    let mut sized = false;
+    while let Some(word) = words.last() {
+      let lower = word.to_ascii_lowercase();
+      match lower.as_str() {
+        "thin" => request.weight = Weight::THIN.0,
+        "extralight" | "ultralight" => request.weight = Weight::EXTRA_LIGHT.0,
+        "light" => request.weight = Weight::LIGHT.0,
+        "regular" | "normal" | "book" => {}
+        "medium" => request.weight = Weight::MEDIUM.0,
+        "semibold" | "demibold" => request.weight = Weight::SEMI_BOLD.0,
+        "bold" => request.weight = Weight::BOLD.0,
+        "extrabold" | "ultrabold" => request.weight = Weight::EXTRA_BOLD.0,
+        "black" | "heavy" => request.weight = Weight::BLACK.0,
+        "italic" => request.style = Style::Italic,
+        "oblique" => request.style = Style::Oblique(Default::default()),
+        _ => match lower.parse::<f32>() {
+          Ok(size) if size > 0. && !sized => {
+            request.size = size;
+            sized = true;
+          }
+          _ => break,
+        },
+      }
+      words.pop();
+    }
+    request.family = if words.is_empty() {"monospace".to_string()} else {words.join(" ")};
+    request
+  }
+}
+
+// what fontconfig's config files say
+#[derive(Default, Debug, PartialEq)]
+struct FontConfig {
+  dirs: Vec<PathBuf>,
+  includes: Vec<PathBuf>,
+  // generic family to the families preferred for it, in order
+  aliases: Vec<(String, Vec<String>)>,
+}
+
+// A tag or the text between tags of an xml file
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+  Open(&'a str, &'a str),
+  Close(&'a str),
+  Text(&'a str),
+}
+
+// Split xml into tags and text, enough for fontconfig's files. Comments, declarations and
+// self closing tags are skipped.
+fn tokens(text: &str) -> Vec<Token<'_>> {
+  let mut tokens = Vec::new();
+  let mut rest = text;
+  while let Some(start) = rest.find('<') {
+    if !rest[..start].trim().is_empty() {
+      tokens.push(Token::Text(rest[..start].trim()));
+    }
+    rest = &rest[start..];
+    let end = if rest.starts_with("<!--") {rest.find("-->").map(|end| end + 3)} else {rest.find('>').map(|end| end + 1)};
+    let end = match end {
+      Some(end) => end,
+      None => break,
+    };
+    let tag = &rest[1..end - 1];
+    rest = &rest[end..];
+    if tag.starts_with('!') || tag.starts_with('?') || tag.ends_with('/') {
+      continue
+    }
+    if let Some(name) = tag.strip_prefix('/') {
+      tokens.push(Token::Close(name.trim()));
+    } else {
+      let (name, attributes) = tag.split_at(tag.find(char::is_whitespace).unwrap_or(tag.len()));
+      tokens.push(Token::Open(name, attributes));
+    }
+  }
+  tokens
+}
+
+// the value of name="value" in the attributes of a tag
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+  let start = attributes.find(&format!("{}=\"", name))? + name.len() + 2;
+  let len = attributes[start..].find('"')?;
+  Some(&attributes[start..start + len])
+}
+
+fn home() -> Option<PathBuf> {
+  std::env::var_os("HOME").map(PathBuf::from)
+}
+
+fn xdg_data_home() -> Option<PathBuf> {
+  std::env::var_os("XDG_DATA_HOME").map(PathBuf::from).or_else(|| Some(home()?.join(".local/share")))
+}
+
+fn xdg_config_home() -> Option<PathBuf> {
+  std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from).or_else(|| Some(home()?.join(".config")))
+}
+
+// a path from a config file, which may start at home, at an xdg directory or at the directory of the file
+fn config_path(path: &str, prefix: Option<&str>, base: &Path, xdg: Option<PathBuf>) -> Option<PathBuf> {
+  if let Some(rest) = path.strip_prefix('~') {
+    return Some(home()?.join(rest.trim_start_matches('/')))
+  }
+  match prefix {
+    Some("xdg") => Some(xdg?.join(path)),
+    _ => Some(base.join(path)),
+  }
+}
+
+// the dirs, includes and aliases of a config file, base is the directory it is in
+fn parse_fontconfig(text: &str, base: &Path) -> FontConfig {
+  let mut config = FontConfig::default();
+  // open tags around the current one, with their attributes
+  let mut open: Vec<(&str, &str)> = Vec::new();
+  for token in tokens(text) {
+    match token {
+      Token::Open(name, attributes) => {
+        if name == "alias" && !open.iter().any(|(name, _)| *name == "match") {
+          config.aliases.push((String::new(), Vec::new()));
+        }
+        open.push((name, attributes));
+      }
+      Token::Close(_) => {
+        open.pop();
+      }
+      Token::Text(text) => {
+        let (name, attributes) = match open.last() {
+          Some(tag) => *tag,
+          None => continue,
+        };
+        let parent = open.len().checked_sub(2).map(|i| open[i].0);
+        let prefix = attribute(attributes, "prefix");
+        match (parent, name) {
+          (Some("fontconfig"), "dir") => config.dirs.extend(config_path(text, prefix, base, xdg_data_home())),
+          (Some("fontconfig"), "include") => config.includes.extend(config_path(text, prefix, base, xdg_config_home())),
+          (Some("alias"), "family") => if let Some((alias, _)) = config.aliases.last_mut() {
+            *alias = text.to_ascii_lowercase();
+          },
+          (Some("prefer"), "family") | (Some("accept"), "family") => if let Some((_, families)) = config.aliases.last_mut() {
+            families.push(text.to_string());
+          },
+          _ => {}
+        }
+      }
+    }
+  }
+  config.aliases.retain(|(alias, families)| !alias.is_empty() && !families.is_empty());
+  config
+}
+
+// the faces in the font file at path
+fn index_fonts(path: &Path, data: &[u8]) -> Vec<FontEntry> {
+  let fonts = match FontDataRef::new(data) {
+    Some(fonts) => fonts,
+    None => return Vec::new(),
+  };
+  (0..fonts.len())
+    .filter_map(|index| {
+      let font = fonts.get(index)?;
+      let (stretch, weight, style) = font.attributes().parts();
+      Some(FontEntry {
+        path: path.to_path_buf(),
+        index,
+        family: family_name(&font)?,
+        weight,
+        style,
+        stretch,
+        monospace: font.metrics(&[]).is_monospace,
+      })
+    })
+    .collect()
+}
+
+// the typographic family if there is one, which leaves out weights like light that some fonts put in the family
+fn family_name(font: &FontRef) -> Option<String> {
+  let strings = font.localized_strings();
+  let name = strings.find_by_id(StringId::TypographicFamily, Some("en"))
+    .or_else(|| strings.find_by_id(StringId::TypographicFamily, None))
+    .or_else(|| strings.find_by_id(StringId::Family, Some("en")))
+    .or_else(|| strings.find_by_id(StringId::Family, None))?;
+  Some(name.to_string())
+}
+
+// how far a face is from the weight and style asked for, lower is closer
+fn distance(entry: &FontEntry, request: &FontRequest) -> u32 {
+  let style = match (entry.style, request.style) {
+    (Style::Normal, Style::Normal) | (Style::Italic, Style::Italic) | (Style::Oblique(_), Style::Oblique(_)) => 0,
+    // slanted either way is closer than upright
+    (Style::Italic, Style::Oblique(_)) | (Style::Oblique(_), Style::Italic) => 1,
+    _ => 2,
+  };
+  // condensed and expanded faces often share the family of the normal ones
+  let stretch = if entry.stretch.is_normal() {0} else {1};
+  style * 1000 + stretch * 500 + (entry.weight.0 as i32 - request.weight as i32).unsigned_abs()
+}
+
+// Installed fonts by family, and the aliases generic families resolve to
+pub struct FontRegistry {
+  fonts: Vec<FontEntry>,
+  // files indexed so far, so a file reached twice is only read once
+  paths: HashSet<PathBuf>,
+  aliases: HashMap<String, Vec<String>>,
+}
+
+impl FontRegistry {
+  pub fn default() -> Self {
+    FontRegistry {fonts: Vec::new(), paths: HashSet::new(), aliases: HashMap::new()}
+  }
+
+  // Index the fonts in the directories fontconfig knows about, or in the usual ones if it is not installed
+  pub fn system() -> Self {
+    let mut registry = FontRegistry::default();
+    let mut dirs = registry.read_fontconfig(Path::new(FONTCONFIG_FILE), 0);
+    if dirs.is_empty() {
+      dirs = ["/usr/share/fonts", "/usr/local/share/fonts", "/Library/Fonts", "/System/Library/Fonts", "C:\\Windows\\Fonts"]
+        .iter()
+        .map(PathBuf::from)
+        .chain(xdg_data_home().map(|dir| dir.join("fonts")))
+        .chain(home().map(|dir| dir.join(".fonts")))
+        .collect();
+    }
+    for dir in dirs {
+      registry.add_dir(&dir);
+    }
+    registry
+  }
+
+  // Read the aliases of a fontconfig file and the files it includes, returns the font directories they list
+  pub fn read_fontconfig(&mut self, path: &Path, depth: usize) -> Vec<PathBuf> {
+    if depth > MAX_INCLUDE_DEPTH {
+      return Vec::new()
+    }
+    // an include can name a directory, then every .conf file in it is read in order
+    if path.is_dir() {
+      let mut files: Vec<PathBuf> = match std::fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|entry| Some(entry.ok()?.path())).collect(),
+        Err(_) => return Vec::new(),
+      };
+      files.retain(|file| file.extension().is_some_and(|ext| ext == "conf"));
+      files.sort();
+      return files.iter().flat_map(|file| self.read_fontconfig(file, depth + 1)).collect()
+    }
+
+    let text = match std::fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(_) => return Vec::new(),
+    };
+    let config = parse_fontconfig(&text, path.parent().unwrap_or_else(|| Path::new("/")));
+    for (alias, families) in config.aliases {
+      self.add_alias(&alias, families);
+    }
+    let mut dirs = config.dirs;
+    for include in &config.includes {
+      dirs.extend(self.read_fontconfig(include, depth + 1));
+    }
+    dirs
+  }
+
+  // prefer families, in order, for a generic family, after the ones added for it before
+  pub fn add_alias(&mut self, alias: &str, families: Vec<String>) {
+    let preferred = self.aliases.entry(alias.to_ascii_lowercase()).or_default();
+    for family in families {
+      if !preferred.contains(&family) {
+        preferred.push(family);
+      }
+    }
+  }
+
+  // index every font file under dir
+  pub fn add_dir(&mut self, dir: &Path) {
+    self.walk_dir(dir, &mut HashSet::new());
+  }
+
+  // visited holds the canonical paths of the directories walked, so symlinks back up the tree are not followed forever
+  fn walk_dir(&mut self, dir: &Path, visited: &mut HashSet<PathBuf>) {
+    if !std::fs::canonicalize(dir).is_ok_and(|canonical| visited.insert(canonical)) {
+      return
+    }
+    let entries = match std::fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+      let path = entry.path();
+      if path.is_dir() {
+        self.walk_dir(&path, visited);
+      } else {
+        self.add_file(&path);
+      }
+    }
+  }
+
+  pub fn add_file(&mut self, path: &Path) {
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase());
+    if !extension.is_some_and(|ext| FONT_EXTENSIONS.contains(&ext.as_str())) || !self.paths.insert(path.to_path_buf()) {
+      return
+    }
+    if let Ok(data) = std::fs::read(path) {
+      self.fonts.extend(index_fonts(path, &data));
+    }
+  }
+
+  pub fn fonts(&self) -> &[FontEntry] {
+    &self.fonts
+  }
+
+  // The closest face of the first family that is installed, trying the families a generic family is an alias for.
+  // When none of those are installed, monospace still matches any fixed pitch font.
+  pub fn resolve(&self, request: &FontRequest) -> Option<&FontEntry> {
+    // fontconfig maps the short names to the generic families with match rules, which are not read
+    let family = match request.family.to_ascii_lowercase().as_str() {
+      "mono" => "monospace".to_string(),
+      "sans" => "sans-serif".to_string(),
+      family => family.to_string(),
+    };
+    let aliased = self.aliases.get(&family).into_iter().flatten().map(|family| family.as_str());
+    let found = aliased.chain(std::iter::once(request.family.as_str())).find_map(|family| {
+      self.fonts.iter()
+        .filter(|font| font.family.eq_ignore_ascii_case(family))
+        .min_by_key(|font| distance(font, request))
+    });
+    found.or_else(|| match family.as_str() {
+      "monospace" => self.fonts.iter().filter(|font| font.monospace).min_by_key(|font| distance(font, request)),
+      _ => None,
+    })
+  }
+
+  // Load the font closest to request and its entry, the bundled font if none is installed or it fails to load
+  pub fn load(&self, request: &FontRequest) -> (FontData, FontEntry) {
+    self.resolve(request)
+      .and_then(|font| Some((FontData::from_file(&font.path, font.index)?, font.clone())))
+      .unwrap_or_else(|| (bundled_font(), bundled_entry()))
+  }
+
+  // Load request as the primary font of a chain followed by the fallback families that are installed,
+  // in the weight and style of request
+  pub fn load_chain(&self, request: &FontRequest, fallbacks: &[&str]) -> FontChain {
+    let (data, entry) = self.load(request);
+    let mut chain = FontChain::new(data);
+    // faces are compared rather than files, the bundled font can also be installed
+    let mut loaded = vec![entry];
+    for family in fallbacks {
+      let fallback = FontRequest {family: family.to_string(), ..request.clone()};
+      let font = match self.resolve(&fallback) {
+        Some(font) if !loaded.iter().any(|entry| entry.same_face(font)) => font,
+        _ => continue,
+      };
+      if let Some(data) = FontData::from_file(&font.path, font.index) {
+        chain.push(data);
+        loaded.push(font.clone());
+      }
+    }
+    chain
+  }
+}
+
+pub fn bundled_font() -> FontData {
+  FontData::from_bytes(BUNDLED_FONT.to_vec(), 0).unwrap()
+}
+
+fn bundled_entry() -> FontEntry {
+  index_fonts(Path::new(""), BUNDLED_FONT).remove(0)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_request() {
+    let request = FontRequest::parse("monospace 14 bold");
+    assert_eq!(request, FontRequest {family: "monospace".to_string(), size: 14., weight: 700, style: Style::Normal});
+    let request = FontRequest::parse("DejaVu Sans Mono Italic");
+    assert_eq!(request, FontRequest {family: "DejaVu Sans Mono".to_string(), size: DEFAULT_FONT_SIZE, weight: 400, style: Style::Italic});
+    // a number inside the family stays in it
+    assert_eq!(FontRequest::parse("Source Code 2 light 9.5").family, "Source Code 2");
+    assert_eq!(FontRequest::parse("Source Code 2 light 9.5").size, 9.5);
+    assert_eq!(FontRequest::parse("12").family, "monospace");
+  }
+
+  #[test]
+  fn test_parse_fontconfig() {
+    let text = r#"<?xml version="1.0"?>
+      <!DOCTYPE fontconfig SYSTEM "urn:fontconfig:fonts.dtd">
+      <fontconfig>
+        <!-- <dir>/commented/out</dir> -->
+        <dir>/usr/share/fonts</dir>
+        <dir prefix="relative">local</dir>
+        <include ignore_missing="yes">conf.d</include>
+        <alias binding="same">
+          <family>Monospace</family>
+          <prefer>
+            <family>Noto Sans Mono</family>
+            <family>DejaVu Sans Mono</family>
+          </prefer>
+        </alias>
+        <match target="pattern">
+          <test name="family"><string>mono</string></test>
+          <edit name="family" mode="assign"><string>monospace</string></edit>
+        </match>
+        <selectfont><rejectfont><glob>*.pcf</glob></rejectfont></selectfont>
+        <config><rescan><int>30</int></rescan></config>
+      </fontconfig>"#;
+    let config = parse_fontconfig(text, Path::new("/etc/fonts"));
+    assert_eq!(config.dirs, vec![PathBuf::from("/usr/share/fonts"), PathBuf::from("/etc/fonts/local")]);
+    assert_eq!(config.includes, vec![PathBuf::from("/etc/fonts/conf.d")]);
+    assert_eq!(config.aliases, vec![("monospace".to_string(), vec!["Noto Sans Mono".to_string(), "DejaVu Sans Mono".to_string()])]);
+  }
+
+  #[test]
+  fn test_resolve() {
+    let bundled = index_fonts(Path::new("bundled.ttf"), BUNDLED_FONT);
+    assert_eq!(bundled.len(), 1);
+    assert_eq!(bundled[0].family, "DejaVu Sans Mono");
+    assert_eq!(bundled[0].weight, Weight::NORMAL);
+    assert!(bundled[0].monospace);
+
+    let face = |family: &str, weight: Weight, style: Style, monospace: bool| FontEntry {
+      path: PathBuf::from(format!("{}-{}.ttf", family, weight.0)),
+      index: 0,
+      family: family.to_string(),
+      weight,
+      style,
+      stretch: Stretch::NORMAL,
+      monospace,
+    };
+    let mut registry = FontRegistry::default();
+    registry.fonts = vec![
+      face("Sans", Weight::NORMAL, Style::Normal, false),
+      face("Mono", Weight::NORMAL, Style::Normal, true),
+      face("Mono", Weight::BOLD, Style::Normal, true),
+      face("Mono", Weight::NORMAL, Style::Italic, true),
+      face("Code", Weight::NORMAL, Style::Normal, true),
+    ];
+    // a condensed bold face is further from sans bold than the normal width regular one
+    let mut condensed = face("Sans", Weight::BOLD, Style::Normal, false);
+    condensed.stretch = Stretch::CONDENSED;
+    condensed.path = PathBuf::from("Sans-Condensed-700.ttf");
+    registry.fonts.push(condensed);
+    let resolve = |registry: &FontRegistry, request: &str| registry.resolve(&FontRequest::parse(request)).map(|font| font.path.clone());
+
+    assert_eq!(resolve(&registry, "mono semibold"), Some(PathBuf::from("Mono-700.ttf")));
+    assert_eq!(resolve(&registry, "sans bold"), Some(PathBuf::from("Sans-400.ttf")));
+    assert_eq!(registry.resolve(&FontRequest::parse("mono italic")).unwrap().style, Style::Italic);
+    assert_eq!(resolve(&registry, "serif"), None);
+    // without aliases monospace is any fixed pitch font
+    assert!(registry.resolve(&FontRequest::parse("monospace")).unwrap().monospace);
+    registry.add_alias("Monospace", vec!["Missing".to_string(), "Code".to_string()]);
+    assert_eq!(resolve(&registry, "monospace 12"), Some(PathBuf::from("Code-400.ttf")));
+
+    // a font that cannot be loaded falls back to the bundled one
+    let (font, _) = registry.load(&FontRequest::parse("code"));
+    assert_eq!(family_name(&font.as_ref()).as_deref(), Some("DejaVu Sans Mono"));
+  }
+
+  #[test]
+  fn test_load_chain() {
+    let mut registry = FontRegistry::default();
+    registry.add_file(Path::new("fonts/DejaVuSansMono.ttf"));
+    registry.add_file(Path::new("fonts/LICENSE-DejaVu"));
+    assert_eq!(registry.fonts().len(), 1);
+
+    // fallbacks that are not installed or already in the chain are left out
+    let chain = registry.load_chain(&FontRequest::parse("monospace"), &["Missing", "DejaVu Sans Mono", "monospace"]);
+    assert_eq!(chain.len(), 1);
+    // the installed copy of the bundled font is the same face
+    let chain = registry.load_chain(&FontRequest::parse("Missing"), &["DejaVu Sans Mono"]);
+    assert_eq!(chain.len(), 1);
+    let mut other = registry.fonts()[0].clone();
+    other.family = "Other".to_string();
+    registry.fonts.push(other);
+    let chain = registry.load_chain(&FontRequest::parse("Missing"), &["DejaVu Sans Mono", "Other"]);
+    assert_eq!(chain.len(), 2);
+  }
+
+  #[test]
+  fn test_add_dir() {
+    let dir = std::env::temp_dir().join(format!("editor-test-fonts-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("nested")).unwrap();
+    std::fs::copy("fonts/DejaVuSansMono.ttf", dir.join("nested/DejaVuSansMono.ttf")).unwrap();
+    // a link back up the tree
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&dir, dir.join("nested/loop")).unwrap();
+
+    let mut registry = FontRegistry::default();
+    registry.add_dir(&dir);
+    registry.add_dir(&dir);
+    registry.add_file(&dir.join("nested/DejaVuSansMono.ttf"));
+    assert_eq!(registry.fonts().len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}